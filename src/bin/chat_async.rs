@@ -1,165 +1,159 @@
 /// A chat server that listens on a local IPv6 TCP port for incoming client connections and
-/// broadcasts every line of input received to each currently connected client. A simple client
-/// connection can be established on the the same machine by entering something like:
+/// broadcasts every line of input received to each client in the same chat room. Clients start in
+/// the `lobby` room, and lines starting with `/` are commands, such as `/join <room>`; `/help` lists
+/// them all. A simple client connection can be established on the the same machine by entering
+/// something like:
 ///     nc -Nv ::1 8080
 ///
+/// The address and port to listen on can be changed with command-line options; run with `--help`
+/// for details. `--json-port` adds a second port, on which clients such as bots speak a JSON-lines
+/// protocol instead, `--ws-port` adds a port on which browsers can connect with WebSockets, and
+/// `--irc-port` adds one for IRC clients. `--tls-cert` and `--tls-key` make every port accept TLS
+/// connections instead of plaintext ones.
+///
 /// This uses the cooperative multitasking provided by Rust's async/.await system in conjuction
 /// with the async-std crate to handle each client's connection and the relaying of chat messages.
-use async_std::channel::{self, Receiver, Sender};
-use async_std::io::prelude::BufReadExt;
-use async_std::io::{BufReader, WriteExt};
-use async_std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
-use async_std::stream::StreamExt;
-use async_std::sync::{Arc, Mutex};
+/// The broadcasting task queues each message for a writer task per client, so a client that reads
+/// slowly cannot hold up the others. `--client-buffer` and `--slow-client` set how many messages can
+/// be queued for a client and what happens when that limit is reached. On SIGINT or SIGTERM the
+/// server sends every client a notice, stops reading from every client and gives the tasks time to
+/// forward their final messages before exiting.
+use async_std::channel;
 use async_std::task;
+use std::io;
 use std::time::Instant;
-
-const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
-const LOCAL_PORT: u16 = 8080;
-
-type Message = String;
-
-fn main() {
-    let time_at_start = Instant::now();
-    println!("Starting at monotonic clock time: {:?}", time_at_start);
+use tcp_echo::broker::asynchronous::{broadcast, Event};
+use tcp_echo::broker::Message;
+use tcp_echo::chat::Chat;
+use tcp_echo::codec::{self, Protocol};
+use tcp_echo::config::Config;
+use tcp_echo::governor::{self, Governor};
+use tcp_echo::handler::asynchronous::{handle_chat_connection, handle_websocket_connection};
+use tcp_echo::listener;
+use tcp_echo::log::{self, Context};
+use tcp_echo::shutdown::asynchronous::{join_until, Connections};
+use tcp_echo::shutdown::Shutdown;
+use tcp_echo::tls;
+use tcp_echo::{info, trace, warn};
+
+fn main() -> io::Result<()> {
+    let config = Config::from_args();
+    log::init(config.log);
+    let tls = tls::acceptor(config.tls.as_ref());
+    let chat = Chat::new(&config.chat)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to open chat log: {e}")))?;
 
     let accept_loop = async {
-        let socket = SocketAddrV6::new(LOCAL_ADDR_IPV6, LOCAL_PORT, 0, 0);
-        let listener = TcpListener::bind(socket)
-            .await
-            .expect("Failed to bind to port {LOCAL_PORT}");
-
-        let mut incoming = listener.incoming();
-
-        let (broadcast_tx, broadcast_rx) = channel::unbounded::<Message>();
-        let user_streams_tx = Arc::new(Mutex::new(Vec::new()));
-
-        // Spawn dedicated thread to broadcast messages to all TCP streams.
-        let ust_cloned = user_streams_tx.clone();
-        task::spawn(async {
-            broadcast(broadcast_rx, ust_cloned).await;
-        });
-
-        while let Some(stream) = incoming.next().await {
-            let stream = stream.unwrap();
-
-            println!(
-                "{}ms: Connection established",
-                time_at_start.elapsed().as_millis()
-            );
-
-            let sender_cloned = broadcast_tx.clone();
-            let stream_cloned = stream.clone();
-            task::spawn(handle_connection(stream_cloned, sender_cloned));
-
-            println!("Control returned to main loop - waiting for more incoming connections");
-
-            let mut a = user_streams_tx.lock().await;
-            a.push(stream);
-            println!("Client registration complete");
+        let listener = listener::bind_async(&config.listen).await;
+
+        let (broadcast_tx, broadcast_rx) = channel::unbounded::<Event>();
+
+        // Spawn dedicated task to broadcast messages to all clients.
+        let broadcaster = task::spawn(broadcast(broadcast_rx, chat, config.outbound.overflow));
+
+        let shutdown = Shutdown::new(
+            listener
+                .local_addr()
+                .expect("Failed to query listener address"),
+        );
+        shutdown.install_signal_handler();
+        let mut connections = Connections::new();
+        let governor = Governor::new(config.governor);
+
+        // Each listener accepts connections in a task of its own and hands them to this loop.
+        let (accepted_tx, accepted_rx) = channel::bounded(1);
+        if let Some(port) = config.listen.json_port {
+            let json_listener =
+                listener::bind_protocol_async(&config.listen, port, Protocol::Json).await;
+            listener::accept_into_async(json_listener, Protocol::Json, accepted_tx.clone());
         }
-    };
-
-    task::block_on(accept_loop);
-}
-
-/// Continuously broadcasts `Messages` received on the given `broadcast_rx` `Receiver` to every
-/// `TcpStream` in `user_streams_tx`. The latter is wrapped in an `Arc` and `Mutex` to allow the
-/// vector of user streams to be updated by a different thread as new clients connect. If an attempt
-/// to send data to a client user stream fails, the client is assumed to have disconnected and their
-/// client user stream is removed from `user_streams_tx`.
-///
-/// The function loops continuously until an error occurs when trying to read from `broadcast_rx`.
-async fn broadcast(broadcast_rx: Receiver<Message>, user_streams_tx: Arc<Mutex<Vec<TcpStream>>>) {
-    println!("Broadcaster started");
-    loop {
-        match broadcast_rx.recv().await {
-            Ok(message) => {
-                println!("\tBroadcaster received message: {}", message);
+        if let Some(port) = config.listen.ws_port {
+            let ws_listener =
+                listener::bind_protocol_async(&config.listen, port, Protocol::WebSocket).await;
+            listener::accept_into_async(ws_listener, Protocol::WebSocket, accepted_tx.clone());
+        }
+        if let Some(port) = config.listen.irc_port {
+            let irc_listener =
+                listener::bind_protocol_async(&config.listen, port, Protocol::Irc).await;
+            listener::accept_into_async(irc_listener, Protocol::Irc, accepted_tx.clone());
+        }
+        listener::accept_into_async(listener, Protocol::Text, accepted_tx);
 
-                let mut good_senders = Vec::new();
-                let mut streams = user_streams_tx.lock().await;
+        while let Ok((stream, protocol)) = accepted_rx.recv().await {
+            if shutdown.is_requested() {
+                break;
+            }
 
-                let response_bytes = message.into_bytes();
+            let context = Context::accepted(stream.peer_addr());
+            let Some(permit) =
+                governor::asynchronous::admit(&governor, &stream, tls.is_none()).await
+            else {
+                continue;
+            };
 
-                for mut stream in streams.drain(..) {
-                    match stream.write_all(&response_bytes).await {
-                        Ok(()) => {
-                            println!("\tSucceeded in broadcasting to a channel");
-                            good_senders.push(stream);
-                        }
-                        Err(_) => {
-                            println!("\tFailed to broadcast to a channel");
-                        }
-                    }
+            let sender_cloned = broadcast_tx.clone();
+            let outbound = config.outbound;
+            let tls = tls.clone();
+            let timeouts = config.timeouts;
+            let input = config.input;
+            let guard = connections.track(&stream);
+            task::spawn(async move {
+                let _guard = guard;
+                let _permit = permit;
+                let result = if protocol == Protocol::WebSocket {
+                    handle_websocket_connection(
+                        stream,
+                        sender_cloned,
+                        outbound,
+                        tls,
+                        timeouts,
+                        input,
+                    )
+                    .await
+                } else {
+                    handle_chat_connection(
+                        stream,
+                        sender_cloned,
+                        outbound,
+                        protocol,
+                        tls,
+                        timeouts,
+                        input,
+                    )
+                    .await
+                };
+                if let Err(e) = result {
+                    warn!(context, "connection_closed", "Closed connection: {e}");
                 }
+            });
 
-                *streams = good_senders;
-            }
-            Err(e) => {
-                println!(
-                    "Broadcaster channel returned '{:?}', so Broadcaster exiting",
-                    e
-                );
-                return;
-            }
+            trace!(
+                Context::SERVER,
+                "accept_loop",
+                "Control returned to main loop - waiting for more incoming connections"
+            );
         }
-    }
-}
-
-/// First asks for the user's display name, then continuously receives newline-delimited input from
-/// the `stream` passed, and sends it as a `Message` to the given `sender` channel. This process is
-/// repeated until `stream` is closed or an error occurs.
-///
-/// # Panics
-///
-/// Panics if an error occurs when sending to `sender` or when attempting to read data from
-/// `stream`.
-async fn handle_connection(mut stream: TcpStream, sender: Sender<Message>) {
-    let mut display_name = None;
-
-    let peer = stream
-        .peer_addr()
-        .expect("Failed to query details of the remote peer");
-    println!("\tIncoming connection is from: {peer:?}");
 
-    stream
-        .write_all(b"Enter your display name\n")
-        .await
-        .expect("Failed to send prompt for user to enter their display name");
+        info!(Context::SERVER, "shutting_down", "Shutting down");
+        let deadline = Instant::now() + config.shutdown_timeout;
 
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-
-    loop {
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                // End of file
-                println!("\t>>[End of data; closing connection]");
-                return;
-            }
-            Ok(n) => {
-                print!("\t>>[{n} chars] {line}"); // No need for newline as input contains one
-
-                if display_name.is_none() {
-                    display_name = Some((line.trim()).to_owned());
+        // The notice must be queued for every client before draining, as each handler deregisters
+        // its client once it stops reading. The handlers then wait for their writers to send
+        // everything queued, and dropping the last sender causes the broadcaster to exit.
+        broadcast_tx
+            .send(Event::Broadcast(Message::system(
+                codec::SHUTDOWN_NOTICE.trim_end(),
+            )))
+            .await
+            .expect("Failed to send shutdown notice to broadcaster");
+        connections.drain(deadline).await;
+        drop(broadcast_tx);
+        join_until(broadcaster, deadline).await;
 
-                    sender
-                        .send(display_name.clone().unwrap().to_owned() + &" has entered the chat\n")
-                        .await
-                        .expect("Failed to send chat entry message to broadcaster");
-                } else {
-                    sender
-                        .send(display_name.clone().unwrap().to_owned() + &": " + &line)
-                        .await
-                        .expect("Failed to send incoming message to broadcaster");
-                }
+        connections.close(None).await;
+        info!(Context::SERVER, "shutdown_complete", "Shutdown complete");
+    };
 
-                line = String::new();
-            }
-            Err(e) => {
-                panic!("\tError while reading from received data:\n\t{e}");
-            }
-        }
-    }
+    task::block_on(accept_loop);
+    Ok(())
 }
@@ -1,164 +1,172 @@
 /// A chat server that listens on a local IPv6 TCP port for incoming client connections and
-/// broadcasts every line of input received to each currently connected client. A simple client
-/// connection can be established on the the same machine by entering something like:
+/// broadcasts every line of input received to each client in the same chat room. Clients start in
+/// the `lobby` room, and lines starting with `/` are commands, such as `/join <room>`; `/help` lists
+/// them all. A simple client connection can be established on the the same machine by entering
+/// something like:
 ///     nc -Nv ::1 8080
 ///
+/// The address and port to listen on can be changed with command-line options; run with `--help`
+/// for details. `--json-port` adds a second port, on which clients such as bots speak a JSON-lines
+/// protocol instead, and `--irc-port` adds one for IRC clients. `--tls-cert` and `--tls-key` make
+/// every port accept TLS connections instead of plaintext ones.
+///
 /// This uses the concurrency provided by `std::thread` to handle each client's connection in a
-/// separate OS thread. The child threads are detached from the parent thread, so the parent does
-/// not need to wait for them to finish as part of program clean-up. A single thread is also
-/// created to broadcast messages to clients.
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+/// separate OS thread. The child threads are detached from the parent thread, but each connection
+/// is tracked so that on SIGINT or SIGTERM the server can send every client a notice, stop reading
+/// from every client and give the threads time to forward their final messages. A single thread is
+/// also created to broadcast messages to clients. Rather than writing to clients itself, it queues
+/// each message for a writer thread per client, so a client that reads slowly cannot hold up the
+/// others. `--client-buffer` and `--slow-client` set how many messages can be queued for a client
+/// and what happens when that limit is reached.
+///
+/// Alternatively, `--pool-size` runs the connections on a fixed pool of worker threads. As each
+/// connection occupies a worker until the client disconnects, the pool size limits the number of
+/// clients that can chat at once.
+use std::io;
+use std::sync::mpsc::{channel, sync_channel};
 use std::thread;
 use std::time::Instant;
-
-const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
-const LOCAL_PORT: u16 = 8080;
-
-type Message = String;
-
-fn main() {
-    let time_at_start = Instant::now();
-    println!("Starting at monotonic clock time: {:?}", time_at_start);
-
-    let socket = SocketAddrV6::new(LOCAL_ADDR_IPV6, LOCAL_PORT, 0, 0);
-    let listener = TcpListener::bind(socket).expect("Failed to bind to port {LOCAL_PORT}");
-
-    let (broadcast_tx, broadcast_rx) = channel::<Message>();
-    let user_streams_tx = Arc::new(Mutex::new(Vec::new()));
-
-    // Spawn dedicated thread to broadcast messages to all TCP streams.
-    let ust_cloned = user_streams_tx.clone();
-    thread::spawn(move || {
-        broadcast(broadcast_rx, ust_cloned);
+use tcp_echo::broker::blocking::{broadcast, Event};
+use tcp_echo::broker::Message;
+use tcp_echo::chat::Chat;
+use tcp_echo::codec::{self, Protocol};
+use tcp_echo::config::Config;
+use tcp_echo::governor::{self, Governor};
+use tcp_echo::handler::blocking::handle_chat_connection;
+use tcp_echo::listener;
+use tcp_echo::log::{self, Context};
+use tcp_echo::pool::{self, Executor};
+use tcp_echo::shutdown::blocking::{join_until, Connections};
+use tcp_echo::shutdown::Shutdown;
+use tcp_echo::tls;
+use tcp_echo::{info, trace, warn};
+
+fn main() -> io::Result<()> {
+    let config = Config::from_args();
+    log::init(config.log);
+    let listener = listener::bind(&config.listen);
+    let tls = tls::acceptor(config.tls.as_ref());
+
+    let (broadcast_tx, broadcast_rx) = channel::<Event>();
+
+    // Spawn dedicated thread to broadcast messages to all clients.
+    let chat = Chat::new(&config.chat)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to open chat log: {e}")))?;
+    let overflow = config.outbound.overflow;
+    let broadcaster = thread::spawn(move || {
+        broadcast(broadcast_rx, chat, overflow);
     });
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!(
-                    "{}ms: Connection established",
-                    time_at_start.elapsed().as_millis()
-                );
-
-                let sender_cloned = broadcast_tx.clone();
-                let stream_cloned = stream
-                    .try_clone()
-                    .expect("Failed to clone stream for handler");
-                thread::spawn(move || {
-                    handle_connection(stream_cloned, sender_cloned);
-                });
-                println!("Handler spawned");
-
-                let mut a = user_streams_tx.lock().unwrap();
-                a.push(stream);
-                println!("Client registration complete");
-            }
-            Err(e) => {
-                panic!("Incoming connection failed with error: {e:?}");
-            }
-        }
-
-        println!("Control returned to main loop - waiting for more incoming connections");
+    let shutdown = Shutdown::new(
+        listener
+            .local_addr()
+            .expect("Failed to query listener address"),
+    );
+    shutdown.install_signal_handler();
+    let mut connections = Connections::new();
+    let executor = Executor::new(&config.pool);
+    let governor = Governor::new(config.governor);
+
+    // Each listener accepts connections on a thread of its own. A rendezvous channel hands them to
+    // this loop one at a time, so connections still wait in the OS's backlog while the pool is
+    // full.
+    let (accepted_tx, accepted_rx) = sync_channel(0);
+    if let Some(port) = config.listen.json_port {
+        let json_listener = listener::bind_protocol(&config.listen, port, Protocol::Json);
+        listener::accept_into(json_listener, Protocol::Json, accepted_tx.clone());
+    }
+    if let Some(port) = config.listen.irc_port {
+        let irc_listener = listener::bind_protocol(&config.listen, port, Protocol::Irc);
+        listener::accept_into(irc_listener, Protocol::Irc, accepted_tx.clone());
+    }
+    listener::accept_into(listener, Protocol::Text, accepted_tx);
+    if config.listen.ws_port.is_some() {
+        warn!(
+            Context::SERVER,
+            "option_ignored",
+            "WebSocket clients are only supported by chat_async; ignoring --ws-port"
+        );
     }
-}
-
-/// Continuously broadcasts `Messages` received on the given `broadcast_rx` `Receiver` to every
-/// `TcpStream` in `user_streams_tx`. The latter is wrapped in an `Arc` and `Mutex` to allow the
-/// vector of user streams to be updated by a different thread as new clients connect. If an attempt
-/// to send data to a client user stream fails, the client is assumed to have disconnected and their
-/// client user stream is removed from `user_streams_tx`.
-///
-/// The function loops continuously until an error occurs when trying to read from `broadcast_rx`.
-fn broadcast(broadcast_rx: Receiver<Message>, user_streams_tx: Arc<Mutex<Vec<TcpStream>>>) {
-    println!("Broadcaster started");
-    loop {
-        match broadcast_rx.recv() {
-            Ok(message) => {
-                println!("\tBroadcaster received message: {}", message);
-
-                let mut good_senders = Vec::new();
-                let mut streams = user_streams_tx.lock().unwrap();
-
-                let response_bytes = message.into_bytes();
 
-                for mut stream in streams.drain(..) {
-                    match stream.write_all(&response_bytes) {
-                        Ok(()) => {
-                            println!("\tSucceeded in broadcasting to a channel");
-                            good_senders.push(stream);
-                        }
-                        Err(_) => {
-                            println!("\tFailed to broadcast to a channel");
-                        }
-                    }
-                }
+    for (stream, protocol) in accepted_rx {
+        if shutdown.is_requested() {
+            break;
+        }
 
-                *streams = good_senders;
-            }
+        let context = Context::accepted(stream.peer_addr());
+        let Some(permit) = governor::blocking::admit(&governor, &stream, tls.is_none()) else {
+            continue;
+        };
+
+        // Cloning fails if the server has run out of file descriptors, which only this connection
+        // needs to suffer for.
+        let tracked = stream
+            .try_clone()
+            .and_then(|cloned| Ok((cloned, connections.track(&stream)?)));
+        let (stream_cloned, guard) = match tracked {
+            Ok(tracked) => tracked,
             Err(e) => {
-                println!(
-                    "Broadcaster channel returned '{:?}', so Broadcaster exiting",
-                    e
+                warn!(
+                    context,
+                    "connection_closed", "Closed connection that could not be set up: {e}"
                 );
-                return;
+                continue;
             }
-        }
-    }
-}
-
-/// First asks for the user's display name, then continuously receives newline-delimited input from
-/// the `stream` passed, and sends it as a `Message` to the given `sender` channel. This process is
-/// repeated until `stream` is closed or an error occurs.
-///
-/// # Panics
-///
-/// Panics if an error occurs when sending to `sender` or when attempting to read data from
-/// `stream`.
-fn handle_connection(mut stream: TcpStream, sender: Sender<Message>) {
-    let mut display_name = None;
-
-    let peer = stream
-        .peer_addr()
-        .expect("Failed to query details of the remote peer");
-    println!("\tIncoming connection is from: {peer:?}");
-
-    stream
-        .write_all(b"Enter your display name\n")
-        .expect("Failed to send prompt for user to enter their display name");
-
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-
-    loop {
-        match reader.read_line(&mut line) {
-            Ok(0) => {
-                // End of file
-                println!("\t>>[End of data; closing connection]");
-                return;
+        };
+        let sender_cloned = broadcast_tx.clone();
+        let outbound = config.outbound;
+        // The busy notice is written straight to the stream, which would corrupt a TLS session.
+        let notify = tls.is_none();
+        let tls = tls.clone();
+        let timeouts = config.timeouts;
+        let input = config.input;
+        let job = move || {
+            let _guard = guard;
+            let _permit = permit;
+            let result = handle_chat_connection(
+                stream_cloned,
+                sender_cloned,
+                outbound,
+                protocol,
+                tls.as_ref(),
+                timeouts,
+                input,
+            );
+            if let Err(e) = result {
+                warn!(context, "connection_closed", "Closed connection: {e}");
             }
-            Ok(n) => {
-                print!("\t>>[{n} chars] {line}"); // No need for newline as input contains one
-
-                if display_name.is_none() {
-                    display_name = Some((line.trim()).to_owned());
-
-                    sender
-                        .send(display_name.clone().unwrap().to_owned() + &" has entered the chat\n")
-                        .expect("Failed to send chat entry message to broadcaster");
-                } else {
-                    sender
-                        .send(display_name.clone().unwrap().to_owned() + &": " + &line)
-                        .expect("Failed to send incoming message to broadcaster");
-                }
+        };
+        let dispatched = executor.execute(job, &shutdown);
 
-                line = String::new();
-            }
-            Err(e) => {
-                panic!("\tError while reading from received data:\n\t{e}");
-            }
+        if dispatched.is_err() {
+            pool::reject(stream, notify);
+        } else {
+            trace!(Context::SERVER, "dispatched", "Handler dispatched");
         }
+
+        trace!(
+            Context::SERVER,
+            "accept_loop",
+            "Control returned to main loop - waiting for more incoming connections"
+        );
     }
+
+    info!(Context::SERVER, "shutting_down", "Shutting down");
+    let deadline = Instant::now() + config.shutdown_timeout;
+
+    // The notice must be queued for every client before draining, as each handler deregisters its
+    // client once it stops reading. The handlers then wait for their writers to send everything
+    // queued, and dropping the last sender causes the broadcaster to exit.
+    broadcast_tx
+        .send(Event::Broadcast(Message::system(
+            codec::SHUTDOWN_NOTICE.trim_end(),
+        )))
+        .expect("Failed to send shutdown notice to broadcaster");
+    connections.drain(deadline);
+    drop(broadcast_tx);
+    join_until(broadcaster, deadline);
+
+    connections.close(None);
+    info!(Context::SERVER, "shutdown_complete", "Shutdown complete");
+    Ok(())
 }
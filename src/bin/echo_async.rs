@@ -1,77 +1,98 @@
-use async_std::io::prelude::BufReadExt;
 /// A server that listens on a local IPv6 TCP port for incoming connections and echoes each line of
 /// input from a client back to that client. A simple client connection can be established on the
 /// the same machine by entering something like:
 ///     nc -Nv ::1 8080
 ///
+/// The address and port to listen on can be changed with command-line options; run with `--help`
+/// for details. `--tls-cert` and `--tls-key` make the server accept TLS connections instead of
+/// plaintext ones.
+///
 /// This code uses Rust's async/.await functionality to allow multiple clients to connect and have
-/// their input echoed seemingly in parallel.
-use async_std::io::{BufReader, WriteExt};
-use async_std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
+/// their input echoed seemingly in parallel. On SIGINT or SIGTERM the server stops reading from
+/// every client, gives the tasks time to send their final responses, and sends each client a notice
+/// before exiting.
 use async_std::stream::StreamExt;
 use async_std::task;
 use std::time::Instant;
-
-const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
-const LOCAL_PORT: u16 = 8080;
+use tcp_echo::codec;
+use tcp_echo::config::Config;
+use tcp_echo::governor::{self, Governor};
+use tcp_echo::handler::asynchronous::handle_echo_connection;
+use tcp_echo::listener::{self, AcceptBackoff};
+use tcp_echo::log::{self, Context};
+use tcp_echo::shutdown::asynchronous::Connections;
+use tcp_echo::shutdown::Shutdown;
+use tcp_echo::tls;
+use tcp_echo::{info, trace, warn};
 
 fn main() {
-    let time_at_start = Instant::now();
-    println!("Starting at monotonic clock time: {:?}", time_at_start);
+    let config = Config::from_args();
+    log::init(config.log);
+    let tls = tls::acceptor(config.tls.as_ref());
 
     let accept_loop = async {
-        let socket = SocketAddrV6::new(LOCAL_ADDR_IPV6, LOCAL_PORT, 0, 0);
-        let listener = TcpListener::bind(socket)
-            .await
-            .expect("Failed to bind to port {LOCAL_PORT}");
-
+        let listener = listener::bind_async(&config.listen).await;
         let mut incoming = listener.incoming();
 
+        let shutdown = Shutdown::new(
+            listener
+                .local_addr()
+                .expect("Failed to query listener address"),
+        );
+        shutdown.install_signal_handler();
+        let mut connections = Connections::new();
+
+        let governor = Governor::new(config.governor);
+        let mut backoff = AcceptBackoff::new();
+
         while let Some(stream) = incoming.next().await {
-            let stream = stream.unwrap();
+            if shutdown.is_requested() {
+                break;
+            }
 
-            println!(
-                "{}ms: Connection established",
-                time_at_start.elapsed().as_millis()
-            );
-            task::spawn(handle_connection(stream));
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    task::sleep(backoff.failed(&e)).await;
+                    continue;
+                }
+            };
+            backoff.accepted();
+            let context = Context::accepted(stream.peer_addr());
+            let Some(permit) =
+                governor::asynchronous::admit(&governor, &stream, tls.is_none()).await
+            else {
+                continue;
+            };
 
-            println!("Control returned to main loop - waiting for more incoming connections");
+            let tls = tls.clone();
+            let timeouts = config.timeouts;
+            let input = config.input;
+            let guard = connections.track(&stream);
+            task::spawn(async move {
+                let _guard = guard;
+                let _permit = permit;
+                if let Err(e) = handle_echo_connection(stream, tls, timeouts, input).await {
+                    warn!(context, "connection_closed", "Closed connection: {e}");
+                }
+            });
+
+            trace!(
+                Context::SERVER,
+                "accept_loop",
+                "Control returned to main loop - waiting for more incoming connections"
+            );
         }
+
+        info!(Context::SERVER, "shutting_down", "Shutting down");
+        connections
+            .drain(Instant::now() + config.shutdown_timeout)
+            .await;
+        // The notice is written straight to each stream, which would corrupt a TLS session.
+        let notice = tls.is_none().then_some(codec::SHUTDOWN_NOTICE.as_bytes());
+        connections.close(notice).await;
+        info!(Context::SERVER, "shutdown_complete", "Shutdown complete");
     };
 
     task::block_on(accept_loop);
 }
-
-/// Receives newline-delimited input from `stream`, and sends the same data back on the same stream.
-async fn handle_connection(mut stream: TcpStream) {
-    let peer = stream
-        .peer_addr()
-        .expect("Failed to query details of the remote peer");
-    println!("\tIncoming connection is from: {peer:?}");
-
-    let mut reader = BufReader::new(stream.clone());
-    let mut line = String::new();
-
-    loop {
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                // End of file
-                println!("\t>>[End of data; closing connection]");
-                return;
-            }
-            Ok(n) => {
-                print!("\t>>[{n} chars] {line}"); // No need for newline as input contains one
-                let response_bytes = ("Server responds: ".to_string() + &line).into_bytes();
-                stream
-                    .write_all(&response_bytes)
-                    .await
-                    .expect("Error occurred sending client response");
-                line.clear();
-            }
-            Err(e) => {
-                panic!("\tError while reading from received data:\n\t{e}");
-            }
-        }
-    }
-}
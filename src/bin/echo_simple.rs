@@ -3,71 +3,57 @@
 /// the same machine by entering something like:
 ///     nc -Nv ::1 8080
 ///
+/// The address and port to listen on can be changed with command-line options; run with `--help`
+/// for details. `--tls-cert` and `--tls-key` make the server accept TLS connections instead of
+/// plaintext ones.
+///
 /// This is a simple single-threaded server with no concurrency. It only handles one client
 /// connection at a time and if multiple clients connect concurrently, all but the first receive
 /// no responses to sent data until the first client disconnects. Such sent data will be
 /// responded to once the server begins processing the connection.
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
-use std::time::Instant;
-
-const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
-const LOCAL_PORT: u16 = 8080;
+use std::thread;
+use tcp_echo::config::Config;
+use tcp_echo::governor::{self, Governor};
+use tcp_echo::handler::blocking::handle_echo_connection;
+use tcp_echo::listener::{self, AcceptBackoff};
+use tcp_echo::log::{self, Context};
+use tcp_echo::tls;
+use tcp_echo::{trace, warn};
 
 fn main() {
-    let time_at_start = Instant::now();
-    println!("Starting at monotonic clock time: {:?}", time_at_start);
+    let config = Config::from_args();
+    log::init(config.log);
+    let listener = listener::bind(&config.listen);
+    let tls = tls::acceptor(config.tls.as_ref());
 
-    let socket = SocketAddrV6::new(LOCAL_ADDR_IPV6, LOCAL_PORT, 0, 0);
-    let listener = TcpListener::bind(socket).expect("Failed to bind to port {LOCAL_PORT}");
+    let governor = Governor::new(config.governor);
+    let mut backoff = AcceptBackoff::new();
 
     for stream in listener.incoming() {
         match stream {
-            Ok(mut stream) => {
-                println!(
-                    "{}ms: Connection established",
-                    time_at_start.elapsed().as_millis()
-                );
-                handle_connection(&mut stream);
+            Ok(stream) => {
+                backoff.accepted();
+                let context = Context::accepted(stream.peer_addr());
+                let Some(_permit) = governor::blocking::admit(&governor, &stream, tls.is_none())
+                else {
+                    continue;
+                };
+                if let Err(e) =
+                    handle_echo_connection(&stream, tls.as_ref(), config.timeouts, config.input)
+                {
+                    warn!(context, "connection_closed", "Closed connection: {e}");
+                }
             }
             Err(e) => {
-                panic!("Incoming connection failed with error: {e:?}",);
+                thread::sleep(backoff.failed(&e));
+                continue;
             }
         }
 
-        println!("Control returned to main loop - waiting for more incoming connections");
-    }
-}
-
-/// Receives newline-delimited input from `stream`, and sends the same data back on the same stream.
-fn handle_connection(stream: &mut TcpStream) {
-    let peer = stream
-        .peer_addr()
-        .expect("Failed to query details of the remote peer");
-    println!("\tIncoming connection is from: {peer:?}");
-
-    let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone network stream"));
-    let mut line = String::new();
-
-    loop {
-        match reader.read_line(&mut line) {
-            Ok(0) => {
-                // End of file
-                println!("\t>>[End of data; closing connection]");
-                return;
-            }
-            Ok(n) => {
-                // Ok(n) if n > 0 => {
-                print!("\t>>[{n} chars] {line}"); // No need for newline as input contains one
-                let response_bytes = ("Server responds: ".to_string() + &line).into_bytes();
-                stream
-                    .write_all(&response_bytes)
-                    .expect("Error occurred sending client response");
-                line.clear();
-            }
-            Err(e) => {
-                panic!("\tError while reading from received data:\n\t{e}");
-            }
-        }
+        trace!(
+            Context::SERVER,
+            "accept_loop",
+            "Control returned to main loop - waiting for more incoming connections"
+        );
     }
 }
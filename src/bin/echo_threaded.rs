@@ -3,76 +3,113 @@
 /// the same machine by entering something like:
 ///     nc -Nv ::1 8080
 ///
+/// The address and port to listen on can be changed with command-line options; run with `--help`
+/// for details. `--tls-cert` and `--tls-key` make the server accept TLS connections instead of
+/// plaintext ones.
+///
 /// This uses the concurrency provided by `std::thread` to handle each client's connection in a
-/// separate OS thread. The child threads are detached from the parent thread, so the parent does
-/// not need to wait for them to finish as part of program clean-up. OS threads are a bit overkill
-/// for this simple task, but required minimal changes to the code to implement.
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
+/// separate OS thread. The child threads are detached from the parent thread, but each connection
+/// is tracked so that on SIGINT or SIGTERM the server can stop reading from every client, give the
+/// threads time to send their final responses, and send each client a notice before exiting. OS
+/// threads are a bit overkill for this simple task, but required minimal changes to the code to
+/// implement.
+///
+/// Alternatively, `--pool-size` runs the connections on a fixed pool of worker threads, so the
+/// two approaches can be compared on the same workload.
 use std::thread;
-use std::time::Instant; // NEW for threading
-
-const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
-const LOCAL_PORT: u16 = 8080;
+use std::time::Instant;
+use tcp_echo::codec;
+use tcp_echo::config::Config;
+use tcp_echo::governor::{self, Governor};
+use tcp_echo::handler::blocking::handle_echo_connection;
+use tcp_echo::listener::{self, AcceptBackoff};
+use tcp_echo::log::{self, Context};
+use tcp_echo::pool::{self, Executor};
+use tcp_echo::shutdown::blocking::Connections;
+use tcp_echo::shutdown::Shutdown;
+use tcp_echo::tls;
+use tcp_echo::{info, trace, warn};
 
 fn main() {
-    let time_at_start = Instant::now();
-    println!("Starting at monotonic clock time: {:?}", time_at_start);
+    let config = Config::from_args();
+    log::init(config.log);
+    let listener = listener::bind(&config.listen);
+    let tls = tls::acceptor(config.tls.as_ref());
 
-    let socket = SocketAddrV6::new(LOCAL_ADDR_IPV6, LOCAL_PORT, 0, 0);
-    let listener = TcpListener::bind(socket).expect("Failed to bind to port {LOCAL_PORT}");
+    let shutdown = Shutdown::new(
+        listener
+            .local_addr()
+            .expect("Failed to query listener address"),
+    );
+    shutdown.install_signal_handler();
+    let mut connections = Connections::new();
+    let executor = Executor::new(&config.pool);
+    let governor = Governor::new(config.governor);
+
+    let mut backoff = AcceptBackoff::new();
 
     for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                println!(
-                    "{}ms: Connection established",
-                    time_at_start.elapsed().as_millis()
-                );
+        if shutdown.is_requested() {
+            break;
+        }
 
-                #[rustfmt::skip]
-                thread::spawn(move || { // NEW for threading
-                    handle_connection(&mut stream);
-                }); // NEW for threading
-            }
+        let stream = match stream {
+            Ok(stream) => stream,
             Err(e) => {
-                panic!("Incoming connection failed with error: {e:?}",);
+                thread::sleep(backoff.failed(&e));
+                continue;
             }
-        }
-
-        println!("Control returned to main loop - waiting for more incoming connections");
-    }
-}
-
-/// Receives newline-delimited input from `stream`, and sends the same data back on the same stream.
-fn handle_connection(stream: &mut TcpStream) {
-    let peer = stream
-        .peer_addr()
-        .expect("Failed to query details of the remote peer");
-    println!("\tIncoming connection is from: {peer:?}");
-
-    let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone network stream"));
-    let mut line = String::new();
+        };
+        backoff.accepted();
+        let context = Context::accepted(stream.peer_addr());
+        let Some(permit) = governor::blocking::admit(&governor, &stream, tls.is_none()) else {
+            continue;
+        };
 
-    loop {
-        match reader.read_line(&mut line) {
-            Ok(0) => {
-                // End of file
-                println!("\t>>[End of data; closing connection]");
-                return;
-            }
-            Ok(n) => {
-                // Ok(n) if n > 0 => {
-                print!("\t>>[{n} chars] {line}"); // No need for newline as input contains one
-                let response_bytes = ("Server responds: ".to_string() + &line).into_bytes();
-                stream
-                    .write_all(&response_bytes)
-                    .expect("Error occurred sending client response");
-                line.clear();
-            }
+        // Cloning fails if the server has run out of file descriptors, which only this connection
+        // needs to suffer for.
+        let tracked = stream
+            .try_clone()
+            .and_then(|cloned| Ok((cloned, connections.track(&stream)?)));
+        let (stream_cloned, guard) = match tracked {
+            Ok(tracked) => tracked,
             Err(e) => {
-                panic!("\tError while reading from received data:\n\t{e}");
+                warn!(
+                    context,
+                    "connection_closed", "Closed connection that could not be set up: {e}"
+                );
+                continue;
+            }
+        };
+        // The busy notice is written straight to the stream, which would corrupt a TLS session.
+        let notify = tls.is_none();
+        let tls = tls.clone();
+        let timeouts = config.timeouts;
+        let input = config.input;
+        let job = move || {
+            let _guard = guard;
+            let _permit = permit;
+            if let Err(e) = handle_echo_connection(&stream_cloned, tls.as_ref(), timeouts, input) {
+                warn!(context, "connection_closed", "Closed connection: {e}");
             }
+        };
+        let dispatched = executor.execute(job, &shutdown);
+
+        if dispatched.is_err() {
+            pool::reject(stream, notify);
         }
+
+        trace!(
+            Context::SERVER,
+            "accept_loop",
+            "Control returned to main loop - waiting for more incoming connections"
+        );
     }
+
+    info!(Context::SERVER, "shutting_down", "Shutting down");
+    connections.drain(Instant::now() + config.shutdown_timeout);
+    // The notice is written straight to each stream, which would corrupt a TLS session.
+    let notice = tls.is_none().then_some(codec::SHUTDOWN_NOTICE.as_bytes());
+    connections.close(notice);
+    info!(Context::SERVER, "shutdown_complete", "Shutdown complete");
 }
@@ -0,0 +1,344 @@
+/// A load generator for benchmarking the echo and chat servers against each other. It opens a
+/// number of concurrent connections to a server, each in its own thread, and has every connection
+/// send lines at a fixed rate for a fixed duration. Each line is timed until the server's reply to
+/// it arrives, e.g., the `Server responds: ` line from an echo server, or the broadcast of the line
+/// back to its sender from a chat server. For example, to load a chat server with 50 clients that
+/// each send 20 lines per second for 30 seconds:
+///     cargo run --release --bin load_gen -- --mode chat --connections 50 --rate 20 --duration 30
+///
+/// When finished, it reports the throughput, the percentiles of the round-trip latency and of the
+/// time to the first byte received on each connection, and the number of errors.
+use std::env;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpStream};
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tcp_echo::codec;
+use tcp_echo::config::{next_value, ConfigError};
+use tcp_echo::listener::LOCAL_PORT;
+
+const USAGE: &str = "\
+Options:
+    --target <ADDRESS:PORT>
+                        Server to connect to [default: [::1]:8080]
+    --mode <MODE>       Protocol the server speaks, either 'echo' or 'chat' [default: echo]
+    --connections <N>   Number of concurrent connections [default: 10]
+    --rate <LINES>      Lines sent per second on each connection; 0 sends the next line as soon
+                        as the reply to the previous one arrives [default: 10]
+    --duration <SECONDS>
+                        How long to send lines for [default: 10]
+    --timeout <SECONDS> How long to wait to connect or for a reply before giving up on a
+                        connection [default: 5]
+    -h, --help          Print this help and exit";
+
+/// The protocol spoken by the server under test, which determines what a reply looks like.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Echo,
+    Chat,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "echo" => Ok(Self::Echo),
+            "chat" => Ok(Self::Chat),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct LoadConfig {
+    target: SocketAddr,
+    mode: Mode,
+    connections: usize,
+    /// Lines per second sent on each connection, or 0 for as fast as replies arrive.
+    rate: f64,
+    duration: Duration,
+    timeout: Duration,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            target: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), LOCAL_PORT),
+            mode: Mode::Echo,
+            connections: 10,
+            rate: 10.0,
+            duration: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl LoadConfig {
+    /// Parses the configuration from the program's command-line arguments, exiting the process if
+    /// help is requested or the arguments are invalid.
+    fn from_args() -> Self {
+        let mut args = env::args();
+        let program = args.next().unwrap_or_default();
+
+        match Self::parse(args) {
+            Ok(config) => config,
+            Err(ConfigError::Help) => {
+                println!("Usage: {program} [OPTIONS]\n\n{USAGE}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{e}\n\nUsage: {program} [OPTIONS]\n\n{USAGE}");
+                process::exit(2);
+            }
+        }
+    }
+
+    fn parse<I>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--target" => config.target = next_value(&mut args, &arg)?,
+                "--mode" => config.mode = next_value(&mut args, &arg)?,
+                "--connections" => config.connections = next_value(&mut args, &arg)?,
+                "--rate" => config.rate = next_rate(&mut args, &arg)?,
+                "--duration" => config.duration = Duration::from_secs(next_value(&mut args, &arg)?),
+                "--timeout" => config.timeout = Duration::from_secs(next_value(&mut args, &arg)?),
+                "-h" | "--help" => return Err(ConfigError::Help),
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Takes the lines per second following `flag` from `args`. The rate must be 0, or a positive
+/// number large enough that the interval between lines can be represented.
+fn next_rate<I>(args: &mut I, flag: &str) -> Result<f64, ConfigError>
+where
+    I: Iterator<Item = String>,
+{
+    let value: String = next_value(args, flag)?;
+    let rate = value.parse::<f64>().ok().filter(|&rate| {
+        rate == 0.0 || (rate.is_finite() && Duration::try_from_secs_f64(1.0 / rate).is_ok())
+    });
+    rate.ok_or_else(|| ConfigError::InvalidValue {
+        flag: flag.to_owned(),
+        value,
+    })
+}
+
+/// Measurements gathered by one or more connections.
+#[derive(Debug, Default)]
+struct Stats {
+    connect_errors: usize,
+    timeouts: usize,
+    io_errors: usize,
+    lines_sent: usize,
+    round_trips: Vec<Duration>,
+    first_bytes: Vec<Duration>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.connect_errors += other.connect_errors;
+        self.timeouts += other.timeouts;
+        self.io_errors += other.io_errors;
+        self.lines_sent += other.lines_sent;
+        self.round_trips.extend(other.round_trips);
+        self.first_bytes.extend(other.first_bytes);
+    }
+}
+
+fn main() {
+    let config = LoadConfig::from_args();
+    println!(
+        "Opening {} connections to {:?} server at {} for {:?}",
+        config.connections, config.mode, config.target, config.duration
+    );
+
+    let time_at_start = Instant::now();
+    let deadline = time_at_start + config.duration;
+
+    let clients: Vec<_> = (0..config.connections)
+        .map(|id| {
+            let config = config.clone();
+            thread::spawn(move || run_client(id, &config, deadline))
+        })
+        .collect();
+
+    let mut stats = Stats::default();
+    for client in clients {
+        stats.merge(client.join().expect("Client thread panicked"));
+    }
+
+    report(&config, stats, time_at_start.elapsed());
+}
+
+/// Connects to the server and sends lines until `deadline`, returning the measurements made.
+fn run_client(id: usize, config: &LoadConfig, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+
+    let time_at_connect = Instant::now();
+    let stream = match TcpStream::connect_timeout(&config.target, config.timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("\tConnection {id} failed: {e}");
+            stats.connect_errors += 1;
+            return stats;
+        }
+    };
+
+    if let Err(e) = run_session(id, config, deadline, time_at_connect, stream, &mut stats) {
+        println!("\tConnection {id} ended early: {e}");
+
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => stats.timeouts += 1,
+            _ => stats.io_errors += 1,
+        }
+    }
+
+    stats
+}
+
+/// Sends lines on `stream` at the configured rate until `deadline`, waiting for the reply to each
+/// before sending the next.
+fn run_session(
+    id: usize,
+    config: &LoadConfig,
+    deadline: Instant,
+    time_at_connect: Instant,
+    mut stream: TcpStream,
+    stats: &mut Stats,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(config.timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let display_name = format!("load{id}");
+    let mut line = String::new();
+
+    if config.mode == Mode::Chat {
+        // The server speaks first, asking for a display name.
+        wait_for_first_byte(&mut reader, time_at_connect, stats)?;
+        read_reply(&mut reader, &mut line)?;
+        stream.write_all(format!("{display_name}\n").as_bytes())?;
+    }
+
+    let interval = (config.rate > 0.0).then(|| Duration::from_secs_f64(1.0 / config.rate));
+    let mut next_send = Instant::now();
+
+    for seq in 0.. {
+        if let Some(interval) = interval {
+            thread::sleep(next_send.saturating_duration_since(Instant::now()));
+            next_send += interval;
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(());
+        }
+
+        let request = format!("c{id}-{seq}\n");
+        let expected = match config.mode {
+            Mode::Echo => codec::echo_response(&request),
+            Mode::Chat => codec::chat_line(&display_name, &request),
+        };
+
+        let time_at_send = Instant::now();
+        stream.write_all(request.as_bytes())?;
+        stats.lines_sent += 1;
+
+        if stats.first_bytes.is_empty() {
+            wait_for_first_byte(&mut reader, time_at_connect, stats)?;
+        }
+
+        // A chat server also relays other clients' lines, which are skipped.
+        loop {
+            read_reply(&mut reader, &mut line)?;
+            if line == expected {
+                break;
+            }
+        }
+
+        stats.round_trips.push(time_at_send.elapsed());
+    }
+
+    Ok(())
+}
+
+/// Waits until data is available from the server and records the time taken since connecting.
+fn wait_for_first_byte(
+    reader: &mut BufReader<TcpStream>,
+    time_at_connect: Instant,
+    stats: &mut Stats,
+) -> io::Result<()> {
+    if reader.fill_buf()?.is_empty() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    stats.first_bytes.push(time_at_connect.elapsed());
+    Ok(())
+}
+
+/// Reads the next line from the server into `line`, treating the end of the stream as an error.
+fn read_reply(reader: &mut BufReader<TcpStream>, line: &mut String) -> io::Result<()> {
+    line.clear();
+
+    match reader.read_line(line)? {
+        0 => Err(ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Prints a summary of `stats`, gathered over `elapsed`.
+fn report(config: &LoadConfig, mut stats: Stats, elapsed: Duration) {
+    stats.round_trips.sort();
+    stats.first_bytes.sort();
+
+    let replies = stats.round_trips.len();
+
+    println!("\nResults after {elapsed:.2?}");
+    println!(
+        "  Connections:   {} opened, {} failed to connect, {} timed out, {} other errors",
+        config.connections - stats.connect_errors,
+        stats.connect_errors,
+        stats.timeouts,
+        stats.io_errors
+    );
+    println!(
+        "  Lines:         {} sent, {replies} replies",
+        stats.lines_sent
+    );
+    println!(
+        "  Throughput:    {:.1} replies/s",
+        replies as f64 / elapsed.as_secs_f64()
+    );
+    println!("  Round trip:    {}", percentiles(&stats.round_trips));
+    println!("  First byte:    {}", percentiles(&stats.first_bytes));
+}
+
+/// Formats the p50, p95, p99 and maximum of `sorted`, which must be in ascending order.
+fn percentiles(sorted: &[Duration]) -> String {
+    if sorted.is_empty() {
+        return "no samples".to_owned();
+    }
+
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    };
+
+    format!(
+        "p50 {:.2?}, p95 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(50.0),
+        percentile(95.0),
+        percentile(99.0),
+        sorted[sorted.len() - 1]
+    )
+}
@@ -0,0 +1,256 @@
+//! The chat broker, which receives every line read by the chat connection handlers, has a `Chat`
+//! decide who should receive what in response, and relays the resulting `Message`s to those
+//! clients.
+//!
+//! A `Message` records who sent what, when and where, rather than the text sent to clients. The
+//! broker formats each message separately for every client it is delivered to, so the same message
+//! can read differently depending on who receives it.
+//!
+//! Rather than writing to clients' streams itself, the broker pushes each message onto a bounded
+//! outbound queue per client, which a dedicated writer thread or task for that client drains onto
+//! its stream. A client whose connection cannot keep up therefore only fills its own queue, and what
+//! happens then is decided by the configured `OverflowPolicy`, instead of stalling every other
+//! client.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::codec::DISPLAY_NAME_PROMPT;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Uniquely identifies a client connection for as long as the server runs.
+pub type ClientId = u64;
+
+/// Returns a `ClientId` that has not been returned before.
+pub fn next_client_id() -> ClientId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The events connection handlers send to the broker. `C` is the handle through which the broker
+/// sends messages to a client, which differs between the blocking and asynchronous flavours.
+#[derive(Debug)]
+pub enum Event<C> {
+    /// A client has connected and should be prompted for its display name.
+    Connect { id: ClientId, client: C },
+    /// A line of input from a client, including its trailing newline.
+    Input { id: ClientId, line: String },
+    /// A message from the server itself to send to every connected client.
+    Broadcast(Message),
+    /// A message from the server itself to send to client `id` alone, such as a warning that it is
+    /// about to be disconnected.
+    Notify { id: ClientId, message: Message },
+    /// A client has disconnected, or its connection has failed, for the given reason. Messages
+    /// already queued for it are still written if possible.
+    Disconnect { id: ClientId, reason: LeaveReason },
+}
+
+/// What a chat `Message` is about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// The sender entered the chat or, if the message has a room, joined that room.
+    Join,
+    /// The sender left the chat, for the reason given in the body, or, if the message has a room,
+    /// left that room.
+    Leave,
+    /// A line of chat sent to a room.
+    Chat,
+    /// A description of what the sender is doing, sent to a room with `/me`.
+    Action,
+    /// The sender changed its display name from the one given in the body.
+    Rename,
+    /// A message sent only to the client called `recipient`, and echoed to its sender.
+    Private { recipient: String },
+    /// The display names of the members of the message's room, sent in response to `/who`.
+    Users { names: Vec<String> },
+    /// A notice from the server itself, such as the response to a command.
+    System,
+    /// The server asking a client that has not entered the chat yet for its display name.
+    Prompt,
+    /// The server's answer to a client checking its connection is alive, echoing the token in the
+    /// body.
+    Pong,
+    /// A notice from the server itself that something the recipient asked for could not be done.
+    Error(ErrorCode),
+}
+
+impl MessageKind {
+    /// Returns the name of the kind of message, e.g. `chat`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Join => "join",
+            Self::Leave => "leave",
+            Self::Chat => "chat",
+            Self::Action => "action",
+            Self::Rename => "rename",
+            Self::Private { .. } => "private",
+            Self::Users { .. } => "users",
+            Self::System => "system",
+            Self::Prompt => "prompt",
+            Self::Pong => "pong",
+            Self::Error(_) => "error",
+        }
+    }
+}
+
+/// Why something a client asked for could not be done, for protocols that tell errors apart. Most
+/// codes give the display name, room or command concerned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The client's input could not be understood, or any other error without a code of its own.
+    Invalid,
+    /// Nobody is using the display name.
+    NoSuchUser(String),
+    /// The room name cannot be used.
+    InvalidRoom(String),
+    /// The client is not in the room, or in any room if none is given.
+    NotInRoom(Option<String>),
+    /// Another client is using the display name.
+    NameTaken(String),
+    /// The display name cannot be used.
+    InvalidName(String),
+    UnknownCommand(String),
+    /// The command was not given the arguments it needs.
+    MissingArguments(String),
+}
+
+/// Something sent to one or more chat clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// The client that sent the message, or `None` for a message from the server itself or one
+    /// reloaded from the chat log.
+    pub sender: Option<ClientId>,
+    /// The sender's display name when the message was sent, or an empty string for a message from
+    /// the server itself.
+    pub display_name: String,
+    pub kind: MessageKind,
+    pub time: SystemTime,
+    /// The room the message was sent to, if any.
+    pub room: Option<String>,
+    /// The text of the message, without a trailing newline. Notices from the server may span
+    /// several lines.
+    pub body: String,
+    /// Whether the message is being replayed from a room's history, rather than having just been
+    /// sent.
+    pub replayed: bool,
+}
+
+impl Message {
+    /// Returns a message of the given `kind` sent now by client `sender`, called `display_name`.
+    pub fn new(
+        sender: ClientId,
+        display_name: &str,
+        kind: MessageKind,
+        room: Option<&str>,
+        body: impl Into<String>,
+    ) -> Self {
+        Self {
+            sender: Some(sender),
+            display_name: display_name.to_owned(),
+            kind,
+            time: SystemTime::now(),
+            room: room.map(str::to_owned),
+            body: body.into(),
+            replayed: false,
+        }
+    }
+
+    /// Returns a notice from the server itself containing `body`.
+    pub fn system(body: impl Into<String>) -> Self {
+        Self::from_server(MessageKind::System, None, body.into())
+    }
+
+    /// Returns an error from the server itself with the given `code`, described by `body`.
+    pub fn error(code: ErrorCode, body: impl Into<String>) -> Self {
+        Self::from_server(MessageKind::Error(code), None, body.into())
+    }
+
+    /// Returns the prompt for a client's display name.
+    pub fn prompt() -> Self {
+        Self::from_server(MessageKind::Prompt, None, DISPLAY_NAME_PROMPT.to_owned())
+    }
+
+    /// Returns the answer to a client checking its connection with `token`.
+    pub fn pong(token: impl Into<String>) -> Self {
+        Self::from_server(MessageKind::Pong, None, token.into())
+    }
+
+    /// Returns a list of the display names of the members of `room`.
+    pub fn users(room: &str, names: Vec<String>) -> Self {
+        Self::from_server(MessageKind::Users { names }, Some(room), String::new())
+    }
+
+    fn from_server(kind: MessageKind, room: Option<&str>, body: String) -> Self {
+        Self {
+            sender: None,
+            display_name: String::new(),
+            kind,
+            time: SystemTime::now(),
+            room: room.map(str::to_owned),
+            body,
+            replayed: false,
+        }
+    }
+
+    /// Returns a copy of the message marked as replayed from history.
+    pub fn replay(&self) -> Self {
+        Self {
+            replayed: true,
+            ..self.clone()
+        }
+    }
+}
+
+/// Why a client left the chat, as given in the announcement of its departure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// The client closed its connection or used `/quit`.
+    Quit,
+    /// The client was disconnected for being idle or taking too long to send or receive data.
+    Timeout,
+    /// The connection failed while reading from or writing to the client.
+    Reset,
+    /// The client fell too far behind reading its messages and was disconnected.
+    TooSlow,
+    /// The client sent something the server does not accept and was disconnected.
+    InvalidInput,
+    /// The client kept sending lines faster than the message rate allows and was disconnected.
+    Flooding,
+}
+
+impl LeaveReason {
+    /// Returns the reason a client left, given the error that occurred reading from or writing to
+    /// it.
+    pub fn from_io_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout,
+            io::ErrorKind::InvalidData => Self::InvalidInput,
+            _ => Self::Reset,
+        }
+    }
+}
+
+impl fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Quit => "quit",
+            Self::Timeout => "timeout",
+            Self::Reset => "connection reset",
+            Self::TooSlow => "too slow",
+            Self::InvalidInput => "invalid input",
+            Self::Flooding => "flooding",
+        })
+    }
+}
+
+/// The reasons a message cannot be pushed onto a client's outbound queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The queue is full and the `OverflowPolicy` does not allow making room.
+    Full,
+    /// The queue has been closed, because the client disconnected or its writer failed.
+    Closed,
+}
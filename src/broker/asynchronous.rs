@@ -0,0 +1,309 @@
+//! The chat broker built on the async-std crate. The broadcaster runs as a dedicated task, as does
+//! the writer of each client.
+
+use super::{ClientId, LeaveReason, PushError};
+use crate::chat::{Chat, Delivery};
+use crate::codec::{self, Protocol};
+use crate::config::OverflowPolicy;
+use crate::log::Context;
+use crate::timeout::asynchronous::write_within;
+use crate::tls::asynchronous::Writer;
+use crate::websocket::asynchronous::FrameWriter;
+use crate::websocket::{Opcode, CLOSE_NORMAL};
+use crate::{debug, info, warn};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::io::WriteExt;
+use async_std::net::TcpStream;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::Shutdown;
+use std::time::Duration;
+
+/// The events sent to the broadcaster by the asynchronous connection handlers.
+pub type Event = super::Event<ClientHandle>;
+
+/// A bounded queue of messages, formatted as text, waiting to be written to a client. Clones share
+/// the same queue.
+#[derive(Clone, Debug)]
+pub struct OutboundQueue {
+    sender: Sender<String>,
+    receiver: Receiver<String>,
+}
+
+impl OutboundQueue {
+    /// Creates an empty queue holding up to `capacity` messages, or 1 if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = channel::bounded(capacity.max(1));
+        Self { sender, receiver }
+    }
+
+    /// Adds `message` to the back of the queue. If the queue is full, `overflow` determines whether
+    /// this discards the oldest message, fails with `PushError::Full`, or waits for room.
+    pub async fn push(&self, message: String, overflow: OverflowPolicy) -> Result<(), PushError> {
+        if overflow == OverflowPolicy::Block {
+            return self
+                .sender
+                .send(message)
+                .await
+                .map_err(|_| PushError::Closed);
+        }
+
+        let mut message = message;
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(_)) => return Err(PushError::Closed),
+                Err(TrySendError::Full(_)) if overflow == OverflowPolicy::DropClient => {
+                    return Err(PushError::Full);
+                }
+                Err(TrySendError::Full(returned)) => {
+                    // The writer may take the oldest message first, in which case there is now
+                    // room anyway.
+                    let _ = self.receiver.try_recv();
+                    message = returned;
+                }
+            }
+        }
+    }
+
+    /// Removes the message at the front of the queue, waiting for one if the queue is empty.
+    /// Returns `None` once the queue has been closed and emptied.
+    pub async fn pop(&self) -> Option<String> {
+        self.receiver.recv().await.ok()
+    }
+
+    /// Closes the queue, so no more messages can be pushed. Messages already queued can still be
+    /// popped.
+    pub fn close(&self) {
+        self.sender.close();
+    }
+}
+
+/// The broadcaster's handle on a connected client.
+#[derive(Debug)]
+pub struct ClientHandle {
+    pub queue: OutboundQueue,
+    /// The protocol the client speaks, which decides how its input is decoded and how messages to
+    /// it are encoded.
+    pub protocol: Protocol,
+    /// Whether control characters and terminal escape sequences are removed from the client's
+    /// input once it is decoded and the client has entered its display name.
+    pub strip_controls: bool,
+    /// A clone of the client's stream, used to disconnect a client that has fallen behind.
+    pub stream: TcpStream,
+}
+
+impl ClientHandle {
+    /// Closes the client's queue and connection, discarding any messages still queued. This also
+    /// ends the client's handler, as its next read fails.
+    pub fn disconnect(&self) {
+        self.queue.close();
+        // Fails harmlessly if the client has already disconnected.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Continuously receives `Event`s from connection handlers on `broadcast_rx`, keeping track of the
+/// connected clients and passing every line of input to `chat`, decoded according to the protocol
+/// its sender speaks. `chat` decides which clients should receive which `Message`s in response.
+/// Each `Message` is encoded in its recipient's protocol and pushed onto the recipient's outbound
+/// queue. A client whose queue is full is dealt with according to `overflow`, and a client
+/// whose queue has been closed by its writer is assumed to have disconnected and is forgotten.
+///
+/// The function loops continuously until an error occurs when trying to read from `broadcast_rx`,
+/// which happens once every sender has been dropped. It then closes the queue of every remaining
+/// client, so their writers exit once they have written everything queued.
+pub async fn broadcast(broadcast_rx: Receiver<Event>, mut chat: Chat, overflow: OverflowPolicy) {
+    info!(
+        Context::SERVER,
+        "broadcaster_started", "Broadcaster started"
+    );
+    let mut clients = BTreeMap::<ClientId, ClientHandle>::new();
+
+    loop {
+        match broadcast_rx.recv().await {
+            Ok(Event::Connect { id, client }) => {
+                info!(
+                    Context::client(id),
+                    "client_registered", "Broadcaster registered client"
+                );
+                clients.insert(id, client);
+                let deliveries = chat.connect(id);
+                deliver(deliveries, &mut chat, &mut clients, overflow).await;
+            }
+            Ok(Event::Input { id, line }) => {
+                let Some(client) = clients.get(&id) else {
+                    continue;
+                };
+                // Display names are left as they are, so that one containing control characters
+                // is refused rather than quietly changed.
+                let strip = client.strip_controls && chat.display_name(id).is_some();
+                let deliveries = codec::decode(client.protocol, line)
+                    .into_iter()
+                    .map(|input| match strip {
+                        true => input.strip_controls(),
+                        false => input,
+                    })
+                    .flat_map(|input| chat.input(id, input))
+                    .collect();
+                deliver(deliveries, &mut chat, &mut clients, overflow).await;
+            }
+            Ok(Event::Broadcast(message)) => {
+                debug!(
+                    Context::SERVER,
+                    "message_received", "Broadcaster received message: {}", message.body
+                );
+                let deliveries = chat.announce(&message);
+                deliver(deliveries, &mut chat, &mut clients, overflow).await;
+            }
+            Ok(Event::Notify { id, message }) => {
+                let deliveries = vec![Delivery::new(id, message)];
+                deliver(deliveries, &mut chat, &mut clients, overflow).await;
+            }
+            Ok(Event::Disconnect { id, reason }) => {
+                if let Some(client) = clients.remove(&id) {
+                    info!(
+                        Context::client(id),
+                        "client_removed", "Broadcaster removed client ({reason})"
+                    );
+                    client.queue.close();
+                }
+                let deliveries = chat.disconnect(id, reason);
+                deliver(deliveries, &mut chat, &mut clients, overflow).await;
+            }
+            Err(e) => {
+                info!(
+                    Context::SERVER,
+                    "broadcaster_stopped",
+                    "Broadcaster channel returned '{e:?}', so Broadcaster exiting"
+                );
+
+                for client in clients.values() {
+                    client.queue.close();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Pushes each of `deliveries` onto the outbound queue of its recipient in `clients`, or closes the
+/// queue of a client that is to be disconnected. A recipient whose queue is full is disconnected,
+/// and any recipient that cannot receive its message is removed from `clients` and `chat`, which
+/// announces its departure to the remaining clients.
+async fn deliver(
+    deliveries: Vec<Delivery>,
+    chat: &mut Chat,
+    clients: &mut BTreeMap<ClientId, ClientHandle>,
+    overflow: OverflowPolicy,
+) {
+    let mut pending = VecDeque::from(deliveries);
+
+    while let Some(delivery) = pending.pop_front() {
+        let (to, message) = match delivery {
+            Delivery::Send { to, message } => (to, message),
+            Delivery::Close { to } => {
+                // The writer shuts the connection down once it has written everything queued.
+                if let Some(client) = clients.get(&to) {
+                    client.queue.close();
+                }
+                continue;
+            }
+        };
+
+        let Some(client) = clients.get(&to) else {
+            continue;
+        };
+
+        // Some protocols have no equivalent of some messages.
+        let encoded = codec::encode(client.protocol, &message, to, chat);
+        if encoded.is_empty() {
+            continue;
+        }
+
+        let reason = match client.queue.push(encoded, overflow).await {
+            Ok(()) => continue,
+            Err(PushError::Full) => {
+                warn!(
+                    Context::client(to),
+                    "client_too_slow", "Client fell too far behind; disconnecting it"
+                );
+                client.disconnect();
+                LeaveReason::TooSlow
+            }
+            Err(PushError::Closed) => {
+                warn!(
+                    Context::client(to),
+                    "broadcast_failed", "Failed to broadcast to client; removing it"
+                );
+                LeaveReason::Reset
+            }
+        };
+
+        clients.remove(&to);
+        pending.extend(chat.disconnect(to, reason));
+    }
+}
+
+/// How the messages in a client's outbound queue are written to its stream.
+pub enum Framing {
+    /// Each message is written to the `Writer` as it is, ending with a newline.
+    Lines(Writer),
+    /// Each message is sent through the `FrameWriter` as a WebSocket text message, without its
+    /// trailing newline.
+    WebSocket(FrameWriter),
+}
+
+/// Writes every message pushed onto `queue` to client `id`, framed according to `framing`, until the
+/// queue is closed and emptied, or a write fails or takes longer than `write_timeout`. In either
+/// case, `queue` is closed and `stream` is shut down before returning, after closing any WebSocket
+/// connection and TLS session cleanly. If a write fails, the broadcaster is told through `broker`
+/// that client `id` has gone, so it does not have to wait for the next message to the client to
+/// find out.
+pub async fn write_outbound(
+    id: ClientId,
+    queue: OutboundQueue,
+    stream: TcpStream,
+    mut framing: Framing,
+    write_timeout: Option<Duration>,
+    broker: Sender<Event>,
+) {
+    while let Some(message) = queue.pop().await {
+        let written = write_within(write_timeout, async {
+            match &mut framing {
+                Framing::Lines(writer) => writer.write_all(message.as_bytes()).await,
+                Framing::WebSocket(writer) => {
+                    let message = message.strip_suffix('\n').unwrap_or(&message);
+                    writer.send(Opcode::Text, message.as_bytes()).await
+                }
+            }
+        })
+        .await;
+        if let Err(e) = written {
+            warn!(
+                Context::client(id),
+                "write_failed", "Failed to write to client: {e}"
+            );
+            queue.close();
+            let _ = broker
+                .send(Event::Disconnect {
+                    id,
+                    reason: LeaveReason::from_io_error(&e),
+                })
+                .await;
+            break;
+        }
+    }
+
+    queue.close();
+    // These fail harmlessly if the client has already disconnected.
+    match &mut framing {
+        Framing::Lines(writer) => {
+            let _ = futures_lite::AsyncWriteExt::close(writer).await;
+        }
+        Framing::WebSocket(writer) => {
+            let _ = writer.close(CLOSE_NORMAL).await;
+            let _ = writer.shutdown().await;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
@@ -0,0 +1,298 @@
+//! The chat broker built on `std::thread`. The broadcaster runs in a dedicated thread, as does the
+//! writer of each client.
+
+use super::{ClientId, LeaveReason, PushError};
+use crate::chat::{Chat, Delivery};
+use crate::codec::{self, Protocol};
+use crate::config::OverflowPolicy;
+use crate::log::Context;
+use crate::tls::blocking::Writer;
+use crate::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The events sent to the broadcaster by the blocking connection handlers.
+pub type Event = super::Event<ClientHandle>;
+
+/// A bounded queue of messages, formatted as text, waiting to be written to a client. Clones share the same queue.
+#[derive(Clone, Debug)]
+pub struct OutboundQueue {
+    shared: Arc<(Mutex<QueueState>, Condvar)>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    messages: VecDeque<String>,
+    closed: bool,
+}
+
+impl OutboundQueue {
+    /// Creates an empty queue holding up to `capacity` messages, or 1 if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new((Mutex::new(QueueState::default()), Condvar::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Adds `message` to the back of the queue. If the queue is full, `overflow` determines whether
+    /// this discards the oldest message, fails with `PushError::Full`, or waits for room.
+    pub fn push(&self, message: String, overflow: OverflowPolicy) -> Result<(), PushError> {
+        let (state, changed) = &*self.shared;
+        let mut state = state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return Err(PushError::Closed);
+            }
+
+            if state.messages.len() < self.capacity {
+                break;
+            }
+
+            match overflow {
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                }
+                OverflowPolicy::DropClient => return Err(PushError::Full),
+                OverflowPolicy::Block => state = changed.wait(state).unwrap(),
+            }
+        }
+
+        state.messages.push_back(message);
+        changed.notify_all();
+        Ok(())
+    }
+
+    /// Removes the message at the front of the queue, waiting for one if the queue is empty.
+    /// Returns `None` once the queue has been closed and emptied.
+    pub fn pop(&self) -> Option<String> {
+        let (state, changed) = &*self.shared;
+        let mut state = state.lock().unwrap();
+
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                changed.notify_all();
+                return Some(message);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = changed.wait(state).unwrap();
+        }
+    }
+
+    /// Closes the queue, so no more messages can be pushed. Messages already queued can still be
+    /// popped.
+    pub fn close(&self) {
+        let (state, changed) = &*self.shared;
+        state.lock().unwrap().closed = true;
+        changed.notify_all();
+    }
+}
+
+/// The broadcaster's handle on a connected client.
+#[derive(Debug)]
+pub struct ClientHandle {
+    pub queue: OutboundQueue,
+    /// The protocol the client speaks, which decides how its input is decoded and how messages to
+    /// it are encoded.
+    pub protocol: Protocol,
+    /// Whether control characters and terminal escape sequences are removed from the client's
+    /// input once it is decoded and the client has entered its display name.
+    pub strip_controls: bool,
+    /// A clone of the client's stream, used to disconnect a client that has fallen behind.
+    pub stream: TcpStream,
+}
+
+impl ClientHandle {
+    /// Closes the client's queue and connection, discarding any messages still queued. This also
+    /// ends the client's handler, as its next read fails.
+    pub fn disconnect(&self) {
+        self.queue.close();
+        // Fails harmlessly if the client has already disconnected.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Continuously receives `Event`s from connection handlers on `broadcast_rx`, keeping track of the
+/// connected clients and passing every line of input to `chat`, decoded according to the protocol
+/// its sender speaks. `chat` decides which clients should receive which `Message`s in response.
+/// Each `Message` is encoded in its recipient's protocol and pushed onto the recipient's outbound
+/// queue. A client whose queue is full is dealt with according to `overflow`, and a client
+/// whose queue has been closed by its writer is assumed to have disconnected and is forgotten.
+///
+/// The function loops continuously until an error occurs when trying to read from `broadcast_rx`,
+/// which happens once every sender has been dropped. It then closes the queue of every remaining
+/// client, so their writers exit once they have written everything queued.
+pub fn broadcast(broadcast_rx: Receiver<Event>, mut chat: Chat, overflow: OverflowPolicy) {
+    info!(
+        Context::SERVER,
+        "broadcaster_started", "Broadcaster started"
+    );
+    let mut clients = BTreeMap::<ClientId, ClientHandle>::new();
+
+    loop {
+        match broadcast_rx.recv() {
+            Ok(Event::Connect { id, client }) => {
+                info!(
+                    Context::client(id),
+                    "client_registered", "Broadcaster registered client"
+                );
+                clients.insert(id, client);
+                let deliveries = chat.connect(id);
+                deliver(deliveries, &mut chat, &mut clients, overflow);
+            }
+            Ok(Event::Input { id, line }) => {
+                let Some(client) = clients.get(&id) else {
+                    continue;
+                };
+                // Display names are left as they are, so that one containing control characters
+                // is refused rather than quietly changed.
+                let strip = client.strip_controls && chat.display_name(id).is_some();
+                let deliveries = codec::decode(client.protocol, line)
+                    .into_iter()
+                    .map(|input| match strip {
+                        true => input.strip_controls(),
+                        false => input,
+                    })
+                    .flat_map(|input| chat.input(id, input))
+                    .collect();
+                deliver(deliveries, &mut chat, &mut clients, overflow);
+            }
+            Ok(Event::Broadcast(message)) => {
+                debug!(
+                    Context::SERVER,
+                    "message_received", "Broadcaster received message: {}", message.body
+                );
+                let deliveries = chat.announce(&message);
+                deliver(deliveries, &mut chat, &mut clients, overflow);
+            }
+            Ok(Event::Notify { id, message }) => {
+                let deliveries = vec![Delivery::new(id, message)];
+                deliver(deliveries, &mut chat, &mut clients, overflow);
+            }
+            Ok(Event::Disconnect { id, reason }) => {
+                if let Some(client) = clients.remove(&id) {
+                    info!(
+                        Context::client(id),
+                        "client_removed", "Broadcaster removed client ({reason})"
+                    );
+                    client.queue.close();
+                }
+                let deliveries = chat.disconnect(id, reason);
+                deliver(deliveries, &mut chat, &mut clients, overflow);
+            }
+            Err(e) => {
+                info!(
+                    Context::SERVER,
+                    "broadcaster_stopped",
+                    "Broadcaster channel returned '{e:?}', so Broadcaster exiting"
+                );
+
+                for client in clients.values() {
+                    client.queue.close();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Pushes each of `deliveries` onto the outbound queue of its recipient in `clients`, or closes the
+/// queue of a client that is to be disconnected. A recipient whose queue is full is disconnected,
+/// and any recipient that cannot receive its message is removed from `clients` and `chat`, which
+/// announces its departure to the remaining clients.
+fn deliver(
+    deliveries: Vec<Delivery>,
+    chat: &mut Chat,
+    clients: &mut BTreeMap<ClientId, ClientHandle>,
+    overflow: OverflowPolicy,
+) {
+    let mut pending = VecDeque::from(deliveries);
+
+    while let Some(delivery) = pending.pop_front() {
+        let (to, message) = match delivery {
+            Delivery::Send { to, message } => (to, message),
+            Delivery::Close { to } => {
+                // The writer shuts the connection down once it has written everything queued.
+                if let Some(client) = clients.get(&to) {
+                    client.queue.close();
+                }
+                continue;
+            }
+        };
+
+        let Some(client) = clients.get(&to) else {
+            continue;
+        };
+
+        // Some protocols have no equivalent of some messages.
+        let encoded = codec::encode(client.protocol, &message, to, chat);
+        if encoded.is_empty() {
+            continue;
+        }
+
+        let reason = match client.queue.push(encoded, overflow) {
+            Ok(()) => continue,
+            Err(PushError::Full) => {
+                warn!(
+                    Context::client(to),
+                    "client_too_slow", "Client fell too far behind; disconnecting it"
+                );
+                client.disconnect();
+                LeaveReason::TooSlow
+            }
+            Err(PushError::Closed) => {
+                warn!(
+                    Context::client(to),
+                    "broadcast_failed", "Failed to broadcast to client; removing it"
+                );
+                LeaveReason::Reset
+            }
+        };
+
+        clients.remove(&to);
+        pending.extend(chat.disconnect(to, reason));
+    }
+}
+
+/// Writes every message pushed onto `queue` to `writer`, the writing half of `stream`, until the
+/// queue is closed and emptied or a write fails. In either case, `queue` is closed and `stream` is
+/// shut down before returning, after ending any TLS session. If a write fails, the broadcaster is
+/// told through `broker` that client `id` has gone, so it does not have to wait for the next
+/// message to the client to find out.
+pub fn write_outbound(
+    id: ClientId,
+    queue: OutboundQueue,
+    mut writer: Writer,
+    stream: TcpStream,
+    broker: Sender<Event>,
+) {
+    while let Some(message) = queue.pop() {
+        if let Err(e) = writer.write_all(message.as_bytes()) {
+            warn!(
+                Context::client(id),
+                "write_failed", "Failed to write to client: {e}"
+            );
+            queue.close();
+            let _ = broker.send(Event::Disconnect {
+                id,
+                reason: LeaveReason::from_io_error(&e),
+            });
+            break;
+        }
+    }
+
+    queue.close();
+    // Dropping the writer ends any TLS session. Shutting down fails harmlessly if the client has
+    // already disconnected.
+    drop(writer);
+    let _ = stream.shutdown(Shutdown::Both);
+}
@@ -0,0 +1,598 @@
+//! The state of a chat server that does not depend on how clients are connected: who is connected
+//! under which display name, and which rooms they are in.
+//!
+//! Both broadcasters keep a `Chat` and feed it every line a client sends. In return it tells them
+//! which `Message`s to deliver to which clients, so rooms and commands work the same way in the
+//! blocking and asynchronous servers.
+
+pub mod commands;
+pub mod history;
+pub mod log;
+pub mod registry;
+
+use crate::broker::{ClientId, ErrorCode, LeaveReason, Message, MessageKind};
+use crate::codec;
+use crate::config::ChatConfig;
+use crate::info;
+use crate::input;
+use crate::log::Context;
+use commands::{Commands, Invocation};
+use history::History;
+use log::ChatLog;
+use registry::{NameError, Registry};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+/// The room every client joins once it has entered its display name.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// The maximum length of a room name, in characters.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Something the broadcaster should do for a single client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Send `message` to the client.
+    Send { to: ClientId, message: Message },
+    /// Disconnect the client once everything already sent to it has been written.
+    Close { to: ClientId },
+}
+
+impl Delivery {
+    /// Returns a delivery sending `message` to client `to`.
+    pub fn new(to: ClientId, message: Message) -> Self {
+        Self::Send { to, message }
+    }
+
+    /// Returns a delivery sending a notice from the server containing `body` to client `to`.
+    pub fn system(to: ClientId, body: impl Into<String>) -> Self {
+        Self::new(to, Message::system(body))
+    }
+
+    /// Returns a delivery sending an error with the given `code`, described by `body`, to client
+    /// `to`.
+    pub fn error(to: ClientId, code: ErrorCode, body: impl Into<String>) -> Self {
+        Self::new(to, Message::error(code, body))
+    }
+}
+
+/// Something a client asks the chat to do, decoded from a line it sent according to the protocol it
+/// speaks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// A line of the text protocol, including its trailing newline: the client's display name if
+    /// it has not entered one yet, a command if it starts with `/`, or else a line of chat.
+    Line(String),
+    /// Send `text` to `room`, or to the client's current room if no room is given, even if it
+    /// starts with `/`.
+    Say { room: Option<String>, text: String },
+    /// Describe what the client is doing in `room`, or in its current room, as `/me` does.
+    Act { room: Option<String>, text: String },
+    /// Run the command called `name` with `args`, as if the client had sent `/name args`. Before
+    /// the client has entered the chat, `nick` gives it its display name.
+    Command { name: String, args: String },
+    /// Check the connection is alive, which is answered with the same token whether or not the
+    /// client has entered the chat.
+    Ping(String),
+    /// Input that could not be decoded, for the reason given.
+    Invalid(String),
+}
+
+impl Input {
+    /// Returns the input with control characters and terminal escape sequences removed from the
+    /// text it contains.
+    pub fn strip_controls(self) -> Self {
+        let strip = |text: String| input::strip_controls(&text);
+        let strip_room = |room: Option<String>| room.map(strip);
+
+        match self {
+            Self::Line(line) => Self::Line(strip(line)),
+            Self::Say { room, text } => Self::Say {
+                room: strip_room(room),
+                text: strip(text),
+            },
+            Self::Act { room, text } => Self::Act {
+                room: strip_room(room),
+                text: strip(text),
+            },
+            Self::Command { name, args } => Self::Command {
+                name: strip(name),
+                args: strip(args),
+            },
+            Self::Ping(token) => Self::Ping(strip(token)),
+            Self::Invalid(reason) => Self::Invalid(reason),
+        }
+    }
+}
+
+/// A connected client. Its display name is kept in the `Registry`.
+#[derive(Debug, Default)]
+struct Session {
+    rooms: BTreeSet<String>,
+    /// The room the client's chat lines are sent to, if it is in any.
+    current_room: Option<String>,
+}
+
+/// The clients connected to a chat server, the rooms they are in, and the commands they can use.
+#[derive(Debug)]
+pub struct Chat {
+    sessions: BTreeMap<ClientId, Session>,
+    /// The display names of the clients that have entered the chat.
+    registry: Registry,
+    /// The members of every room with at least one member.
+    rooms: BTreeMap<String, BTreeSet<ClientId>>,
+    history: History,
+    log: Option<ChatLog>,
+    commands: Commands,
+}
+
+impl Chat {
+    /// Creates a chat with no clients, configured by `config`, in which clients can use the
+    /// built-in commands. If `config` names a chat log, the log is opened and the history of each
+    /// room is reloaded from it.
+    pub fn new(config: &ChatConfig) -> io::Result<Self> {
+        Self::with_commands(config, Commands::default())
+    }
+
+    /// Creates a chat with no clients, configured by `config`, in which clients can use `commands`.
+    pub fn with_commands(config: &ChatConfig, commands: Commands) -> io::Result<Self> {
+        let mut history = History::new(config);
+
+        let log = match &config.log_path {
+            Some(path) => {
+                let (log, messages) = ChatLog::open(path, config.log_max_size)?;
+                info!(
+                    Context::SERVER,
+                    "chat_log_reloaded",
+                    "Reloaded {} message(s) from chat log {}",
+                    messages.len(),
+                    path.display()
+                );
+
+                for message in messages {
+                    if let Some(room) = message.room.clone() {
+                        history.record(&room, message);
+                    }
+                }
+                Some(log)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            sessions: BTreeMap::new(),
+            registry: Registry::new(),
+            rooms: BTreeMap::new(),
+            history,
+            log,
+            commands,
+        })
+    }
+
+    /// Returns the commands clients can use.
+    pub fn commands(&self) -> &Commands {
+        &self.commands
+    }
+
+    /// Registers a newly connected client, returning the prompt for its display name.
+    pub fn connect(&mut self, id: ClientId) -> Vec<Delivery> {
+        self.sessions.insert(id, Session::default());
+        vec![Delivery::new(id, Message::prompt())]
+    }
+
+    /// Forgets a client that has left for `reason`, removing it from every room it was in. Returns
+    /// the announcement of its departure to everyone else in the chat, if it had entered it.
+    pub fn disconnect(&mut self, id: ClientId, reason: LeaveReason) -> Vec<Delivery> {
+        let Some(session) = self.sessions.remove(&id) else {
+            return Vec::new();
+        };
+
+        for room in &session.rooms {
+            self.remove_member(room, id);
+        }
+
+        match self.registry.release(id) {
+            Some(display_name) => self.to_entered(Message::new(
+                id,
+                &display_name,
+                MessageKind::Leave,
+                None,
+                reason.to_string(),
+            )),
+            None => Vec::new(),
+        }
+    }
+
+    /// Handles `input` from client `id`. The client must enter its display name first, after which
+    /// it can chat and run commands. In the text protocol, the first line is the client's display
+    /// name, after which lines starting with `/` are commands and any other line is sent to the
+    /// client's current room. A line starting with `//` is sent to the room without its first `/`.
+    pub fn input(&mut self, id: ClientId, input: Input) -> Vec<Delivery> {
+        if !self.sessions.contains_key(&id) {
+            return Vec::new();
+        }
+
+        let Some(display_name) = self.registry.name(id).map(str::to_owned) else {
+            return match input {
+                Input::Line(line) => self.enter(id, &codec::parse_display_name(&line)),
+                Input::Command { name, args } if name == "nick" => {
+                    self.enter(id, &codec::parse_display_name(&args))
+                }
+                Input::Ping(token) => vec![Delivery::new(id, Message::pong(token))],
+                Input::Invalid(reason) => vec![Delivery::error(id, ErrorCode::Invalid, reason)],
+                _ => vec![Delivery::new(id, Message::prompt())],
+            };
+        };
+
+        match input {
+            Input::Line(line) => match line.strip_prefix('/') {
+                Some(escaped) if escaped.starts_with('/') => self.say(id, None, escaped),
+                Some(command) => {
+                    let command = command.trim();
+                    let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+                    self.run_command(id, &display_name, name, args)
+                }
+                None => self.say(id, None, &line),
+            },
+            Input::Say { room, text } => self.say(id, room.as_deref(), &text),
+            Input::Act { room, text } => self.act(id, room.as_deref(), &text),
+            Input::Command { name, args } => self.run_command(id, &display_name, &name, &args),
+            Input::Ping(token) => vec![Delivery::new(id, Message::pong(token))],
+            Input::Invalid(reason) => vec![Delivery::error(id, ErrorCode::Invalid, reason)],
+        }
+    }
+
+    /// Returns deliveries of `message` to every connected client, whether or not it has entered its
+    /// display name yet.
+    pub fn announce(&self, message: &Message) -> Vec<Delivery> {
+        self.sessions
+            .keys()
+            .map(|&id| Delivery::new(id, message.clone()))
+            .collect()
+    }
+
+    /// Returns the display name of client `id`, if it is connected and has entered one.
+    pub fn display_name(&self, id: ClientId) -> Option<&str> {
+        self.registry.name(id)
+    }
+
+    /// Returns the room client `id` is talking in, if any.
+    pub fn current_room(&self, id: ClientId) -> Option<&str> {
+        self.sessions.get(&id)?.current_room.as_deref()
+    }
+
+    /// Returns the client using `display_name`, ignoring differences in case.
+    pub fn find_client(&self, display_name: &str) -> Option<ClientId> {
+        self.registry.find(display_name)
+    }
+
+    /// Returns the display names of every client that has entered the chat, in the order they
+    /// connected.
+    pub fn display_names(&self) -> Vec<&str> {
+        self.registry.iter().map(|(_, name)| name).collect()
+    }
+
+    /// Returns the display names of the members of `room`, in the order they connected.
+    pub fn members(&self, room: &str) -> Vec<&str> {
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter_map(|&id| self.display_name(id))
+            .collect()
+    }
+
+    /// Returns every room with at least one member and how many members it has. The default room
+    /// is always included, even when empty.
+    pub fn rooms(&self) -> Vec<(&str, usize)> {
+        let mut rooms: Vec<(&str, usize)> = self
+            .rooms
+            .iter()
+            .map(|(room, members)| (room.as_str(), members.len()))
+            .collect();
+
+        if !self.rooms.contains_key(DEFAULT_ROOM) {
+            rooms.insert(0, (DEFAULT_ROOM, 0));
+        }
+
+        rooms
+    }
+
+    /// Returns deliveries of `message` to every member of `room`.
+    pub fn to_room(&self, room: &str, message: Message) -> Vec<Delivery> {
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .map(|&to| Delivery::new(to, message.clone()))
+            .collect()
+    }
+
+    /// Returns deliveries of `message` to every client sharing a room with client `id`, including
+    /// `id` itself, sending it to each client once.
+    pub fn to_neighbours(&self, id: ClientId, message: Message) -> Vec<Delivery> {
+        let mut neighbours = BTreeSet::from([id]);
+        if let Some(session) = self.sessions.get(&id) {
+            for room in &session.rooms {
+                neighbours.extend(self.rooms.get(room).into_iter().flatten());
+            }
+        }
+
+        neighbours
+            .into_iter()
+            .map(|to| Delivery::new(to, message.clone()))
+            .collect()
+    }
+
+    /// Sends the chat `line` from client `id` to `room`, which it must be in, or to its current
+    /// room if `room` is `None`.
+    pub fn say(&mut self, id: ClientId, room: Option<&str>, line: &str) -> Vec<Delivery> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        self.post_from(id, room, MessageKind::Chat, line)
+    }
+
+    /// Sends a description of what client `id` is doing to `room`, which it must be in, or to its
+    /// current room if `room` is `None`.
+    pub fn act(&mut self, id: ClientId, room: Option<&str>, action: &str) -> Vec<Delivery> {
+        self.post_from(id, room, MessageKind::Action, action)
+    }
+
+    /// Sends `message` to every member of the room it was sent to, records it in the room's history
+    /// and appends it to the chat log, if there is one. Failing to write to the log does not stop
+    /// the message being sent.
+    pub fn post(&mut self, message: Message) -> Vec<Delivery> {
+        let Some(room) = message.room.clone() else {
+            return Vec::new();
+        };
+
+        if let Some(log) = &self.log {
+            log.append(&message);
+        }
+
+        self.history.record(&room, message.clone());
+        self.to_room(&room, message)
+    }
+
+    /// Makes `room` the current room of client `id`, first adding the client to it if necessary.
+    pub fn join(&mut self, id: ClientId, room: &str) -> Vec<Delivery> {
+        let room = room.strip_prefix('#').unwrap_or(room);
+        if !valid_room_name(room) {
+            return vec![Delivery::error(
+                id,
+                ErrorCode::InvalidRoom(room.to_owned()),
+                codec::invalid_room_name(room),
+            )];
+        }
+
+        let (Some(session), Some(display_name)) =
+            (self.sessions.get_mut(&id), self.registry.name(id))
+        else {
+            return Vec::new();
+        };
+        let display_name = display_name.to_owned();
+        session.current_room = Some(room.to_owned());
+
+        if !session.rooms.insert(room.to_owned()) {
+            return vec![Delivery::system(id, codec::talking_in(room))];
+        }
+
+        self.rooms.entry(room.to_owned()).or_default().insert(id);
+        let mut deliveries = self.replay_history(id, room);
+        let joined = Message::new(id, &display_name, MessageKind::Join, Some(room), "");
+        deliveries.extend(self.to_room(room, joined));
+        deliveries
+    }
+
+    /// Removes client `id` from `room`, or from its current room if `room` is empty.
+    pub fn part(&mut self, id: ClientId, room: &str) -> Vec<Delivery> {
+        let (Some(session), Some(display_name)) =
+            (self.sessions.get_mut(&id), self.registry.name(id))
+        else {
+            return Vec::new();
+        };
+        let display_name = display_name.to_owned();
+
+        let room = match room.strip_prefix('#').unwrap_or(room) {
+            "" => match &session.current_room {
+                Some(room) => room.clone(),
+                None => {
+                    return vec![Delivery::error(
+                        id,
+                        ErrorCode::NotInRoom(None),
+                        codec::NOT_IN_ROOM_NOTICE,
+                    )]
+                }
+            },
+            room => room.to_owned(),
+        };
+
+        if !session.rooms.remove(&room) {
+            let not_in_room = codec::not_in_room(&room);
+            return vec![Delivery::error(
+                id,
+                ErrorCode::NotInRoom(Some(room)),
+                not_in_room,
+            )];
+        }
+
+        // Announce the departure while the client is still a member, so it sees it too.
+        let left = Message::new(id, &display_name, MessageKind::Leave, Some(&room), "");
+        let mut deliveries = self.to_room(&room, left);
+        self.remove_member(&room, id);
+
+        let session = self.sessions.get_mut(&id).unwrap();
+        if session.current_room.as_deref() == Some(room.as_str()) {
+            session.current_room = if session.rooms.contains(DEFAULT_ROOM) {
+                Some(DEFAULT_ROOM.to_owned())
+            } else {
+                session.rooms.first().cloned()
+            };
+
+            deliveries.push(Delivery::system(
+                id,
+                match &session.current_room {
+                    Some(current) => codec::talking_in(current),
+                    None => codec::NOT_IN_ROOM_NOTICE.to_owned(),
+                },
+            ));
+        }
+
+        deliveries
+    }
+
+    /// Changes the display name of client `id` to `display_name`, announcing the change to everyone
+    /// sharing a room with it. If the name cannot be used, only the client is told why.
+    pub fn rename(&mut self, id: ClientId, display_name: &str) -> Vec<Delivery> {
+        let Some(old_name) = self.registry.name(id).map(str::to_owned) else {
+            return Vec::new();
+        };
+
+        if let Err(e) = self.registry.claim(id, display_name) {
+            return vec![name_rejected(id, display_name, &e)];
+        }
+
+        let renamed = Message::new(id, display_name, MessageKind::Rename, None, old_name);
+        self.to_neighbours(id, renamed)
+    }
+
+    /// Runs the command called `name` with `args` for client `id`, called `display_name`.
+    fn run_command(
+        &mut self,
+        id: ClientId,
+        display_name: &str,
+        name: &str,
+        args: &str,
+    ) -> Vec<Delivery> {
+        let Some(command) = self.commands.get(name).copied() else {
+            return vec![Delivery::error(
+                id,
+                ErrorCode::UnknownCommand(name.to_owned()),
+                codec::unknown_command(name),
+            )];
+        };
+
+        (command.run)(
+            self,
+            Invocation {
+                client: id,
+                display_name,
+                args: args.trim(),
+            },
+        )
+    }
+
+    /// Gives client `id` its display name and puts it in the default room. If the name cannot be
+    /// used, the client is told why and prompted for another.
+    fn enter(&mut self, id: ClientId, display_name: &str) -> Vec<Delivery> {
+        if let Err(e) = self.registry.claim(id, display_name) {
+            return vec![
+                name_rejected(id, display_name, &e),
+                Delivery::new(id, Message::prompt()),
+            ];
+        }
+
+        let session = self.sessions.get_mut(&id).unwrap();
+        session.rooms.insert(DEFAULT_ROOM.to_owned());
+        session.current_room = Some(DEFAULT_ROOM.to_owned());
+        self.rooms
+            .entry(DEFAULT_ROOM.to_owned())
+            .or_default()
+            .insert(id);
+
+        let mut deliveries = self.replay_history(id, DEFAULT_ROOM);
+        let entered = Message::new(id, display_name, MessageKind::Join, None, "");
+        deliveries.extend(self.to_entered(entered));
+        deliveries
+    }
+
+    /// Sends a message of the given `kind` from client `id` to `room`, or to its current room if
+    /// `room` is `None`. The client is told if it is not in the room.
+    fn post_from(
+        &mut self,
+        id: ClientId,
+        room: Option<&str>,
+        kind: MessageKind,
+        body: &str,
+    ) -> Vec<Delivery> {
+        let (Some(display_name), Some(session)) = (self.display_name(id), self.sessions.get(&id))
+        else {
+            return Vec::new();
+        };
+
+        let room = match room.map(|room| room.strip_prefix('#').unwrap_or(room)) {
+            Some(room) if session.rooms.contains(room) => room,
+            Some(room) => {
+                return vec![Delivery::error(
+                    id,
+                    ErrorCode::NotInRoom(Some(room.to_owned())),
+                    codec::not_in_room(room),
+                )]
+            }
+            None => match &session.current_room {
+                Some(room) => room,
+                None => {
+                    return vec![Delivery::error(
+                        id,
+                        ErrorCode::NotInRoom(None),
+                        codec::NOT_IN_ROOM_NOTICE,
+                    )]
+                }
+            },
+        };
+
+        let message = Message::new(id, display_name, kind, Some(room), body);
+        self.post(message)
+    }
+
+    /// Returns deliveries to client `id` of the recent messages in `room`, marked as history.
+    fn replay_history(&self, id: ClientId, room: &str) -> Vec<Delivery> {
+        self.history
+            .recent(room)
+            .into_iter()
+            .map(|message| Delivery::new(id, message.replay()))
+            .collect()
+    }
+
+    /// Returns deliveries of `message` to every client that has entered its display name.
+    fn to_entered(&self, message: Message) -> Vec<Delivery> {
+        self.registry
+            .iter()
+            .map(|(to, _)| Delivery::new(to, message.clone()))
+            .collect()
+    }
+
+    /// Removes client `id` from the members of `room`, forgetting the room once it is empty.
+    fn remove_member(&mut self, room: &str, id: ClientId) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+
+                // Nobody will see the history of a room that no longer exists, except the default
+                // room, which always exists in all but name.
+                if room != DEFAULT_ROOM {
+                    self.history.forget(room);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the delivery telling client `id` why it cannot use `display_name`.
+fn name_rejected(id: ClientId, display_name: &str, error: &NameError) -> Delivery {
+    let code = match error {
+        NameError::Taken(_) => ErrorCode::NameTaken(display_name.to_owned()),
+        _ => ErrorCode::InvalidName(display_name.to_owned()),
+    };
+    Delivery::error(id, code, codec::name_rejected(error))
+}
+
+/// Returns `true` if `room` is not empty, is short enough and contains only letters, digits, `-`
+/// and `_`.
+fn valid_room_name(room: &str) -> bool {
+    !room.is_empty()
+        && room.chars().count() <= MAX_ROOM_NAME_LEN
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,272 @@
+//! The commands chat clients can use by sending a line starting with `/`, such as `/join rust`.
+//!
+//! Each command is a plain function registered by name in `Commands`. The `Chat` looks up the
+//! command named on each line and runs it, so adding a command only means writing its function and
+//! registering it, either in `Commands::default` or on a `Commands` passed to
+//! `Chat::with_commands`.
+
+use super::{Chat, Delivery};
+use crate::broker::{ClientId, ErrorCode, Message, MessageKind};
+use crate::codec;
+use std::collections::BTreeMap;
+
+/// The client that sent a command, and the rest of the line after the command's name with leading
+/// and trailing whitespace removed.
+#[derive(Clone, Copy, Debug)]
+pub struct Invocation<'a> {
+    pub client: ClientId,
+    pub display_name: &'a str,
+    pub args: &'a str,
+}
+
+impl Invocation<'_> {
+    /// Returns a delivery of a notice from the server containing `body` to the client that sent
+    /// the command.
+    pub fn reply(&self, body: impl Into<String>) -> Vec<Delivery> {
+        vec![Delivery::system(self.client, body)]
+    }
+
+    /// Returns a delivery of an error with the given `code`, described by `body`, to the client
+    /// that sent the command.
+    pub fn error(&self, code: ErrorCode, body: impl Into<String>) -> Vec<Delivery> {
+        vec![Delivery::error(self.client, code, body)]
+    }
+
+    /// Returns a delivery telling the client that sent the command how to use the command called
+    /// `name`, which is described by `synopsis`.
+    pub fn usage(&self, name: &str, synopsis: &str) -> Vec<Delivery> {
+        self.error(
+            ErrorCode::MissingArguments(name.to_owned()),
+            codec::usage(synopsis),
+        )
+    }
+}
+
+/// A command that can be run by sending `/name` followed by its arguments.
+#[derive(Clone, Copy, Debug)]
+pub struct Command {
+    pub name: &'static str,
+    /// The command's arguments, as shown by `/help`, e.g. `<room>`.
+    pub args: &'static str,
+    /// A one-line description of what the command does, as shown by `/help`.
+    pub summary: &'static str,
+    pub run: fn(&mut Chat, Invocation) -> Vec<Delivery>,
+}
+
+impl Command {
+    /// Returns how to use the command, e.g. `/join <room>`.
+    pub fn synopsis(&self) -> String {
+        if self.args.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {}", self.name, self.args)
+        }
+    }
+}
+
+/// The commands available to chat clients, by name.
+#[derive(Clone, Debug)]
+pub struct Commands {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl Default for Commands {
+    /// Returns the built-in commands.
+    fn default() -> Self {
+        let mut commands = Self::empty();
+        commands.register(Command {
+            name: "join",
+            args: "<room>",
+            summary: "Join a room, creating it if necessary, and talk in it",
+            run: join,
+        });
+        commands.register(Command {
+            name: "part",
+            args: "[room]",
+            summary: "Leave a room, by default the one you are talking in",
+            run: part,
+        });
+        commands.register(Command {
+            name: "rooms",
+            args: "",
+            summary: "List the rooms and how many people are in each",
+            run: rooms,
+        });
+        commands.register(Command {
+            name: "nick",
+            args: "<name>",
+            summary: "Change your display name",
+            run: nick,
+        });
+        commands.register(Command {
+            name: "who",
+            args: "[room]",
+            summary: "List the people in a room, by default the one you are talking in",
+            run: who,
+        });
+        commands.register(Command {
+            name: "me",
+            args: "<action>",
+            summary: "Describe what you are doing, e.g. /me waves",
+            run: me,
+        });
+        commands.register(Command {
+            name: "msg",
+            args: "<name> <text>",
+            summary: "Send a private message to one person",
+            run: msg,
+        });
+        commands.register(Command {
+            name: "quit",
+            args: "",
+            summary: "Leave the chat and disconnect",
+            run: quit,
+        });
+        commands.register(Command {
+            name: "help",
+            args: "[command]",
+            summary: "List the commands, or describe one",
+            run: help,
+        });
+        commands
+    }
+}
+
+impl Commands {
+    /// Returns a registry with no commands.
+    pub fn empty() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `command`, replacing any command with the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.insert(command.name, command);
+    }
+
+    /// Returns the command called `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// Returns every command, in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.values()
+    }
+}
+
+fn join(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    if invocation.args.is_empty() {
+        return invocation.usage("join", "/join <room>");
+    }
+
+    chat.join(invocation.client, invocation.args)
+}
+
+fn part(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    chat.part(invocation.client, invocation.args)
+}
+
+fn rooms(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    invocation.reply(codec::room_list(&chat.rooms()))
+}
+
+fn nick(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    let display_name = codec::parse_display_name(invocation.args);
+    if display_name.is_empty() {
+        return invocation.usage("nick", "/nick <name>");
+    }
+
+    chat.rename(invocation.client, &display_name)
+}
+
+fn who(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    let room = match invocation.args.strip_prefix('#').unwrap_or(invocation.args) {
+        "" => match chat.current_room(invocation.client) {
+            Some(room) => room,
+            None => return invocation.error(ErrorCode::NotInRoom(None), codec::NOT_IN_ROOM_NOTICE),
+        },
+        room => room,
+    };
+
+    let names = chat.members(room).into_iter().map(str::to_owned).collect();
+    vec![Delivery::new(
+        invocation.client,
+        Message::users(room, names),
+    )]
+}
+
+fn me(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    if invocation.args.is_empty() {
+        return invocation.usage("me", "/me <action>");
+    }
+
+    chat.act(invocation.client, None, invocation.args)
+}
+
+fn msg(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    let (recipient, text) = invocation
+        .args
+        .split_once(' ')
+        .unwrap_or((invocation.args, ""));
+    let text = text.trim_start();
+    if recipient.is_empty() || text.is_empty() {
+        return invocation.usage("msg", "/msg <name> <text>");
+    }
+
+    let Some(to) = chat.find_client(recipient) else {
+        return invocation.error(
+            ErrorCode::NoSuchUser(recipient.to_owned()),
+            codec::no_such_user(recipient),
+        );
+    };
+    // Names are matched ignoring case, so use the recipient's name as they spell it.
+    let recipient = chat.display_name(to).unwrap_or(recipient).to_owned();
+    let message = Message::new(
+        invocation.client,
+        invocation.display_name,
+        MessageKind::Private { recipient },
+        None,
+        text,
+    );
+
+    // The sender is sent a copy, so it can see the message in context. A message to oneself is only
+    // sent once.
+    let mut deliveries = vec![Delivery::new(invocation.client, message.clone())];
+    if to != invocation.client {
+        deliveries.push(Delivery::new(to, message));
+    }
+    deliveries
+}
+
+fn quit(_chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    let mut deliveries = invocation.reply(codec::GOODBYE);
+    deliveries.push(Delivery::Close {
+        to: invocation.client,
+    });
+    deliveries
+}
+
+fn help(chat: &mut Chat, invocation: Invocation) -> Vec<Delivery> {
+    let name = invocation.args.strip_prefix('/').unwrap_or(invocation.args);
+
+    let lines: Vec<(String, &str)> = if name.is_empty() {
+        chat.commands()
+            .iter()
+            .map(|command| (command.synopsis(), command.summary))
+            .collect()
+    } else {
+        match chat.commands().get(name) {
+            Some(command) => vec![(command.synopsis(), command.summary)],
+            None => {
+                return invocation.error(
+                    ErrorCode::UnknownCommand(name.to_owned()),
+                    codec::unknown_command(name),
+                )
+            }
+        }
+    };
+
+    invocation.reply(codec::help(&lines))
+}
@@ -0,0 +1,63 @@
+//! The recent messages in each chat room, replayed to clients as they enter it.
+
+use crate::broker::Message;
+use crate::config::ChatConfig;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// A ring buffer of the most recent messages sent to each room.
+#[derive(Debug)]
+pub struct History {
+    /// The maximum number of messages kept for each room.
+    length: usize,
+    max_age: Option<Duration>,
+    rooms: BTreeMap<String, VecDeque<Message>>,
+}
+
+impl History {
+    /// Creates an empty history, holding as many messages as `config` allows.
+    pub fn new(config: &ChatConfig) -> Self {
+        Self {
+            length: config.history_length,
+            max_age: config.history_age,
+            rooms: BTreeMap::new(),
+        }
+    }
+
+    /// Records `message` as sent to `room`, discarding the room's oldest message if it already has
+    /// as many as allowed.
+    pub fn record(&mut self, room: &str, message: Message) {
+        if self.length == 0 {
+            return;
+        }
+
+        let messages = self.rooms.entry(room.to_owned()).or_default();
+        if messages.len() == self.length {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Returns the messages recorded for `room`, oldest first, leaving out any older than the
+    /// maximum age.
+    pub fn recent(&self, room: &str) -> Vec<&Message> {
+        let now = SystemTime::now();
+
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter(
+                |message| match (self.max_age, now.duration_since(message.time)) {
+                    (Some(max_age), Ok(age)) => age <= max_age,
+                    _ => true,
+                },
+            )
+            .collect()
+    }
+
+    /// Discards the messages recorded for `room`.
+    pub fn forget(&mut self, room: &str) {
+        self.rooms.remove(room);
+    }
+}
@@ -0,0 +1,341 @@
+//! The optional append-only log of every message sent to a chat room, which survives server
+//! restarts.
+//!
+//! Each message is one line of tab-separated fields: the time in RFC 3339 format (UTC), the room,
+//! the sender's display name, the kind of message (`chat` or `action`), and the text. Tabs,
+//! newlines and backslashes within fields are escaped with a backslash, e.g.
+//! `2024-05-01T12:34:56.789Z\tlobby\talice\tchat\thello everyone` with each `\t` being a tab.
+//!
+//! When the log would grow beyond its maximum size, it is renamed by appending `.1` to its name,
+//! replacing any earlier file of that name, and a new log is started.
+//!
+//! Messages are written to the file by a thread of its own, so a slow disk cannot hold up the
+//! broadcaster, which in `chat_async` shares the executor with every connection.
+
+use crate::broker::{Message, MessageKind};
+use crate::log::Context;
+use crate::{error, info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An open chat log. Dropping it waits for every message appended to be written.
+#[derive(Debug)]
+pub struct ChatLog {
+    /// Sends each line to append to the writer thread, which exits once this is dropped.
+    lines: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl ChatLog {
+    /// Opens the log at `path` for appending, creating it if necessary, and returns it with the
+    /// messages already in it and in any log it was rotated from, oldest first. Lines that cannot
+    /// be parsed are skipped.
+    pub fn open(path: &Path, max_size: u64) -> io::Result<(Self, Vec<Message>)> {
+        let mut messages = Vec::new();
+        for existing in [rotated_path(path), path.to_owned()] {
+            match File::open(&existing) {
+                Ok(file) => messages.extend(read_messages(&existing, file)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let file = LogFile {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        };
+
+        let (lines, received) = channel();
+        let writer = thread::spawn(move || file.write_lines(received));
+        let log = Self {
+            lines: Some(lines),
+            writer: Some(writer),
+        };
+
+        Ok((log, messages))
+    }
+
+    /// Queues `message`, which was sent to a room, to be appended to the log. Failing to write it
+    /// is logged by the writer thread.
+    pub fn append(&self, message: &Message) {
+        if let Some(lines) = &self.lines {
+            // Only fails if the writer thread has panicked, which has already been reported.
+            let _ = lines.send(to_line(message));
+        }
+    }
+}
+
+impl Drop for ChatLog {
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The file a chat log is written to, owned by the log's writer thread.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// The current size of the log, in bytes.
+    size: u64,
+    /// The size beyond which the log is rotated, or 0 to never rotate it.
+    max_size: u64,
+}
+
+impl LogFile {
+    /// Appends each line received from `lines` until every sender has been dropped. Failing to
+    /// write a line does not stop later ones being written.
+    fn write_lines(mut self, lines: Receiver<String>) {
+        for line in lines {
+            if let Err(e) = self.append(&line) {
+                error!(
+                    Context::SERVER,
+                    "chat_log_failed", "Failed to write to chat log: {e}"
+                );
+            }
+        }
+    }
+
+    /// Appends `line`, including its trailing newline, first rotating the log if the line would
+    /// take it over its maximum size.
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Renames the log by appending `.1` to its name, and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        info!(
+            Context::SERVER,
+            "chat_log_rotated",
+            "Rotated chat log {}",
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Returns the path a log at `path` is renamed to when it is rotated.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+/// Reads the messages in the log `file`, which was opened from `path`.
+fn read_messages(path: &Path, file: File) -> io::Result<Vec<Message>> {
+    let mut reader = BufReader::new(file);
+    let mut messages = Vec::new();
+    let mut line = Vec::new();
+
+    for number in 1.. {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        // A line that is not valid UTF-8, e.g. one damaged by a crash, is skipped like any other
+        // that cannot be parsed.
+        let message = str::from_utf8(&line)
+            .ok()
+            .and_then(|line| from_line(line.trim_end_matches(['\r', '\n'])));
+        match message {
+            Some(message) => messages.push(message),
+            None => warn!(
+                Context::SERVER,
+                "chat_log_unreadable",
+                "Skipping unreadable line {} of chat log {}",
+                number,
+                path.display()
+            ),
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Returns `message` as a line of the log, including its trailing newline.
+fn to_line(message: &Message) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        format_time(message.time),
+        escape(message.room.as_deref().unwrap_or_default()),
+        escape(&message.display_name),
+        message.kind.name(),
+        escape(&message.body)
+    )
+}
+
+/// Parses a line of the log, without its trailing newline.
+fn from_line(line: &str) -> Option<Message> {
+    let mut fields = line.split('\t');
+    let (Some(time), Some(room), Some(sender), Some(kind), Some(body), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+
+    Some(Message {
+        sender: None,
+        display_name: unescape(sender),
+        kind: match kind {
+            "chat" => MessageKind::Chat,
+            "action" => MessageKind::Action,
+            _ => return None,
+        },
+        time: parse_time(time)?,
+        room: Some(unescape(room)),
+        body: unescape(body),
+        replayed: false,
+    })
+}
+
+/// Escapes backslashes, tabs, carriage returns and newlines in `field`.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape`.
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Formats `time` in RFC 3339 format in UTC with millisecond precision, e.g.
+/// `2024-05-01T12:34:56.789Z`.
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses a time formatted by `format_time`.
+fn parse_time(text: &str) -> Option<SystemTime> {
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, millis) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hours, minutes, secs) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=3).contains(&millis.len()) || !millis.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let millis: u64 = millis.parse().ok()?;
+
+    // Out of range fields would otherwise still give a time, and a year this large would overflow
+    // `days_from_civil`.
+    if !(0..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hours >= 24
+        || minutes >= 60
+        || secs >= 60
+    {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hours * 3600 + minutes * 60 + secs)?;
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(secs))?
+        .checked_add(Duration::from_millis(millis))
+}
+
+/// Returns the year, month and day of the date `days` days after 1970-01-01. This is Howard
+/// Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Returns the number of days from 1970-01-01 to the given date. This is Howard Hinnant's
+/// `days_from_civil` algorithm, the inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
@@ -0,0 +1,108 @@
+//! The registry of display names in use, which guarantees no two chat clients share a name.
+
+use crate::broker::ClientId;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/// The maximum length of a display name, in characters.
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+/// The reasons a display name cannot be used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    /// The name contains a control character, such as an escape sequence.
+    ControlCharacter,
+    /// The name contains whitespace, which would make it impossible to address with `/msg`.
+    Whitespace,
+    /// Another client is using the name, ignoring differences in case.
+    Taken(String),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Display name cannot be empty"),
+            Self::TooLong => write!(
+                f,
+                "Display name cannot be longer than {MAX_DISPLAY_NAME_LEN} characters"
+            ),
+            Self::ControlCharacter => write!(f, "Display name cannot contain control characters"),
+            Self::Whitespace => write!(f, "Display name cannot contain spaces"),
+            Self::Taken(name) => write!(f, "Display name '{name}' is already taken"),
+        }
+    }
+}
+
+impl Error for NameError {}
+
+/// Checks that `display_name` is acceptable as a display name, without checking whether it is in
+/// use.
+pub fn validate(display_name: &str) -> Result<(), NameError> {
+    if display_name.is_empty() {
+        Err(NameError::Empty)
+    } else if display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+        Err(NameError::TooLong)
+    } else if display_name.chars().any(char::is_control) {
+        Err(NameError::ControlCharacter)
+    } else if display_name.chars().any(char::is_whitespace) {
+        Err(NameError::Whitespace)
+    } else {
+        Ok(())
+    }
+}
+
+/// The display name of every client that has entered the chat.
+#[derive(Debug, Default)]
+pub struct Registry {
+    names: BTreeMap<ClientId, String>,
+    /// The client using each name, keyed by the name in lower case.
+    clients: BTreeMap<String, ClientId>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives client `id` the name `display_name`, releasing any name it had before.
+    pub fn claim(&mut self, id: ClientId, display_name: &str) -> Result<(), NameError> {
+        validate(display_name)?;
+
+        let key = display_name.to_lowercase();
+        if let Some(&owner) = self.clients.get(&key) {
+            if owner != id {
+                return Err(NameError::Taken(display_name.to_owned()));
+            }
+        }
+
+        self.release(id);
+        self.clients.insert(key, id);
+        self.names.insert(id, display_name.to_owned());
+        Ok(())
+    }
+
+    /// Releases the name of client `id`, returning it if the client had one.
+    pub fn release(&mut self, id: ClientId) -> Option<String> {
+        let display_name = self.names.remove(&id)?;
+        self.clients.remove(&display_name.to_lowercase());
+        Some(display_name)
+    }
+
+    /// Returns the display name of client `id`, if it has one.
+    pub fn name(&self, id: ClientId) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// Returns the client using `display_name`, ignoring differences in case.
+    pub fn find(&self, display_name: &str) -> Option<ClientId> {
+        self.clients.get(&display_name.to_lowercase()).copied()
+    }
+
+    /// Returns every client with a display name, and the name, in the order they connected.
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &str)> {
+        self.names.iter().map(|(&id, name)| (id, name.as_str()))
+    }
+}
@@ -0,0 +1,252 @@
+//! The line-based text protocol spoken by the servers. Clients send newline-terminated lines and
+//! receive newline-terminated lines back; this module builds the lines the servers send.
+//!
+//! The chat servers' notices to clients are built here as the bodies of system `Message`s, without
+//! trailing newlines, and `format_message` turns each `Message` into the line sent to a client.
+//!
+//! Chat clients can instead speak the JSON-lines protocol in the `json` submodule, or the subset of
+//! IRC in the `irc` submodule. Each chat listener speaks one `Protocol`, which `decode` and `encode`
+//! use to translate between a client's lines and the chat's `Input`s and `Message`s.
+
+pub mod irc;
+pub mod json;
+
+use crate::broker::{ClientId, Message, MessageKind};
+use crate::chat::registry::NameError;
+use crate::chat::{Chat, Input, DEFAULT_ROOM, MAX_ROOM_NAME_LEN};
+use crate::flood::Verdict;
+use crate::governor::Refusal;
+use crate::input::Rejection;
+use crate::timeout::Timeout;
+use std::fmt;
+use std::io;
+
+/// Prefix of every line the echo servers send back to a client.
+pub const ECHO_PREFIX: &str = "Server responds: ";
+
+/// Prompt sent by the chat servers to each newly connected client.
+pub const DISPLAY_NAME_PROMPT: &str = "Enter your display name";
+
+/// Notice sent to every client when a server shuts down.
+pub const SHUTDOWN_NOTICE: &str = "Server shutting down\n";
+
+/// Notice sent to a client whose connection is rejected because the server is too busy.
+pub const BUSY_NOTICE: &str = "Server busy, please try again later\n";
+
+/// Notice sent to a chat client that tries to chat, or leave its current room, while in no room.
+pub const NOT_IN_ROOM_NOTICE: &str = "You are not in any room; use /join <room> to enter one";
+
+/// Sent to a chat client that uses `/quit`, before it is disconnected.
+pub const GOODBYE: &str = "Goodbye";
+
+/// The protocol spoken by a chat client, which is decided by the port it connects to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Human-readable lines, as sent and read by `nc`.
+    #[default]
+    Text,
+    /// A JSON object per line; see the `json` module.
+    Json,
+    /// The text protocol, with each line carried in a WebSocket text message instead of ending
+    /// with a newline; see the `websocket` module.
+    WebSocket,
+    /// The subset of IRC understood by standard IRC clients; see the `irc` module.
+    Irc,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "JSON",
+            Self::WebSocket => "WebSocket",
+            Self::Irc => "IRC",
+        })
+    }
+}
+
+/// Returns what a chat client speaking `protocol` asks for by sending `line`, which is expected to
+/// include its trailing newline. Some protocols have lines that ask for nothing, or for several
+/// things at once.
+pub fn decode(protocol: Protocol, line: String) -> Vec<Input> {
+    match protocol {
+        Protocol::Text | Protocol::WebSocket => vec![Input::Line(line)],
+        Protocol::Json => vec![json::decode(&line)],
+        Protocol::Irc => irc::decode(&line),
+    }
+}
+
+/// Returns `message` as the lines sent to client `recipient`, which speaks `protocol`, including
+/// their trailing newlines. This is usually a single line, but is empty if the protocol has no
+/// equivalent of the message. `chat` is consulted by protocols that describe the recipient's
+/// situation, such as who else is in a room it has joined.
+pub fn encode(protocol: Protocol, message: &Message, recipient: ClientId, chat: &Chat) -> String {
+    match protocol {
+        Protocol::Text | Protocol::WebSocket => format_message(message, recipient),
+        Protocol::Json => json::encode(message),
+        Protocol::Irc => irc::encode(message, recipient, chat),
+    }
+}
+
+/// Returns the echo servers' response to `line`. `line` is expected to include its trailing
+/// newline, which is preserved in the response.
+pub fn echo_response(line: &str) -> String {
+    ECHO_PREFIX.to_string() + line
+}
+
+/// Returns the line warning a client that it is being disconnected because reading from it failed
+/// with `e`, if `e` means the client exceeded a timeout or sent a line that is not accepted.
+pub fn disconnect_notice(e: &io::Error) -> Option<String> {
+    let reason = match (Timeout::from_error(e), Rejection::from_error(e)) {
+        (Some(timeout), _) => timeout.to_string(),
+        (None, Some(rejection)) => rejection.to_string(),
+        (None, None) => return None,
+    };
+    Some(format!("Disconnecting: {reason}"))
+}
+
+/// Returns the notice sent to a client whose new connection is refused because of `refusal`.
+pub fn refusal_notice(refusal: &Refusal) -> String {
+    format!("Connection refused: {refusal}, please try again later\n")
+}
+
+/// Returns the line telling a client that a line it sent was ignored because of `rejection`.
+pub fn ignored_line(rejection: &Rejection) -> String {
+    format!("Ignored {rejection}")
+}
+
+/// Returns the line telling a chat client what became of a line it sent too soon after others, or
+/// `None` if it is not told, either because the line was passed on or because the client is muted.
+pub fn flood_notice(verdict: &Verdict) -> Option<String> {
+    match verdict {
+        Verdict::Allow | Verdict::Muted => None,
+        Verdict::Warn => {
+            Some("Not sent: you are sending messages too fast, please slow down".to_owned())
+        }
+        Verdict::Mute(duration) => Some(format!(
+            "You are muted for {} seconds for sending messages too fast",
+            duration.as_secs()
+        )),
+        Verdict::Kick => Some("Disconnecting: sending messages too fast".to_owned()),
+    }
+}
+
+/// Returns the display name contained in the first line a chat client sends.
+pub fn parse_display_name(line: &str) -> String {
+    line.trim().to_owned()
+}
+
+/// Returns the line broadcast to all chat clients when `display_name` sends `line`. A trailing
+/// newline in `line` is preserved.
+pub fn chat_line(display_name: &str, line: &str) -> String {
+    display_name.to_owned() + ": " + line
+}
+
+/// Returns `message` as the line sent to client `recipient`, including its trailing newline.
+///
+/// Messages sent to any room other than the default room are prefixed with the room's name, so
+/// lines in the default room look the same as they did before the chat servers had rooms. A
+/// private message reads differently for its sender, who is reminded who it was sent to, and a
+/// message replayed from history is marked as such.
+pub fn format_message(message: &Message, recipient: ClientId) -> String {
+    let name = &message.display_name;
+    let body = &message.body;
+
+    let line = match (&message.kind, message.room.as_deref()) {
+        (MessageKind::Join, None) => format!("{name} has entered the chat"),
+        (MessageKind::Join, Some(room)) => format!("{name} has joined #{room}"),
+        (MessageKind::Leave, None) => format!("{name} has left the chat ({body})"),
+        (MessageKind::Leave, Some(room)) => format!("{name} has left #{room}"),
+        (MessageKind::Chat, room) => in_room(room, chat_line(name, body)),
+        (MessageKind::Action, room) => in_room(room, format!("* {name} {body}")),
+        (MessageKind::Rename, _) => format!("{body} is now known as {name}"),
+        (MessageKind::Private { recipient: to }, _) if message.sender == Some(recipient) => {
+            format!("[private to {to}] {name}: {body}")
+        }
+        (MessageKind::Private { .. }, _) => format!("[private] {name}: {body}"),
+        (MessageKind::Users { names }, room) => {
+            format!(
+                "People in #{}: {}",
+                room.unwrap_or_default(),
+                names.join(", ")
+            )
+        }
+        (
+            MessageKind::System | MessageKind::Prompt | MessageKind::Pong | MessageKind::Error(_),
+            _,
+        ) => body.clone(),
+    };
+
+    if message.replayed {
+        format!("[history] {line}\n")
+    } else {
+        line + "\n"
+    }
+}
+
+/// Returns `line`, prefixed with the name of `room` unless it is the default room.
+fn in_room(room: Option<&str>, line: String) -> String {
+    match room {
+        Some(room) if room != DEFAULT_ROOM => format!("[#{room}] {line}"),
+        _ => line,
+    }
+}
+
+/// Returns the line telling a chat client that its lines are now sent to `room`.
+pub fn talking_in(room: &str) -> String {
+    format!("Now talking in #{room}")
+}
+
+/// Returns the line listing chat `rooms`, each given with its number of members.
+pub fn room_list(rooms: &[(&str, usize)]) -> String {
+    let rooms: Vec<String> = rooms
+        .iter()
+        .map(|(room, members)| format!("#{room} ({members})"))
+        .collect();
+    format!("Rooms: {}", rooms.join(", "))
+}
+
+/// Returns the line telling a chat client it cannot leave `room` because it is not in it.
+pub fn not_in_room(room: &str) -> String {
+    format!("You are not in #{room}")
+}
+
+/// Returns the line telling a chat client that `room` cannot be used as a room name.
+pub fn invalid_room_name(room: &str) -> String {
+    format!("Invalid room name '{room}': use up to {MAX_ROOM_NAME_LEN} letters, digits, '-' or '_'")
+}
+
+/// Returns the line telling a chat client how to use a command.
+pub fn usage(synopsis: &str) -> String {
+    format!("Usage: {synopsis}")
+}
+
+/// Returns the line telling a chat client that `/{command}` is not a command.
+pub fn unknown_command(command: &str) -> String {
+    format!("Unknown command: /{command}; use /help to list the commands")
+}
+
+/// Returns the line telling a chat client that nobody is using `display_name`.
+pub fn no_such_user(display_name: &str) -> String {
+    format!("No such user: {display_name}")
+}
+
+/// Returns the line telling a chat client why it cannot use the display name it asked for.
+pub fn name_rejected(error: &NameError) -> String {
+    error.to_string()
+}
+
+/// Returns the lines describing chat commands, each given as its synopsis and a summary.
+pub fn help(commands: &[(String, &str)]) -> String {
+    let width = commands
+        .iter()
+        .map(|(synopsis, _)| synopsis.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut help = String::from("Commands:");
+    for (synopsis, summary) in commands {
+        help += &format!("\n  {synopsis:width$}  {summary}");
+    }
+    help
+}
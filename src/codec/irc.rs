@@ -0,0 +1,230 @@
+//! The subset of IRC (RFC 1459 and RFC 2812) spoken by chat clients that connect to a server's IRC
+//! port, so standard IRC clients can be pointed at the chat servers.
+//!
+//! Clients register by sending `NICK`, which enters the chat, and `USER`, which is accepted but
+//! otherwise ignored. They can then use `JOIN`, `PART`, `PRIVMSG`, `NAMES`, `NICK`, `PING` and
+//! `QUIT`. Rooms are channels, e.g. `#lobby`, which every client is put in once registered. Any
+//! other command is run as the chat command of the same name, e.g. `HELP` or `ROOMS`.
+//!
+//! The server sends the usual numeric replies: `001` once registered, `353` and `366` listing the
+//! members of a channel, and an error numeric when something cannot be done. Other notices from
+//! the server are sent as `NOTICE`s, as are messages replayed from a room's history.
+
+use super::format_message;
+use crate::broker::{ClientId, ErrorCode, Message, MessageKind};
+use crate::chat::{Chat, Input, DEFAULT_ROOM};
+
+/// The name the server gives itself in the messages it sends.
+pub const SERVER_NAME: &str = "tcp_echo";
+
+/// Marks the start and end of a CTCP request, such as the `ACTION` sent by `/me`.
+const CTCP_DELIMITER: char = '\x01';
+
+/// Returns what a client asks for by sending `line`. A line may ask for nothing, such as `USER`, or
+/// for one thing in each of the channels or nicknames it lists, as in `JOIN #rust,#async`.
+pub fn decode(line: &str) -> Vec<Input> {
+    let Some((command, params)) = parse(line) else {
+        return Vec::new();
+    };
+    let param = |i: usize| params.get(i).copied().unwrap_or_default();
+
+    match command.to_ascii_uppercase().as_str() {
+        "NICK" => vec![run("nick", param(0))],
+        // The user name and real name are not used, and the server never sends a PING of its own.
+        "USER" | "PONG" => Vec::new(),
+        "JOIN" => targets(param(0)).map(|room| run("join", room)).collect(),
+        "PART" if param(0).is_empty() => vec![run("part", "")],
+        "PART" => targets(param(0)).map(|room| run("part", room)).collect(),
+        "NAMES" if param(0).is_empty() => vec![run("who", "")],
+        "NAMES" => targets(param(0)).map(|room| run("who", room)).collect(),
+        "PRIVMSG" | "NOTICE" if params.len() < 2 => {
+            vec![Input::Invalid(format!("Usage: {command} <target> :<text>"))]
+        }
+        "PRIVMSG" | "NOTICE" => targets(param(0))
+            .map(|target| send(target, param(1)))
+            .collect(),
+        "PING" => vec![Input::Ping(param(0).to_owned())],
+        "QUIT" => vec![run("quit", "")],
+        _ => vec![run(&command.to_ascii_lowercase(), &params.join(" "))],
+    }
+}
+
+/// Splits `line` into its command and parameters, ignoring any prefix. Returns `None` for a blank
+/// line.
+fn parse(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let line = match line.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ')?.1,
+        None => line,
+    };
+
+    // The last parameter may contain spaces if it starts with a colon.
+    let (line, trailing) = match line.split_once(" :") {
+        Some((line, trailing)) => (line, Some(trailing)),
+        None => (line, None),
+    };
+
+    let mut words = line.split(' ').filter(|word| !word.is_empty());
+    let command = words.next()?;
+    let mut params: Vec<&str> = words.collect();
+    params.extend(trailing);
+    Some((command, params))
+}
+
+/// Returns the non-empty entries of the comma-separated list of channels or nicknames `targets`.
+fn targets(targets: &str) -> impl Iterator<Item = &str> {
+    targets.split(',').filter(|target| !target.is_empty())
+}
+
+/// Returns the input running the chat command called `name` with `args`.
+fn run(name: &str, args: &str) -> Input {
+    Input::Command {
+        name: name.to_owned(),
+        args: args.to_owned(),
+    }
+}
+
+/// Returns the input sending `text` to `target`, which is a channel if it starts with `#` and a
+/// nickname otherwise. A CTCP `ACTION` sent to a channel is a `/me`; one sent to a nickname is
+/// passed on as it is, for the recipient's client to show.
+fn send(target: &str, text: &str) -> Input {
+    if !target.starts_with('#') {
+        return run("msg", &format!("{target} {text}"));
+    }
+
+    let room = Some(target.to_owned());
+    match text
+        .strip_prefix("\x01ACTION ")
+        .map(|action| action.trim_end_matches(CTCP_DELIMITER))
+    {
+        Some(action) => Input::Act {
+            room,
+            text: action.to_owned(),
+        },
+        None => Input::Say {
+            room,
+            text: text.to_owned(),
+        },
+    }
+}
+
+/// Returns `message` as the lines sent to client `recipient`, each ending with CRLF. A client's own
+/// chat lines are not echoed back to it, as IRC clients show them as they are sent, and the prompt
+/// for a display name is not sent at all.
+pub fn encode(message: &Message, recipient: ClientId, chat: &Chat) -> String {
+    // Until the client has registered, numeric replies are addressed to `*`.
+    let nick = chat.display_name(recipient).unwrap_or("*");
+    let from = &message.display_name;
+    let body = &message.body;
+    let own = message.sender == Some(recipient);
+
+    // IRC has no way to mark messages as old, so history is replayed as notices from the server.
+    if message.replayed {
+        return notice(nick, format_message(message, recipient).trim_end());
+    }
+
+    match (&message.kind, message.room.as_deref()) {
+        (MessageKind::Join, room) => {
+            let room = room.unwrap_or(DEFAULT_ROOM);
+            let joined = from_user(from, &format!("JOIN #{room}"));
+            if !own {
+                return joined;
+            }
+
+            let mut lines = String::new();
+            if message.room.is_none() {
+                lines += &numeric("001", nick, &format!(":Welcome to the chat, {nick}"));
+                lines += &numeric("422", nick, ":MOTD File is missing");
+            }
+            lines + &joined + &names(nick, room, &chat.members(room))
+        }
+        (MessageKind::Leave, None) => from_user(from, &format!("QUIT :{body}")),
+        (MessageKind::Leave, Some(room)) => from_user(from, &format!("PART #{room}")),
+        (MessageKind::Chat | MessageKind::Action, _) if own => String::new(),
+        (MessageKind::Chat, room) => privmsg(from, &channel(room), body),
+        (MessageKind::Action, room) => privmsg(
+            from,
+            &channel(room),
+            &format!("{CTCP_DELIMITER}ACTION {body}{CTCP_DELIMITER}"),
+        ),
+        (MessageKind::Rename, _) => from_user(body, &format!("NICK :{from}")),
+        // The sender's copy is only needed if it sent the message to itself.
+        (MessageKind::Private { recipient: to }, _)
+            if own && chat.find_client(to) != Some(recipient) =>
+        {
+            String::new()
+        }
+        (MessageKind::Private { recipient: to }, _) => privmsg(from, to, body),
+        (MessageKind::Users { names: members }, room) => {
+            let members: Vec<&str> = members.iter().map(String::as_str).collect();
+            names(nick, room.unwrap_or_default(), &members)
+        }
+        (MessageKind::System, _) => notice(nick, body),
+        (MessageKind::Prompt, _) => String::new(),
+        (MessageKind::Pong, _) => format!(":{SERVER_NAME} PONG {SERVER_NAME} :{body}\r\n"),
+        (MessageKind::Error(code), _) => error(nick, code, body),
+    }
+}
+
+/// Returns the error numeric telling `nick` about the error with `code`, described by `body`.
+/// Errors without a numeric of their own are sent as a notice.
+fn error(nick: &str, code: &ErrorCode, body: &str) -> String {
+    let (reply, subject) = match code {
+        ErrorCode::NoSuchUser(name) => ("401", word(name).to_owned()),
+        ErrorCode::InvalidRoom(room) => ("403", channel(Some(word(room)))),
+        ErrorCode::UnknownCommand(command) => ("421", word(command).to_ascii_uppercase()),
+        ErrorCode::InvalidName(name) if name.is_empty() => ("431", String::new()),
+        ErrorCode::InvalidName(name) => ("432", word(name).to_owned()),
+        ErrorCode::NameTaken(name) => ("433", word(name).to_owned()),
+        ErrorCode::NotInRoom(Some(room)) => ("442", channel(Some(word(room)))),
+        ErrorCode::MissingArguments(command) => ("461", command.to_ascii_uppercase()),
+        ErrorCode::NotInRoom(None) | ErrorCode::Invalid => return notice(nick, body),
+    };
+
+    if subject.is_empty() {
+        numeric(reply, nick, &format!(":{body}"))
+    } else {
+        numeric(reply, nick, &format!("{subject} :{body}"))
+    }
+}
+
+/// Returns the reply `353` listing the `members` of `room` to `nick`, followed by the `366` that
+/// ends the list.
+fn names(nick: &str, room: &str, members: &[&str]) -> String {
+    let room = channel(Some(room));
+    numeric("353", nick, &format!("= {room} :{}", members.join(" ")))
+        + &numeric("366", nick, &format!("{room} :End of /NAMES list"))
+}
+
+/// Returns the numeric reply `numeric` to `nick` with `params`.
+fn numeric(numeric: &str, nick: &str, params: &str) -> String {
+    format!(":{SERVER_NAME} {numeric} {nick} {params}\r\n")
+}
+
+/// Returns a notice from the server to `nick` for each line of `text`.
+fn notice(nick: &str, text: &str) -> String {
+    text.lines()
+        .map(|line| format!(":{SERVER_NAME} NOTICE {nick} :{line}\r\n"))
+        .collect()
+}
+
+/// Returns `command` as sent by the user called `name`.
+fn from_user(name: &str, command: &str) -> String {
+    format!(":{name}!{name}@{SERVER_NAME} {command}\r\n")
+}
+
+/// Returns `text` sent by the user called `name` to `target`.
+fn privmsg(name: &str, target: &str, text: &str) -> String {
+    from_user(name, &format!("PRIVMSG {target} :{text}"))
+}
+
+/// Returns the channel name of `room`.
+fn channel(room: Option<&str>) -> String {
+    format!("#{}", room.unwrap_or(DEFAULT_ROOM))
+}
+
+/// Returns the first word of `param`, as a parameter in the middle of a message cannot contain
+/// spaces.
+fn word(param: &str) -> &str {
+    param.split(' ').next().unwrap_or_default()
+}
@@ -0,0 +1,128 @@
+//! The JSON-lines protocol spoken by chat clients that connect to a server's JSON port, for bots
+//! and user interfaces that would otherwise have to pick apart the text protocol's lines.
+//!
+//! Each line a client sends is a JSON object whose `type` names a command, e.g.
+//! `{"type":"nick","name":"alice"}`, `{"type":"say","text":"hello"}`,
+//! `{"type":"join","room":"rust"}` or `{"type":"msg","to":"bob","text":"psst"}`. A `say` can name
+//! the `room` it is sent to, if not the client's current room, and `{"type":"ping","token":"1"}`
+//! is answered with a `pong` carrying the same token. Any other command can be run as
+//! `{"type":"<command>","args":"<arguments>"}`.
+//!
+//! Each line the server sends is a JSON object whose `type` is one of `message`, `join`, `leave`,
+//! `rename`, `users`, `system`, `pong` or `error`, with a `time` in RFC 3339 format and `"history": true`
+//! if it is being replayed from a room's history.
+
+use crate::broker::{Message, MessageKind};
+use crate::chat::log::format_time;
+use crate::chat::Input;
+use serde_json::{json, Value};
+
+/// Returns what a client asks for by sending `line`. A line that is not a valid command is decoded
+/// as `Input::Invalid`, so the client can be told what is wrong with it.
+pub fn decode(line: &str) -> Input {
+    let command = match serde_json::from_str::<Value>(line) {
+        Ok(command) => command,
+        Err(e) => return Input::Invalid(format!("Invalid JSON: {e}")),
+    };
+
+    decode_command(&command).unwrap_or_else(Input::Invalid)
+}
+
+fn decode_command(command: &Value) -> Result<Input, String> {
+    let name = string_field(command, "type")?;
+
+    Ok(match name {
+        "say" => Input::Say {
+            room: Some(optional_field(command, "room")?)
+                .filter(|room| !room.is_empty())
+                .map(str::to_owned),
+            text: string_field(command, "text")?.to_owned(),
+        },
+        "nick" => run(name, string_field(command, "name")?),
+        "join" => run(name, string_field(command, "room")?),
+        "part" | "who" => run(name, optional_field(command, "room")?),
+        "me" => run(name, string_field(command, "text")?),
+        "ping" => Input::Ping(optional_field(command, "token")?.to_owned()),
+        "msg" => run(
+            name,
+            &format!(
+                "{} {}",
+                string_field(command, "to")?,
+                string_field(command, "text")?
+            ),
+        ),
+        _ => run(name, optional_field(command, "args")?),
+    })
+}
+
+/// Returns the input running the command called `name` with `args`.
+fn run(name: &str, args: &str) -> Input {
+    Input::Command {
+        name: name.to_owned(),
+        args: args.to_owned(),
+    }
+}
+
+/// Returns the string field `name` of `command`, which must be present and not empty.
+fn string_field<'a>(command: &'a Value, name: &str) -> Result<&'a str, String> {
+    match optional_field(command, name)? {
+        "" => Err(format!("Missing \"{name}\" field")),
+        value => Ok(value),
+    }
+}
+
+/// Returns the string field `name` of `command`, or an empty string if it is absent or null. Line
+/// breaks are not allowed, as they would let one message pass for several in the text protocol.
+fn optional_field<'a>(command: &'a Value, name: &str) -> Result<&'a str, String> {
+    match command.get(name) {
+        None | Some(Value::Null) => Ok(""),
+        Some(Value::String(value)) if value.contains(['\n', '\r']) => {
+            Err(format!("The \"{name}\" field cannot contain line breaks"))
+        }
+        Some(Value::String(value)) => Ok(value),
+        Some(_) => Err(format!("The \"{name}\" field must be a string")),
+    }
+}
+
+/// Returns `message` as a line of JSON, including its trailing newline.
+pub fn encode(message: &Message) -> String {
+    let name = &message.display_name;
+    let room = message.room.as_deref();
+    let body = &message.body;
+
+    let mut object = match &message.kind {
+        MessageKind::Chat | MessageKind::Action => json!({
+            "type": "message",
+            "kind": message.kind.name(),
+            "from": name,
+            "room": room,
+            "text": body,
+        }),
+        MessageKind::Private { recipient } => json!({
+            "type": "message",
+            "kind": message.kind.name(),
+            "from": name,
+            "to": recipient,
+            "text": body,
+        }),
+        MessageKind::Join => json!({ "type": "join", "user": name, "room": room }),
+        MessageKind::Leave => json!({
+            "type": "leave",
+            "user": name,
+            "room": room,
+            "reason": room.is_none().then_some(body),
+        }),
+        MessageKind::Rename => json!({ "type": "rename", "from": body, "to": name }),
+        MessageKind::Users { names } => json!({ "type": "users", "room": room, "users": names }),
+        MessageKind::System | MessageKind::Prompt => json!({ "type": "system", "text": body }),
+        MessageKind::Pong => json!({ "type": "pong", "token": body }),
+        MessageKind::Error(_) => json!({ "type": "error", "message": body }),
+    };
+
+    object["time"] = json!(format_time(message.time));
+    if message.replayed {
+        object["history"] = json!(true);
+    }
+
+    object.to_string() + "\n"
+}
@@ -0,0 +1,741 @@
+//! Command-line configuration shared by every server binary.
+//!
+//! Options take the form `--name value`. Parsing is done by hand rather than with an argument
+//! parsing crate to keep the dependencies of these experiments to a minimum.
+
+use crate::listener::{LOCAL_ADDR_IPV6, LOCAL_PORT};
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a server waits for connections to finish when shutting down, unless configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many accepted connections can wait for a free thread pool worker, unless configured.
+pub const DEFAULT_QUEUE_DEPTH: usize = 16;
+
+/// How many chat messages can be queued for a client, unless configured.
+pub const DEFAULT_CLIENT_BUFFER: usize = 64;
+
+/// How many recent chat messages are replayed to each client that enters a room, unless configured.
+pub const DEFAULT_HISTORY_LENGTH: usize = 20;
+
+/// The size in bytes beyond which a chat log is rotated, unless configured.
+pub const DEFAULT_CHAT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The longest line accepted from a client, in bytes and excluding its newline, unless configured.
+pub const DEFAULT_MAX_LINE_LEN: usize = 4096;
+
+/// How many times a flooding chat client is warned before it is muted, unless configured.
+pub const DEFAULT_FLOOD_WARNINGS: u32 = 2;
+
+/// How long a flooding chat client is muted for, unless configured.
+pub const DEFAULT_FLOOD_MUTE: Duration = Duration::from_secs(30);
+
+/// How many times a flooding chat client is muted before it is disconnected instead, unless
+/// configured.
+pub const DEFAULT_FLOOD_KICK_AFTER: u32 = 2;
+
+/// Usage text printed for `--help` and after an invalid argument.
+pub const USAGE: &str = "\
+Options:
+    --bind <ADDRESS>    IP address to listen on [default: ::1, or 127.0.0.1 with --ipv4, or ::
+                        with --dual-stack]
+    --port <PORT>       TCP port to listen on; 0 picks a free port, which is printed at startup
+                        [default: 8080]
+    --ipv4              Listen for IPv4 connections only
+    --ipv6              Listen for IPv6 connections only [default]
+    --dual-stack        Listen for both IPv6 and IPv4 connections on a single IPv6 socket
+    --json-port <PORT>  Also listen on this TCP port for chat clients speaking the JSON-lines
+                        protocol (chat servers only) [default: none]
+    --ws-port <PORT>    Also listen on this TCP port for chat clients connecting with WebSockets,
+                        such as browsers (chat_async only) [default: none]
+    --irc-port <PORT>   Also listen on this TCP port for chat clients speaking IRC (chat servers
+                        only) [default: none]
+    --tls-cert <PATH>   Accept only TLS connections, on every port, using the certificate chain in
+                        this PEM file; requires --tls-key [default: plain TCP]
+    --tls-key <PATH>    The PEM file holding the private key of the --tls-cert certificate
+    --log-level <LEVEL> Least severe diagnostics printed: 'error', 'warn', 'info', 'debug' (which
+                        adds every line clients send) or 'trace' [default: info]
+    --log-format <FORMAT>
+                        How diagnostics are printed: 'text' for people, 'json' for one JSON object
+                        per line [default: text]
+    --shutdown-timeout <SECONDS>
+                        How long to wait for clients' in-flight messages to finish on shutdown
+                        [default: 5]
+    --idle-timeout <SECONDS>
+                        Warn and disconnect a client that sends no complete line for this long;
+                        0 means no limit [default: 0]
+    --line-timeout <SECONDS>
+                        Warn and disconnect a client that takes longer than this to finish a line
+                        it has started sending; 0 means no limit [default: 0]
+    --write-timeout <SECONDS>
+                        Disconnect a client that accepts nothing sent to it for this long; 0 means
+                        no limit [default: 0]
+    --max-line-length <BYTES>
+                        Longest line accepted from a client, excluding its newline; 0 means no
+                        limit [default: 4096]
+    --long-lines <POLICY>
+                        What to do with a longer line: 'truncate' cuts it short, 'reject' discards
+                        it and tells the client, 'disconnect' warns and disconnects the client
+                        [default: reject]
+    --utf8 <POLICY>     What to do with a line that is not valid UTF-8: 'lossy' replaces the
+                        invalid bytes with U+FFFD, 'strict' discards it and tells the client
+                        [default: lossy]
+    --keep-control-chars
+                        Pass on control characters and terminal escape sequences sent by clients
+                        rather than removing them
+    --max-connections <CONNECTIONS>
+                        Refuse new connections while this many are open; 0 means no limit
+                        [default: 0]
+    --max-per-ip <CONNECTIONS>
+                        Refuse new connections from an IP address that has this many open; 0 means
+                        no limit [default: 0]
+    --accept-rate <PER_SECOND>
+                        Refuse new connections arriving faster than this on average; 0 means no
+                        limit [default: 0]
+    --accept-burst <CONNECTIONS>
+                        Number of new connections accepted in a burst before --accept-rate applies
+                        [default: the --accept-rate]
+    --message-rate <PER_SECOND>
+                        Hold back chat lines a client sends faster than this on average; 0 means
+                        no limit (chat servers only) [default: 0]
+    --message-burst <MESSAGES>
+                        Number of chat lines accepted in a burst before --message-rate applies
+                        [default: the --message-rate]
+    --flood-warnings <WARNINGS>
+                        Number of times a client going over --message-rate is warned before it is
+                        muted [default: 2]
+    --flood-mute <SECONDS>
+                        How long a client is muted for, discarding everything it sends
+                        [default: 30]
+    --kick-after <MUTES>
+                        Disconnect a client rather than mute it once it has been muted this many
+                        times [default: 2]
+    --pool-size <THREADS>
+                        Handle connections with a fixed pool of worker threads rather than a thread
+                        per connection (threaded servers only) [default: 0, meaning no pool]
+    --queue-depth <CONNECTIONS>
+                        Number of accepted connections that can wait for a free worker thread
+                        [default: 16]
+    --when-full <POLICY>
+                        What to do with a new connection when the queue is full: 'queue' waits for
+                        space, 'reject' tells the client the server is busy [default: queue]
+    --client-buffer <MESSAGES>
+                        Number of chat messages queued for a client whose connection cannot keep up
+                        (chat servers only) [default: 64]
+    --slow-client <POLICY>
+                        What to do when a chat client's queue is full: 'drop-oldest' discards its
+                        oldest queued message, 'drop-client' disconnects it, 'block' makes everyone
+                        wait for it [default: drop-client]
+    --history <MESSAGES>
+                        Number of recent messages in a chat room replayed to each client that
+                        enters it; 0 disables history [default: 20]
+    --history-age <SECONDS>
+                        Only replay chat messages sent within this many seconds [default: no limit]
+    --chat-log <PATH>   Append every message sent to a chat room to this file, and reload the
+                        history from it at startup [default: no log]
+    --chat-log-max-size <BYTES>
+                        Size beyond which the chat log is renamed with a '.1' suffix and a new one
+                        started; 0 never rotates it [default: 10485760]
+    -h, --help          Print this help and exit";
+
+/// The IP protocol family, or families, a server accepts connections over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    Ipv4,
+    #[default]
+    Ipv6,
+    /// An IPv6 socket that also accepts IPv4 connections as IPv4-mapped IPv6 addresses.
+    DualStack,
+}
+
+/// Where a server listens for incoming connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenConfig {
+    /// The address to bind to, or `None` to use the default address for `family`.
+    pub bind: Option<IpAddr>,
+    /// The port to bind to, or 0 to have the OS pick a free port.
+    pub port: u16,
+    pub family: IpFamily,
+    /// The port to accept chat clients speaking the JSON-lines protocol on, if any.
+    pub json_port: Option<u16>,
+    /// The port to accept chat clients connecting with WebSockets on, if any.
+    pub ws_port: Option<u16>,
+    /// The port to accept chat clients speaking IRC on, if any.
+    pub irc_port: Option<u16>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            port: LOCAL_PORT,
+            family: IpFamily::default(),
+            json_port: None,
+            ws_port: None,
+            irc_port: None,
+        }
+    }
+}
+
+impl ListenConfig {
+    /// Returns the socket address to bind to.
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.socket_addr_with_port(self.port)
+    }
+
+    /// Returns the socket address to bind to for a listener on `port` rather than the main port.
+    pub fn socket_addr_with_port(&self, port: u16) -> SocketAddr {
+        let ip = self.bind.unwrap_or(match self.family {
+            IpFamily::Ipv4 => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpFamily::Ipv6 => IpAddr::V6(LOCAL_ADDR_IPV6),
+            IpFamily::DualStack => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+
+        SocketAddr::new(ip, port)
+    }
+}
+
+/// What the thread pool does with a newly accepted connection when its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Stop accepting connections until there is space in the queue. Clients that connect in the
+    /// meantime wait in the OS's backlog of pending connections.
+    #[default]
+    Queue,
+    /// Tell the client the server is busy and close the connection.
+    Reject,
+}
+
+impl FromStr for FullPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Self::Queue),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How severe a diagnostic is, from the most to the least. A server prints those at or above the
+/// configured level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Something went wrong with the server itself.
+    Error,
+    /// Something went wrong with a client, or a client was turned away or disconnected.
+    Warn,
+    /// The server started or stopped something, or a client connected or left.
+    #[default]
+    Info,
+    /// A client sent something, or the broadcaster relayed it.
+    Debug,
+    /// Everything else, such as each step of the accept loops.
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        })
+    }
+}
+
+/// How a server prints its diagnostics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line of text per record, for people to read.
+    #[default]
+    Text,
+    /// One JSON object per line, for programs to read.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which diagnostics a server prints, and how.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+/// What a server does with a line longer than the maximum line length.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LongLinePolicy {
+    /// Keep the start of the line, up to the maximum length, and discard the rest.
+    Truncate,
+    /// Discard the whole line and tell the client why.
+    #[default]
+    Reject,
+    /// Tell the client why and disconnect it.
+    Disconnect,
+}
+
+impl FromStr for LongLinePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "reject" => Ok(Self::Reject),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a server does with a line that is not valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Replace each invalid sequence of bytes with U+FFFD REPLACEMENT CHARACTER.
+    #[default]
+    Lossy,
+    /// Discard the whole line and tell the client why.
+    Strict,
+}
+
+impl FromStr for Utf8Policy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lossy" => Ok(Self::Lossy),
+            "strict" => Ok(Self::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The limits and clean-up applied to every line a client sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputConfig {
+    /// The longest line accepted, in bytes and excluding its newline, or 0 for no limit.
+    pub max_line_len: usize,
+    pub long_lines: LongLinePolicy,
+    pub utf8: Utf8Policy,
+    /// Whether control characters and terminal escape sequences are removed from input before it
+    /// is echoed or broadcast.
+    pub strip_controls: bool,
+    /// How fast a chat client may send lines. Not used by the echo servers.
+    pub flood: FloodConfig,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            long_lines: LongLinePolicy::default(),
+            utf8: Utf8Policy::default(),
+            strip_controls: true,
+            flood: FloodConfig::default(),
+        }
+    }
+}
+
+/// How fast a chat client may send lines, and what happens to one that sends them faster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FloodConfig {
+    /// The average number of lines accepted per second, or `None` for no limit.
+    pub message_rate: Option<u32>,
+    /// The number of lines accepted in a burst, if not `message_rate`.
+    pub message_burst: Option<u32>,
+    /// The number of lines held back with a warning before the client is muted.
+    pub warnings: u32,
+    /// How long a client is muted for.
+    pub mute: Duration,
+    /// The number of times a client is muted before it is disconnected instead.
+    pub kick_after: u32,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            message_rate: None,
+            message_burst: None,
+            warnings: DEFAULT_FLOOD_WARNINGS,
+            mute: DEFAULT_FLOOD_MUTE,
+            kick_after: DEFAULT_FLOOD_KICK_AFTER,
+        }
+    }
+}
+
+/// The limits on the connections a server accepts. Each limit is disabled if `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GovernorConfig {
+    /// The maximum number of connections open at once.
+    pub max_connections: Option<usize>,
+    /// The maximum number of connections open at once from a single IP address.
+    pub max_per_ip: Option<usize>,
+    /// The average number of new connections accepted per second.
+    pub accept_rate: Option<u32>,
+    /// The number of new connections accepted in a burst, if not `accept_rate`.
+    pub accept_burst: Option<u32>,
+}
+
+/// How the threaded servers dispatch accepted connections to threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// The number of worker threads, or 0 to spawn a thread per connection instead of using a pool.
+    pub size: usize,
+    /// The number of accepted connections that can wait for a free worker.
+    pub queue_depth: usize,
+    pub when_full: FullPolicy,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            when_full: FullPolicy::default(),
+        }
+    }
+}
+
+/// What the chat broker does with a message for a client whose outbound queue is full because the
+/// client is not reading its messages as fast as they are sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest message in the client's queue to make room.
+    DropOldest,
+    /// Disconnect the client, as IRC servers do when a client's send queue is exceeded.
+    #[default]
+    DropClient,
+    /// Wait until the client has made room, which delays the message for every other client.
+    Block,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-client" => Ok(Self::DropClient),
+            "block" => Ok(Self::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the chat servers queue messages for each client's writer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutboundConfig {
+    /// The maximum number of messages queued for a client.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CLIENT_BUFFER,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// The settings of the chat servers' `Chat`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatConfig {
+    /// The maximum number of recent messages in each room replayed to a client entering it.
+    pub history_length: usize,
+    /// If given, messages older than this are not replayed.
+    pub history_age: Option<Duration>,
+    /// The file every message sent to a room is appended to, if any.
+    pub log_path: Option<PathBuf>,
+    /// The size in bytes beyond which the log is rotated, or 0 to never rotate it.
+    pub log_max_size: u64,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            history_length: DEFAULT_HISTORY_LENGTH,
+            history_age: None,
+            log_path: None,
+            log_max_size: DEFAULT_CHAT_LOG_MAX_SIZE,
+        }
+    }
+}
+
+/// How long clients are given to send and receive data before they are disconnected. Each timeout
+/// is disabled if `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    /// How long a client can go without sending a complete line.
+    pub idle: Option<Duration>,
+    /// How long a client can take to finish a line once it has started sending it.
+    pub line: Option<Duration>,
+    /// How long a single write to a client can take.
+    pub write: Option<Duration>,
+}
+
+/// Where a server's TLS certificate and private key are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// A PEM file holding the server's certificate, optionally followed by the rest of its chain.
+    pub cert_path: PathBuf,
+    /// A PEM file holding the certificate's private key.
+    pub key_path: PathBuf,
+}
+
+/// The complete configuration of a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub log: LogConfig,
+    /// How long to wait for in-flight messages to be handled and sent when shutting down.
+    pub shutdown_timeout: Duration,
+    pub pool: PoolConfig,
+    pub outbound: OutboundConfig,
+    pub chat: ChatConfig,
+    pub timeouts: TimeoutConfig,
+    pub input: InputConfig,
+    pub governor: GovernorConfig,
+    /// The certificate and key to encrypt connections with, or `None` to accept plain TCP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: ListenConfig::default(),
+            log: LogConfig::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            pool: PoolConfig::default(),
+            outbound: OutboundConfig::default(),
+            chat: ChatConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            input: InputConfig::default(),
+            governor: GovernorConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the configuration from the program's command-line arguments. If `--help` is given,
+    /// the usage text is printed and the process exits successfully. If the arguments are invalid,
+    /// the error and usage text are printed and the process exits with status 2.
+    pub fn from_args() -> Self {
+        let mut args = env::args();
+        let program = args.next().unwrap_or_default();
+
+        match Self::parse(args) {
+            Ok(config) => config,
+            Err(ConfigError::Help) => {
+                println!("Usage: {program} [OPTIONS]\n\n{USAGE}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{e}\n\nUsage: {program} [OPTIONS]\n\n{USAGE}");
+                process::exit(2);
+            }
+        }
+    }
+
+    /// Parses the configuration from `args`, which must not include the program name.
+    pub fn parse<I>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+        let mut family = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => config.listen.bind = Some(next_value(&mut args, &arg)?),
+                "--port" => config.listen.port = next_value(&mut args, &arg)?,
+                "--ipv4" => family = Some(IpFamily::Ipv4),
+                "--ipv6" => family = Some(IpFamily::Ipv6),
+                "--dual-stack" => family = Some(IpFamily::DualStack),
+                "--json-port" => config.listen.json_port = Some(next_value(&mut args, &arg)?),
+                "--ws-port" => config.listen.ws_port = Some(next_value(&mut args, &arg)?),
+                "--irc-port" => config.listen.irc_port = Some(next_value(&mut args, &arg)?),
+                "--tls-cert" => tls_cert = Some(next_value(&mut args, &arg)?),
+                "--tls-key" => tls_key = Some(next_value(&mut args, &arg)?),
+                "--log-level" => config.log.level = next_value(&mut args, &arg)?,
+                "--log-format" => config.log.format = next_value(&mut args, &arg)?,
+                "--shutdown-timeout" => {
+                    config.shutdown_timeout = Duration::from_secs(next_value(&mut args, &arg)?)
+                }
+                "--idle-timeout" => config.timeouts.idle = next_timeout(&mut args, &arg)?,
+                "--line-timeout" => config.timeouts.line = next_timeout(&mut args, &arg)?,
+                "--write-timeout" => config.timeouts.write = next_timeout(&mut args, &arg)?,
+                "--max-line-length" => config.input.max_line_len = next_value(&mut args, &arg)?,
+                "--long-lines" => config.input.long_lines = next_value(&mut args, &arg)?,
+                "--utf8" => config.input.utf8 = next_value(&mut args, &arg)?,
+                "--keep-control-chars" => config.input.strip_controls = false,
+                "--max-connections" => {
+                    config.governor.max_connections = next_limit(&mut args, &arg)?
+                }
+                "--max-per-ip" => config.governor.max_per_ip = next_limit(&mut args, &arg)?,
+                "--accept-rate" => config.governor.accept_rate = next_limit(&mut args, &arg)?,
+                "--accept-burst" => config.governor.accept_burst = next_limit(&mut args, &arg)?,
+                "--message-rate" => config.input.flood.message_rate = next_limit(&mut args, &arg)?,
+                "--message-burst" => {
+                    config.input.flood.message_burst = next_limit(&mut args, &arg)?
+                }
+                "--flood-warnings" => config.input.flood.warnings = next_value(&mut args, &arg)?,
+                "--flood-mute" => {
+                    config.input.flood.mute = Duration::from_secs(next_value(&mut args, &arg)?)
+                }
+                "--kick-after" => config.input.flood.kick_after = next_value(&mut args, &arg)?,
+                "--pool-size" => config.pool.size = next_value(&mut args, &arg)?,
+                "--queue-depth" => config.pool.queue_depth = next_value(&mut args, &arg)?,
+                "--when-full" => config.pool.when_full = next_value(&mut args, &arg)?,
+                "--client-buffer" => config.outbound.capacity = next_value(&mut args, &arg)?,
+                "--slow-client" => config.outbound.overflow = next_value(&mut args, &arg)?,
+                "--history" => config.chat.history_length = next_value(&mut args, &arg)?,
+                "--history-age" => {
+                    config.chat.history_age =
+                        Some(Duration::from_secs(next_value(&mut args, &arg)?))
+                }
+                "--chat-log" => config.chat.log_path = Some(next_value(&mut args, &arg)?),
+                "--chat-log-max-size" => config.chat.log_max_size = next_value(&mut args, &arg)?,
+                "-h" | "--help" => return Err(ConfigError::Help),
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+
+        // Without an explicit family, infer it from the bind address, if any.
+        config.listen.family = match (family, config.listen.bind) {
+            (Some(IpFamily::Ipv4), Some(IpAddr::V6(addr))) => {
+                return Err(ConfigError::FamilyMismatch(IpAddr::V6(addr)))
+            }
+            (Some(IpFamily::Ipv6 | IpFamily::DualStack), Some(IpAddr::V4(addr))) => {
+                return Err(ConfigError::FamilyMismatch(IpAddr::V4(addr)))
+            }
+            (Some(family), _) => family,
+            (None, Some(IpAddr::V4(_))) => IpFamily::Ipv4,
+            (None, _) => IpFamily::Ipv6,
+        };
+
+        config.tls = match (tls_cert, tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            (Some(_), None) => return Err(ConfigError::MissingOption("--tls-key".to_owned())),
+            (None, Some(_)) => return Err(ConfigError::MissingOption("--tls-cert".to_owned())),
+        };
+
+        Ok(config)
+    }
+}
+
+/// Takes the value following `flag` from `args` and parses it. Public so that programs with options
+/// of their own, such as `load_gen`, can parse them the same way.
+pub fn next_value<T, I>(args: &mut I, flag: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    I: Iterator<Item = String>,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| ConfigError::MissingValue(flag.to_owned()))?;
+
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        flag: flag.to_owned(),
+        value,
+    })
+}
+
+/// Takes the number of seconds following `flag` from `args` as a timeout, which is disabled by 0.
+fn next_timeout<I>(args: &mut I, flag: &str) -> Result<Option<Duration>, ConfigError>
+where
+    I: Iterator<Item = String>,
+{
+    let seconds = next_value(args, flag)?;
+    Ok((seconds > 0).then(|| Duration::from_secs(seconds)))
+}
+
+/// Takes the limit following `flag` from `args`, which is disabled by 0.
+fn next_limit<T, I>(args: &mut I, flag: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr + Default + PartialEq,
+    I: Iterator<Item = String>,
+{
+    let limit = next_value(args, flag)?;
+    Ok((limit != T::default()).then_some(limit))
+}
+
+/// The reasons command-line arguments can fail to parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `--help` was requested, so the caller should print the usage text rather than run.
+    Help,
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue {
+        flag: String,
+        value: String,
+    },
+    /// The bind address does not belong to the requested IP family.
+    FamilyMismatch(IpAddr),
+    /// An option that is required by another option given was not given.
+    MissingOption(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Help => write!(f, "Help requested"),
+            Self::UnknownArgument(arg) => write!(f, "Unknown argument '{arg}'"),
+            Self::MissingValue(flag) => write!(f, "Missing value for '{flag}'"),
+            Self::InvalidValue { flag, value } => write!(f, "Invalid value '{value}' for '{flag}'"),
+            Self::FamilyMismatch(addr) => {
+                write!(
+                    f,
+                    "Bind address '{addr}' does not match the requested IP family"
+                )
+            }
+            Self::MissingOption(flag) => write!(f, "Missing option '{flag}'"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
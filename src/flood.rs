@@ -0,0 +1,109 @@
+//! Flood protection for the chat servers, which stops a single client from making the broadcaster
+//! relay lines to everyone as fast as the client can send them.
+//!
+//! Each chat connection handler keeps a `FloodGuard` and checks it before passing a line to the
+//! broadcaster. Lines within the client's message rate are passed on. A line over the rate is held
+//! back, and the client warned. A client that keeps going over the rate after being warned is muted
+//! for a while, and everything it sends meanwhile is discarded. A client that has already been
+//! muted too often is disconnected instead. The rate is enforced with a token bucket, so short
+//! bursts of lines are still passed on.
+
+use crate::config::FloodConfig;
+use crate::rate::TokenBucket;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What becomes of a line a client sends, as decided by a `FloodGuard`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The line is within the rate and is passed on.
+    Allow,
+    /// The line is over the rate and is held back, and the client is warned.
+    Warn,
+    /// The line is over the rate once too often, so the client is muted for the given time.
+    Mute(Duration),
+    /// The client is muted, so the line is discarded without telling it.
+    Muted,
+    /// The client has been muted too often and is disconnected.
+    Kick,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Allow => f.write_str("allowed"),
+            Self::Warn => f.write_str("warned"),
+            Self::Mute(duration) => write!(f, "muted for {} seconds", duration.as_secs()),
+            Self::Muted => f.write_str("still muted"),
+            Self::Kick => f.write_str("disconnected"),
+        }
+    }
+}
+
+/// Tracks how fast one client sends lines, and decides what becomes of each.
+#[derive(Debug)]
+pub struct FloodGuard {
+    config: FloodConfig,
+    /// Limits the client's message rate, if it is limited.
+    bucket: Option<TokenBucket>,
+    /// The lines held back since the client was last muted or kept within the rate for long
+    /// enough to be forgiven.
+    warnings: u32,
+    /// The number of times the client has been muted.
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(config: FloodConfig) -> Self {
+        let bucket = config.message_rate.map(|rate| {
+            let burst = config.message_burst.unwrap_or(rate);
+            TokenBucket::new(f64::from(rate), f64::from(burst))
+        });
+
+        Self {
+            config,
+            bucket,
+            warnings: 0,
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Decides what becomes of the line the client has just sent. Warnings are forgotten once the
+    /// client has sent nothing for long enough to use a full burst again, but mutes never are.
+    pub fn check(&mut self) -> Verdict {
+        let Some(bucket) = &mut self.bucket else {
+            return Verdict::Allow;
+        };
+
+        if let Some(until) = self.muted_until {
+            if Instant::now() < until {
+                return Verdict::Muted;
+            }
+            self.muted_until = None;
+        }
+
+        if bucket.is_full() {
+            self.warnings = 0;
+        }
+
+        if bucket.take() {
+            return Verdict::Allow;
+        }
+
+        if self.warnings < self.config.warnings {
+            self.warnings += 1;
+            return Verdict::Warn;
+        }
+
+        if self.mutes >= self.config.kick_after {
+            return Verdict::Kick;
+        }
+
+        self.warnings = 0;
+        self.mutes += 1;
+        self.muted_until = Some(Instant::now() + self.config.mute);
+        Verdict::Mute(self.config.mute)
+    }
+}
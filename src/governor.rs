@@ -0,0 +1,132 @@
+//! The connection governor, which decides in a server's accept loop whether each new connection is
+//! served, so that no one host, nor a flood of connections from many, can take all of a server's
+//! threads, tasks or file descriptors.
+//!
+//! A connection is refused if the server already has its maximum number of connections open, if
+//! the client's IP address already has its maximum open, or if connections are arriving faster
+//! than the accept rate allows. The rate is enforced with a token bucket, so short bursts of
+//! connections are still accepted. The `blocking` and `asynchronous` submodules apply a `Governor`
+//! to accepted streams, telling refused clients why before closing their connections.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::config::GovernorConfig;
+use crate::rate::TokenBucket;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Why a new connection is refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The server already has its maximum number of connections open.
+    ServerFull,
+    /// The client's IP address already has its maximum number of connections open.
+    TooManyFromAddress,
+    /// Connections are arriving faster than the accept rate allows.
+    TooFast,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ServerFull => "server is full",
+            Self::TooManyFromAddress => "too many connections from your address",
+            Self::TooFast => "too many new connections",
+        })
+    }
+}
+
+/// The connections a server has open, counted so that the limits can be enforced.
+#[derive(Debug)]
+struct State {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Limits the accept rate, if it is limited.
+    bucket: Option<TokenBucket>,
+}
+
+/// Decides whether new connections are served, according to the limits in a `GovernorConfig`.
+#[derive(Debug)]
+pub struct Governor {
+    config: GovernorConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl Governor {
+    pub fn new(config: GovernorConfig) -> Self {
+        let bucket = config.accept_rate.map(|rate| {
+            let burst = config.accept_burst.unwrap_or(rate);
+            TokenBucket::new(f64::from(rate), f64::from(burst))
+        });
+        let state = State {
+            total: 0,
+            per_ip: HashMap::new(),
+            bucket,
+        };
+
+        Self {
+            config,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Decides whether a new connection from `ip` is served. If it is, returns a permit that must
+    /// be held for as long as the connection is open, as the connection counts towards the limits
+    /// until the permit is dropped. Otherwise, returns why the connection is refused.
+    pub fn admit(&self, ip: IpAddr) -> Result<Permit, Refusal> {
+        // An IPv4 client of a dual-stack server has an IPv4-mapped IPv6 address, which must count
+        // as the same address as the client connecting over IPv4.
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+
+        if self
+            .config
+            .max_connections
+            .is_some_and(|max| state.total >= max)
+        {
+            return Err(Refusal::ServerFull);
+        }
+        let from_ip = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.config.max_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(Refusal::TooManyFromAddress);
+        }
+        // Checked last, so that a refused connection does not use up a token.
+        if let Some(bucket) = &mut state.bucket {
+            if !bucket.take() {
+                return Err(Refusal::TooFast);
+            }
+        }
+
+        state.total += 1;
+        *state.per_ip.entry(ip).or_default() += 1;
+
+        Ok(Permit {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// Counts a connection towards a `Governor`'s limits until dropped.
+#[derive(Debug)]
+pub struct Permit {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.total -= 1;
+
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Applying a `Governor` to connections accepted with the async-std crate.
+
+use super::{Governor, Permit, Refusal};
+use crate::codec;
+use crate::log::Context;
+use crate::warn;
+use async_std::io::{self, WriteExt};
+use async_std::net::TcpStream;
+use std::net::Shutdown;
+use std::time::Duration;
+
+/// How long a refused client is given to accept the notice saying why.
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Decides through `governor` whether the connection on `stream` is served. Returns the permit to
+/// hold while serving it, or `None` if it is refused, in which case the connection has been closed
+/// after telling the client why if `notify` is true. `notify` should be false if connections are
+/// encrypted, as the notice is sent in plaintext.
+pub async fn admit(governor: &Governor, stream: &TcpStream, notify: bool) -> Option<Permit> {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(e) => {
+            warn!(
+                Context::SERVER,
+                "connection_dropped", "Dropping connection whose peer cannot be queried: {e}"
+            );
+            return None;
+        }
+    };
+
+    match governor.admit(peer.ip()) {
+        Ok(permit) => Some(permit),
+        Err(refusal) => {
+            warn!(
+                Context::peer(peer),
+                "connection_refused", "Refusing connection: {refusal}"
+            );
+            reject(stream, refusal, notify).await;
+            None
+        }
+    }
+}
+
+/// Closes the connection on `stream`, first telling the client it is refused for `refusal` if
+/// `notify` is true.
+async fn reject(mut stream: &TcpStream, refusal: Refusal, notify: bool) {
+    // Errors are ignored as the connection is being closed anyway.
+    if notify {
+        let notice = codec::refusal_notice(&refusal);
+        let _ = io::timeout(NOTICE_WRITE_TIMEOUT, stream.write_all(notice.as_bytes())).await;
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
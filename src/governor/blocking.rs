@@ -0,0 +1,52 @@
+//! Applying a `Governor` to connections accepted with the blocking I/O of `std::net`.
+
+use super::{Governor, Permit, Refusal};
+use crate::codec;
+use crate::log::Context;
+use crate::warn;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+/// How long a refused client is given to accept the notice saying why.
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Decides through `governor` whether the connection on `stream` is served. Returns the permit to
+/// hold while serving it, or `None` if it is refused, in which case the connection has been closed
+/// after telling the client why if `notify` is true. `notify` should be false if connections are
+/// encrypted, as the notice is sent in plaintext.
+pub fn admit(governor: &Governor, stream: &TcpStream, notify: bool) -> Option<Permit> {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(e) => {
+            warn!(
+                Context::SERVER,
+                "connection_dropped", "Dropping connection whose peer cannot be queried: {e}"
+            );
+            return None;
+        }
+    };
+
+    match governor.admit(peer.ip()) {
+        Ok(permit) => Some(permit),
+        Err(refusal) => {
+            warn!(
+                Context::peer(peer),
+                "connection_refused", "Refusing connection: {refusal}"
+            );
+            reject(stream, refusal, notify);
+            None
+        }
+    }
+}
+
+/// Closes the connection on `stream`, first telling the client it is refused for `refusal` if
+/// `notify` is true.
+fn reject(mut stream: &TcpStream, refusal: Refusal, notify: bool) {
+    // Errors are ignored as the connection is being closed anyway.
+    if notify {
+        let _ = stream.set_write_timeout(Some(NOTICE_WRITE_TIMEOUT));
+        let _ = stream.write_all(codec::refusal_notice(&refusal).as_bytes());
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
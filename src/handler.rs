@@ -0,0 +1,61 @@
+//! Connection handlers that service a single client connection from start to finish. The echo
+//! handlers send each line straight back to its sender, while the chat handlers forward each line to
+//! a broadcaster (see the `broker` module) as an `Event`.
+//!
+//! A handler that cannot go on serving its client returns a `ConnectionError` rather than
+//! panicking, having closed the connection, so a failure only ever affects the one client.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::debug;
+use crate::log::Context;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a handler stopped serving its client before the client disconnected or was disconnected on
+/// purpose, e.g., for exceeding a timeout.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The connection could not be prepared for use, e.g., because its socket could not be queried
+    /// or cloned.
+    Setup(io::Error),
+    /// The TLS handshake with the client failed.
+    Tls(io::Error),
+    /// Reading from the client failed.
+    Read(io::Error),
+    /// Writing to the client failed.
+    Write(io::Error),
+    /// The chat broadcaster is no longer running, so there is nothing to forward the client's input
+    /// to.
+    BrokerStopped,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Setup(e) => write!(f, "failed to set up connection: {e}"),
+            Self::Tls(e) => write!(f, "TLS handshake failed: {e}"),
+            Self::Read(e) => write!(f, "failed to read from client: {e}"),
+            Self::Write(e) => write!(f, "failed to write to client: {e}"),
+            Self::BrokerStopped => f.write_str("the broadcaster has stopped"),
+        }
+    }
+}
+
+impl Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Setup(e) | Self::Tls(e) | Self::Read(e) | Self::Write(e) => Some(e),
+            Self::BrokerStopped => None,
+        }
+    }
+}
+
+/// Logs `line`, which the client of the connection in `context` sent, at `LogLevel::Debug`, so that
+/// the content of every line is only logged when asked for.
+fn log_line(context: Context, line: &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    debug!(context, "line_received", "[{} chars] {line}", line.len());
+}
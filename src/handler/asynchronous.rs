@@ -0,0 +1,518 @@
+//! Connection handlers built on the async-std crate. Each handler is intended to be run as its own
+//! task, yielding to other tasks whenever it waits for network input.
+
+use super::{log_line, ConnectionError};
+use crate::broker::asynchronous::{write_outbound, ClientHandle, Event, Framing, OutboundQueue};
+use crate::broker::{next_client_id, ClientId, ErrorCode, LeaveReason, Message};
+use crate::codec::{self, Protocol};
+use crate::config::{InputConfig, OutboundConfig, TimeoutConfig};
+use crate::flood::{FloodGuard, Verdict};
+use crate::input::{self, Line, Rejection};
+use crate::log::Context;
+use crate::timeout::asynchronous::{handshake_within, write_within, TimedReader};
+use crate::tls::{self, TlsAcceptor};
+use crate::websocket::asynchronous::{read_handshake, read_message, FrameWriter};
+use crate::websocket::{handshake_rejection, handshake_response, parse_handshake};
+use crate::{debug, info, warn};
+use async_std::channel::Sender;
+use async_std::io::{BufRead, BufReader, WriteExt};
+use async_std::net::TcpStream;
+use async_std::task;
+use std::io;
+use std::net::Shutdown;
+
+/// Receives newline-delimited input from `stream`, and sends the same data back on the same stream.
+/// The connection is encrypted with TLS if `tls` is given. Each line is limited and cleaned up as
+/// `input` says, and the client is told of any line that is ignored. A client that exceeds one of
+/// `timeouts`, or sends a line for which `input` says to disconnect it, is told so and
+/// disconnected.
+///
+/// # Errors
+///
+/// Returns an error, having closed the connection, if it cannot be set up, if the TLS handshake
+/// fails, or if reading from or writing to the client fails other than by the client disconnecting.
+pub async fn handle_echo_connection(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let result = serve_echo(stream.clone(), tls, timeouts, input).await;
+    close_if_failed(&stream, result)
+}
+
+/// Registers the client with the broadcaster through `broker`, then continuously receives
+/// newline-delimited input from the `stream` passed, and sends each line to the broadcaster, which
+/// decodes it according to `protocol` and decides what to do with it. This process is repeated
+/// until `stream` is closed or an error occurs, after which the broadcaster is told why the client
+/// left. The connection is encrypted with TLS if `tls` is given, and the client is not registered
+/// if the TLS handshake fails. Each line is limited and cleaned up as `input` says, and the client
+/// is told of any line that is ignored. Lines sent faster than the message rate in `input` allows
+/// are held back, and the client warned, muted or disconnected. A client that exceeds one of
+/// `timeouts`, or sends a line for which `input` says to disconnect it, is disconnected, after
+/// being warned unless it could not be written to.
+///
+/// Everything sent to the client, starting with the broadcaster's prompt for a display name, goes
+/// through an outbound queue of the size given by `outbound`, which a separate writer task drains
+/// onto `stream`. Once the client disconnects, this waits for the writer to finish writing anything
+/// still queued.
+///
+/// # Errors
+///
+/// Returns an error, having closed the connection, if it cannot be set up, if the TLS handshake
+/// fails, or if the broadcaster has stopped.
+pub async fn handle_chat_connection(
+    stream: TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    protocol: Protocol,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let result = serve_chat(
+        stream.clone(),
+        broker,
+        outbound,
+        protocol,
+        tls,
+        timeouts,
+        input,
+    )
+    .await;
+    close_if_failed(&stream, result)
+}
+
+/// Accepts the WebSocket handshake a browser or other WebSocket client sends on `stream`, then
+/// handles the client like `handle_chat_connection` does a text protocol client, except that each
+/// text message it sends is split into lines, and each line sent to it is a text message. Pings are
+/// answered as they arrive. The client is not registered with the broadcaster if its handshake is
+/// refused, or if the TLS handshake fails when `tls` is given. The idle timeout in `timeouts`
+/// applies to each message, and the line timeout does not apply at all. The lines of each message
+/// are limited, cleaned up and held to the message rate as `input` says.
+///
+/// # Errors
+///
+/// Returns an error, having closed the connection, if it cannot be set up, if the TLS or WebSocket
+/// handshake fails other than by being refused, or if the broadcaster has stopped.
+pub async fn handle_websocket_connection(
+    stream: TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let result = serve_websocket(stream.clone(), broker, outbound, tls, timeouts, input).await;
+    close_if_failed(&stream, result)
+}
+
+async fn serve_echo(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let peer = stream.peer_addr().map_err(ConnectionError::Setup)?;
+    let context = Context::connection(next_client_id(), peer);
+    info!(context, "connection_established", "Connection established");
+
+    let (reader, mut writer) = handshake_within(
+        timeouts.idle,
+        tls::asynchronous::split(stream.clone(), tls.as_ref()),
+    )
+    .await
+    .map_err(ConnectionError::Tls)?;
+    let mut reader = BufReader::new(TimedReader::new(reader, timeouts));
+
+    loop {
+        let response = match input::asynchronous::read_line(&mut reader, input).await {
+            Ok(None) => {
+                info!(context, "end_of_data", "End of data; closing connection");
+                return Ok(());
+            }
+            Ok(Some(Line::Accepted(mut line))) => {
+                log_line(context, &line);
+                if input.strip_controls {
+                    line = input::strip_controls(&line);
+                }
+                codec::echo_response(&line)
+            }
+            Ok(Some(Line::Rejected(rejection))) => {
+                debug!(context, "line_ignored", "Ignored {rejection}");
+                format!("{}\n", codec::ignored_line(&rejection))
+            }
+            Err(e) => match codec::disconnect_notice(&e) {
+                Some(notice) => {
+                    warn!(context, "disconnecting", "Disconnecting client: {e}");
+                    let warning = format!("{notice}\n");
+                    // Fails harmlessly if the client has already disconnected.
+                    let _ =
+                        write_within(timeouts.write, writer.write_all(warning.as_bytes())).await;
+                    break;
+                }
+                None => return Err(ConnectionError::Read(e)),
+            },
+        };
+
+        write_within(timeouts.write, writer.write_all(response.as_bytes()))
+            .await
+            .map_err(ConnectionError::Write)?;
+    }
+
+    // The server is closing the connection, so any TLS session is ended first. The connection is
+    // then shut down rather than just dropped, as the server may hold another handle on it. These
+    // fail harmlessly if the client has already disconnected.
+    let _ = futures_lite::AsyncWriteExt::close(&mut writer).await;
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+async fn serve_chat(
+    stream: TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    protocol: Protocol,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let peer = stream.peer_addr().map_err(ConnectionError::Setup)?;
+    let id = next_client_id();
+    let context = Context::connection(id, peer);
+    info!(context, "connection_established", "Connection established");
+
+    let (reader, writer) = handshake_within(
+        timeouts.idle,
+        tls::asynchronous::split(stream.clone(), tls.as_ref()),
+    )
+    .await
+    .map_err(ConnectionError::Tls)?;
+
+    let queue = OutboundQueue::new(outbound.capacity);
+    let writer = task::spawn(write_outbound(
+        id,
+        queue.clone(),
+        stream.clone(),
+        Framing::Lines(writer),
+        timeouts.write,
+        broker.clone(),
+    ));
+
+    let client = ClientHandle {
+        queue: queue.clone(),
+        stream,
+        protocol,
+        strip_controls: input.strip_controls,
+    };
+    let served = async {
+        send(&broker, Event::Connect { id, client }).await?;
+        debug!(context, "registered", "Client registration complete");
+
+        let reader = BufReader::new(TimedReader::new(reader, timeouts));
+        let reason = forward_lines(reader, id, context, &broker, input).await?;
+        send(&broker, Event::Disconnect { id, reason }).await
+    };
+    if let Err(e) = served.await {
+        // Without a broadcaster, nothing else will close the queue, which tells the writer to stop.
+        queue.close();
+        return Err(e);
+    }
+
+    writer.await;
+    Ok(())
+}
+
+async fn serve_websocket(
+    stream: TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    tls: Option<TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let peer = stream.peer_addr().map_err(ConnectionError::Setup)?;
+    let id = next_client_id();
+    let context = Context::connection(id, peer);
+    info!(
+        context,
+        "connection_established", "WebSocket connection established"
+    );
+
+    let (reader, mut handshake_writer) = handshake_within(
+        timeouts.idle,
+        tls::asynchronous::split(stream.clone(), tls.as_ref()),
+    )
+    .await
+    .map_err(ConnectionError::Tls)?;
+    let reader = TimedReader::without_lines(reader, timeouts);
+    let mut reader = BufReader::new(Box::new(reader) as tls::asynchronous::Reader);
+    let request = read_handshake(&mut reader, input)
+        .await
+        .map_err(ConnectionError::Read)?;
+    let response = match parse_handshake(&request) {
+        Ok(key) => handshake_response(&key),
+        Err(e) => {
+            warn!(
+                context,
+                "handshake_refused", "Refusing WebSocket handshake: {e}"
+            );
+            // These fail harmlessly if the client has already disconnected or stopped reading.
+            let rejection = handshake_rejection(&e);
+            let _ = write_within(
+                timeouts.write,
+                handshake_writer.write_all(rejection.as_bytes()),
+            )
+            .await;
+            let _ = write_within(
+                timeouts.write,
+                futures_lite::AsyncWriteExt::close(&mut handshake_writer),
+            )
+            .await;
+            let _ = stream.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+    };
+    write_within(
+        timeouts.write,
+        handshake_writer.write_all(response.as_bytes()),
+    )
+    .await
+    .map_err(ConnectionError::Write)?;
+
+    let queue = OutboundQueue::new(outbound.capacity);
+    let frames = FrameWriter::new(handshake_writer);
+    let writer = task::spawn(write_outbound(
+        id,
+        queue.clone(),
+        stream.clone(),
+        Framing::WebSocket(frames.clone()),
+        timeouts.write,
+        broker.clone(),
+    ));
+
+    let client = ClientHandle {
+        queue: queue.clone(),
+        stream,
+        protocol: Protocol::WebSocket,
+        strip_controls: input.strip_controls,
+    };
+    let served = async {
+        send(&broker, Event::Connect { id, client }).await?;
+        debug!(context, "registered", "Client registration complete");
+
+        let reason = forward_messages(reader, &frames, id, context, &broker, input).await?;
+        send(&broker, Event::Disconnect { id, reason }).await
+    };
+    if let Err(e) = served.await {
+        // Without a broadcaster, nothing else will close the queue, which tells the writer to stop.
+        queue.close();
+        return Err(e);
+    }
+
+    writer.await;
+    Ok(())
+}
+
+/// Sends each line read from `reader`, the input of client `id`, to the broadcaster through
+/// `broker`, until the client disconnects, reading fails or the client is disconnected for
+/// flooding. Returns why the client left. Records are logged in `context`.
+async fn forward_lines(
+    mut reader: impl BufRead + Unpin,
+    id: ClientId,
+    context: Context,
+    broker: &Sender<Event>,
+    input: InputConfig,
+) -> Result<LeaveReason, ConnectionError> {
+    let mut flood = FloodGuard::new(input.flood);
+
+    loop {
+        match input::asynchronous::read_line(&mut reader, input).await {
+            Ok(None) => {
+                info!(context, "end_of_data", "End of data; closing connection");
+                return Ok(LeaveReason::Quit);
+            }
+            Ok(Some(Line::Accepted(line))) => {
+                log_line(context, &line);
+                if let Some(reason) = send_input(broker, id, context, line, &mut flood).await? {
+                    return Ok(reason);
+                }
+            }
+            Ok(Some(Line::Rejected(rejection))) => {
+                debug!(context, "line_ignored", "Ignored {rejection}");
+                tell_of_rejection(broker, id, rejection).await?;
+            }
+            Err(e) => {
+                warn!(
+                    context,
+                    "read_failed", "Error while reading from client: {e}"
+                );
+                warn_of_disconnect(broker, id, &e).await?;
+                return Ok(LeaveReason::from_io_error(&e));
+            }
+        }
+    }
+}
+
+/// Sends the lines of each WebSocket message read from `reader`, the input of client `id`, to the
+/// broadcaster through `broker`, answering pings through `frames`, until the client disconnects,
+/// reading fails or the client is disconnected for flooding. Returns why the client left. Records
+/// are logged in `context`.
+async fn forward_messages(
+    mut reader: BufReader<tls::asynchronous::Reader>,
+    frames: &FrameWriter,
+    id: ClientId,
+    context: Context,
+    broker: &Sender<Event>,
+    input: InputConfig,
+) -> Result<LeaveReason, ConnectionError> {
+    let mut flood = FloodGuard::new(input.flood);
+
+    loop {
+        match read_message(&mut reader, frames).await {
+            Ok(Some(message)) => {
+                debug!(
+                    context,
+                    "message_received",
+                    "[{} chars] {message}",
+                    message.len()
+                );
+
+                if let Some(reason) =
+                    send_lines(broker, id, context, &message, input, &mut flood).await?
+                {
+                    return Ok(reason);
+                }
+            }
+            Ok(None) => {
+                info!(context, "websocket_closed", "WebSocket closed by client");
+                return Ok(LeaveReason::Quit);
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!(context, "end_of_data", "End of data; closing connection");
+                return Ok(LeaveReason::Quit);
+            }
+            Err(e) => {
+                warn!(
+                    context,
+                    "read_failed", "Error while reading from client: {e}"
+                );
+                warn_of_disconnect(broker, id, &e).await?;
+                return Ok(LeaveReason::from_io_error(&e));
+            }
+        }
+    }
+}
+
+/// Sends each line of `message`, a WebSocket message from client `id`, to the broadcaster through
+/// `broker`, limited and cleaned up as `input` says and held to the message rate by `flood`. If the
+/// client is to be disconnected for one of the lines, it is warned, the lines after it are not
+/// sent, and the reason it leaves is returned. Records are logged in `context`.
+async fn send_lines(
+    broker: &Sender<Event>,
+    id: ClientId,
+    context: Context,
+    message: &str,
+    input: InputConfig,
+    flood: &mut FloodGuard,
+) -> Result<Option<LeaveReason>, ConnectionError> {
+    for line in message.lines() {
+        match input::accept_line(&format!("{line}\n"), input) {
+            Ok(Line::Accepted(line)) => {
+                if let Some(reason) = send_input(broker, id, context, line, flood).await? {
+                    return Ok(Some(reason));
+                }
+            }
+            Ok(Line::Rejected(rejection)) => {
+                debug!(context, "line_ignored", "Ignored {rejection}");
+                tell_of_rejection(broker, id, rejection).await?;
+            }
+            Err(e) => {
+                warn!(context, "disconnecting", "Disconnecting client: {e}");
+                warn_of_disconnect(broker, id, &e).await?;
+                return Ok(Some(LeaveReason::from_io_error(&e)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Sends `line`, the input of client `id`, to the broadcaster through `broker` if `flood` allows
+/// it. Otherwise, asks the broadcaster to tell the client why it was not sent, if the client is to
+/// be told. Returns why the client leaves if it is to be disconnected for flooding. Records are
+/// logged in `context`.
+async fn send_input(
+    broker: &Sender<Event>,
+    id: ClientId,
+    context: Context,
+    line: String,
+    flood: &mut FloodGuard,
+) -> Result<Option<LeaveReason>, ConnectionError> {
+    let verdict = flood.check();
+    if verdict == Verdict::Allow {
+        send(broker, Event::Input { id, line }).await?;
+        return Ok(None);
+    }
+
+    // A muted client's lines are dropped until the mute ends, which is not worth a warning each.
+    match verdict {
+        Verdict::Muted => debug!(context, "flooding", "Dropped line from muted client"),
+        _ => warn!(
+            context,
+            "flooding", "Client is sending messages too fast: {verdict}"
+        ),
+    }
+    if let Some(notice) = codec::flood_notice(&verdict) {
+        let message = Message::system(notice);
+        send(broker, Event::Notify { id, message }).await?;
+    }
+    Ok((verdict == Verdict::Kick).then_some(LeaveReason::Flooding))
+}
+
+/// Asks the broadcaster through `broker` to tell client `id` that a line it sent was ignored
+/// because of `rejection`.
+async fn tell_of_rejection(
+    broker: &Sender<Event>,
+    id: ClientId,
+    rejection: Rejection,
+) -> Result<(), ConnectionError> {
+    let message = Message::error(ErrorCode::Invalid, codec::ignored_line(&rejection));
+    send(broker, Event::Notify { id, message }).await
+}
+
+/// Asks the broadcaster through `broker` to warn client `id` that it is being disconnected, if the
+/// read error `e` means it exceeded a timeout or sent a line that is not accepted.
+async fn warn_of_disconnect(
+    broker: &Sender<Event>,
+    id: ClientId,
+    e: &io::Error,
+) -> Result<(), ConnectionError> {
+    match codec::disconnect_notice(e) {
+        Some(notice) => {
+            let message = Message::system(notice);
+            send(broker, Event::Notify { id, message }).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Sends `event` to the broadcaster through `broker`, failing if the broadcaster has stopped.
+async fn send(broker: &Sender<Event>, event: Event) -> Result<(), ConnectionError> {
+    broker
+        .send(event)
+        .await
+        .map_err(|_| ConnectionError::BrokerStopped)
+}
+
+/// Shuts down `stream` if `result` is an error, as the server may hold another handle on the
+/// connection that would keep it open, then returns `result`.
+fn close_if_failed(
+    stream: &TcpStream,
+    result: Result<(), ConnectionError>,
+) -> Result<(), ConnectionError> {
+    if result.is_err() {
+        // Fails harmlessly if the client has already disconnected.
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    result
+}
@@ -0,0 +1,273 @@
+//! Connection handlers built on the blocking I/O of `std::net`. Each handler occupies the calling
+//! thread until its client disconnects.
+
+use super::{log_line, ConnectionError};
+use crate::broker::blocking::{write_outbound, ClientHandle, Event, OutboundQueue};
+use crate::broker::{next_client_id, ClientId, ErrorCode, LeaveReason, Message};
+use crate::codec::{self, Protocol};
+use crate::config::{InputConfig, OutboundConfig, TimeoutConfig};
+use crate::flood::{FloodGuard, Verdict};
+use crate::input::{self, Line};
+use crate::log::Context;
+use crate::timeout::blocking::TimedReader;
+use crate::tls::{self, TlsAcceptor};
+use crate::{debug, info, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+/// Receives newline-delimited input from `stream`, and sends the same data back on the same stream.
+/// The connection is encrypted with TLS if `tls` is given. Each line is limited and cleaned up as
+/// `input` says, and the client is told of any line that is ignored. A client that exceeds one of
+/// `timeouts`, or sends a line for which `input` says to disconnect it, is told so and
+/// disconnected.
+///
+/// # Errors
+///
+/// Returns an error, having closed the connection, if it cannot be set up, or if reading from or
+/// writing to the client fails other than by the client disconnecting.
+pub fn handle_echo_connection(
+    stream: &TcpStream,
+    tls: Option<&TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let result = serve_echo(stream, tls, timeouts, input);
+    close_if_failed(stream, result)
+}
+
+/// Registers the client with the broadcaster through `broker`, then continuously receives
+/// newline-delimited input from the `stream` passed, and sends each line to the broadcaster, which
+/// decodes it according to `protocol` and decides what to do with it. This process is repeated
+/// until `stream` is closed or an error occurs, after which the broadcaster is told why the client
+/// left. The connection is encrypted with TLS if `tls` is given. Each line is limited and cleaned up
+/// as `input` says, and the client is told of any line that is ignored. Lines sent faster than the
+/// message rate in `input` allows are held back, and the client warned, muted or disconnected. A
+/// client that exceeds one of `timeouts`, or sends a line for which `input` says to disconnect it,
+/// is disconnected, after being warned unless it could not be written to.
+///
+/// Everything sent to the client, starting with the broadcaster's prompt for a display name, goes
+/// through an outbound queue of the size given by `outbound`, which a separate writer thread drains
+/// onto `stream`. Once the client disconnects, this waits for the writer to finish writing anything
+/// still queued.
+///
+/// # Errors
+///
+/// Returns an error, having closed the connection, if it cannot be set up or the broadcaster has
+/// stopped.
+pub fn handle_chat_connection(
+    stream: TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    protocol: Protocol,
+    tls: Option<&TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let result = serve_chat(&stream, broker, outbound, protocol, tls, timeouts, input);
+    close_if_failed(&stream, result)
+}
+
+fn serve_echo(
+    stream: &TcpStream,
+    tls: Option<&TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let peer = stream.peer_addr().map_err(ConnectionError::Setup)?;
+    let context = Context::connection(next_client_id(), peer);
+    info!(context, "connection_established", "Connection established");
+
+    stream
+        .set_write_timeout(timeouts.write)
+        .map_err(ConnectionError::Setup)?;
+    let (reader, mut writer) = tls::blocking::split(stream, tls).map_err(ConnectionError::Setup)?;
+    let reader = TimedReader::new(reader, stream, timeouts).map_err(ConnectionError::Setup)?;
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let response = match input::blocking::read_line(&mut reader, input) {
+            Ok(None) => {
+                info!(context, "end_of_data", "End of data; closing connection");
+                return Ok(());
+            }
+            Ok(Some(Line::Accepted(mut line))) => {
+                log_line(context, &line);
+                if input.strip_controls {
+                    line = input::strip_controls(&line);
+                }
+                codec::echo_response(&line)
+            }
+            Ok(Some(Line::Rejected(rejection))) => {
+                debug!(context, "line_ignored", "Ignored {rejection}");
+                format!("{}\n", codec::ignored_line(&rejection))
+            }
+            Err(e) => match codec::disconnect_notice(&e) {
+                Some(notice) => {
+                    warn!(context, "disconnecting", "Disconnecting client: {e}");
+                    // Fails harmlessly if the client has already disconnected.
+                    let _ = writer.write_all(format!("{notice}\n").as_bytes());
+                    break;
+                }
+                None => return Err(ConnectionError::Read(e)),
+            },
+        };
+
+        writer
+            .write_all(response.as_bytes())
+            .map_err(ConnectionError::Write)?;
+    }
+
+    // The server is closing the connection, so any TLS session is ended first. The connection is
+    // then shut down rather than just dropped, as the server may hold another handle on it.
+    drop(writer);
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+fn serve_chat(
+    stream: &TcpStream,
+    broker: Sender<Event>,
+    outbound: OutboundConfig,
+    protocol: Protocol,
+    tls: Option<&TlsAcceptor>,
+    timeouts: TimeoutConfig,
+    input: InputConfig,
+) -> Result<(), ConnectionError> {
+    let peer = stream.peer_addr().map_err(ConnectionError::Setup)?;
+    let id = next_client_id();
+    let context = Context::connection(id, peer);
+    info!(context, "connection_established", "Connection established");
+
+    let queue = OutboundQueue::new(outbound.capacity);
+
+    stream
+        .set_write_timeout(timeouts.write)
+        .map_err(ConnectionError::Setup)?;
+    let (reader, writer) = tls::blocking::split(stream, tls).map_err(ConnectionError::Setup)?;
+    let reader = TimedReader::new(reader, stream, timeouts).map_err(ConnectionError::Setup)?;
+    let writer_stream = stream.try_clone().map_err(ConnectionError::Setup)?;
+    let client = ClientHandle {
+        queue: queue.clone(),
+        stream: stream.try_clone().map_err(ConnectionError::Setup)?,
+        protocol,
+        strip_controls: input.strip_controls,
+    };
+
+    let writer_queue = queue.clone();
+    let writer_broker = broker.clone();
+    let writer = thread::spawn(move || {
+        write_outbound(id, writer_queue, writer, writer_stream, writer_broker)
+    });
+
+    let served = send(&broker, Event::Connect { id, client }).and_then(|()| {
+        debug!(context, "registered", "Client registration complete");
+        let reason = forward_lines(BufReader::new(reader), id, context, &broker, input)?;
+        send(&broker, Event::Disconnect { id, reason })
+    });
+    if let Err(e) = served {
+        // Without a broadcaster, nothing else will close the queue, which tells the writer to stop.
+        queue.close();
+        return Err(e);
+    }
+
+    let _ = writer.join();
+    Ok(())
+}
+
+/// Sends each line read from `reader`, the input of client `id`, to the broadcaster through
+/// `broker`, until the client disconnects, reading fails or the client is disconnected for
+/// flooding. Returns why the client left. Records are logged in `context`.
+fn forward_lines(
+    mut reader: impl BufRead,
+    id: ClientId,
+    context: Context,
+    broker: &Sender<Event>,
+    input: InputConfig,
+) -> Result<LeaveReason, ConnectionError> {
+    let mut flood = FloodGuard::new(input.flood);
+
+    loop {
+        match input::blocking::read_line(&mut reader, input) {
+            Ok(None) => {
+                info!(context, "end_of_data", "End of data; closing connection");
+                return Ok(LeaveReason::Quit);
+            }
+            Ok(Some(Line::Accepted(line))) => {
+                log_line(context, &line);
+                if let Some(reason) = send_input(broker, id, context, line, &mut flood)? {
+                    return Ok(reason);
+                }
+            }
+            Ok(Some(Line::Rejected(rejection))) => {
+                debug!(context, "line_ignored", "Ignored {rejection}");
+                let message = Message::error(ErrorCode::Invalid, codec::ignored_line(&rejection));
+                send(broker, Event::Notify { id, message })?;
+            }
+            Err(e) => {
+                warn!(
+                    context,
+                    "read_failed", "Error while reading from client: {e}"
+                );
+                if let Some(notice) = codec::disconnect_notice(&e) {
+                    let message = Message::system(notice);
+                    send(broker, Event::Notify { id, message })?;
+                }
+                return Ok(LeaveReason::from_io_error(&e));
+            }
+        }
+    }
+}
+
+/// Sends `line`, the input of client `id`, to the broadcaster through `broker` if `flood` allows
+/// it. Otherwise, asks the broadcaster to tell the client why it was not sent, if the client is to
+/// be told. Returns why the client leaves if it is to be disconnected for flooding. Records are
+/// logged in `context`.
+fn send_input(
+    broker: &Sender<Event>,
+    id: ClientId,
+    context: Context,
+    line: String,
+    flood: &mut FloodGuard,
+) -> Result<Option<LeaveReason>, ConnectionError> {
+    let verdict = flood.check();
+    if verdict == Verdict::Allow {
+        send(broker, Event::Input { id, line })?;
+        return Ok(None);
+    }
+
+    // A muted client's lines are dropped until the mute ends, which is not worth a warning each.
+    match verdict {
+        Verdict::Muted => debug!(context, "flooding", "Dropped line from muted client"),
+        _ => warn!(
+            context,
+            "flooding", "Client is sending messages too fast: {verdict}"
+        ),
+    }
+    if let Some(notice) = codec::flood_notice(&verdict) {
+        let message = Message::system(notice);
+        send(broker, Event::Notify { id, message })?;
+    }
+    Ok((verdict == Verdict::Kick).then_some(LeaveReason::Flooding))
+}
+
+/// Sends `event` to the broadcaster through `broker`, failing if the broadcaster has stopped.
+fn send(broker: &Sender<Event>, event: Event) -> Result<(), ConnectionError> {
+    broker
+        .send(event)
+        .map_err(|_| ConnectionError::BrokerStopped)
+}
+
+/// Shuts down `stream` if `result` is an error, as the server may hold another handle on the
+/// connection that would keep it open, then returns `result`.
+fn close_if_failed(
+    stream: &TcpStream,
+    result: Result<(), ConnectionError>,
+) -> Result<(), ConnectionError> {
+    if result.is_err() {
+        // Fails harmlessly if the client has already disconnected.
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    result
+}
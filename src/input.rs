@@ -0,0 +1,198 @@
+//! The limits and clean-up applied to every line a client sends, so no client can make a server
+//! allocate without bound, and no client can send control characters or terminal escape sequences
+//! to the terminals of others.
+//!
+//! Lines are read with the `read_line` of the `blocking` or `asynchronous` submodule, which never
+//! holds more of a line than the configured maximum length. What happens to a longer line, or to
+//! one that is not valid UTF-8, is decided by the `InputConfig`. Control characters are removed by
+//! `strip_controls`, which the echo servers apply to each line and the chat servers to the text of
+//! each decoded `Input`, so protocols such as IRC can still use control characters of their own.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::config::{InputConfig, LongLinePolicy, Utf8Policy};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// A line read from a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Line {
+    /// A line to handle, including its trailing newline unless the client disconnected part way
+    /// through it.
+    Accepted(String),
+    /// A line that is discarded, for the reason given.
+    Rejected(Rejection),
+}
+
+/// Why a line is not accepted from a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The line is longer than this many bytes.
+    TooLong(usize),
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+}
+
+impl Rejection {
+    /// Returns the rejection carried by `e`, if it is the error returned when a line is rejected by
+    /// disconnecting the client.
+    pub fn from_error(e: &io::Error) -> Option<Self> {
+        e.get_ref()?.downcast_ref().copied()
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(max) => write!(f, "line longer than {max} bytes"),
+            Self::InvalidUtf8 => f.write_str("line that is not valid UTF-8"),
+        }
+    }
+}
+
+impl Error for Rejection {}
+
+impl From<Rejection> for io::Error {
+    fn from(rejection: Rejection) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, rejection)
+    }
+}
+
+/// A line as it is received, holding no more than the maximum line length.
+#[derive(Debug)]
+struct LineBuffer {
+    config: InputConfig,
+    bytes: Vec<u8>,
+    /// Whether bytes beyond the maximum length have been discarded.
+    overflowed: bool,
+    /// Whether the newline ending the line has been received.
+    ended: bool,
+}
+
+impl LineBuffer {
+    fn new(config: InputConfig) -> Self {
+        Self {
+            config,
+            bytes: Vec::new(),
+            overflowed: false,
+            ended: false,
+        }
+    }
+
+    /// Adds bytes from the start of `data` to the line, up to and including the newline that ends
+    /// it, and returns how many were used. Fails with a `Rejection` if the line is too long and the
+    /// client is to be disconnected for it.
+    fn push(&mut self, data: &[u8]) -> io::Result<usize> {
+        let (used, text) = match data.iter().position(|&b| b == b'\n') {
+            Some(newline) => {
+                self.ended = true;
+                (newline + 1, &data[..newline])
+            }
+            None => (data.len(), data),
+        };
+
+        let max = self.config.max_line_len;
+        let room = match max {
+            0 => text.len(),
+            max => max.saturating_sub(self.bytes.len()),
+        };
+        if text.len() > room {
+            if self.config.long_lines == LongLinePolicy::Disconnect {
+                return Err(Rejection::TooLong(max).into());
+            }
+            self.overflowed = true;
+        }
+        self.bytes.extend_from_slice(&text[..text.len().min(room)]);
+
+        Ok(used)
+    }
+
+    /// Returns the line received, decoded according to the UTF-8 policy, or why it is rejected.
+    fn finish(mut self) -> Line {
+        if self.overflowed {
+            if self.config.long_lines == LongLinePolicy::Reject {
+                return Line::Rejected(Rejection::TooLong(self.config.max_line_len));
+            }
+            // Truncating may have cut a character in two, which should not make the line invalid.
+            if let Err(e) = str::from_utf8(&self.bytes) {
+                if e.error_len().is_none() {
+                    self.bytes.truncate(e.valid_up_to());
+                }
+            }
+        }
+
+        let mut line = match self.config.utf8 {
+            Utf8Policy::Lossy => String::from_utf8_lossy(&self.bytes).into_owned(),
+            Utf8Policy::Strict => match String::from_utf8(self.bytes) {
+                Ok(line) => line,
+                Err(_) => return Line::Rejected(Rejection::InvalidUtf8),
+            },
+        };
+        if self.ended {
+            line.push('\n');
+        }
+        Line::Accepted(line)
+    }
+}
+
+/// Returns `line`, which is a whole line of text including its newline, as it would be returned by
+/// `read_line`. This applies the same limits to lines that arrive some other way, such as those of
+/// a WebSocket message.
+pub fn accept_line(line: &str, config: InputConfig) -> io::Result<Line> {
+    let mut buffer = LineBuffer::new(config);
+    buffer.push(line.as_bytes())?;
+    Ok(buffer.finish())
+}
+
+/// Returns `text` without its control characters and terminal escape sequences, such as those that
+/// move the cursor or clear the screen. Tabs and newlines are kept.
+pub fn strip_controls(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let sequence = match c {
+            '\t' | '\n' => {
+                stripped.push(c);
+                continue;
+            }
+            '\x1b' => chars.next_if(|c| ('@'..='_').contains(c)),
+            // The 8-bit equivalents of `ESC [`, `ESC ]` and so on.
+            '\u{80}'..='\u{9f}' => char::from_u32(u32::from(c) - 0x40),
+            c if c.is_control() => continue,
+            c => {
+                stripped.push(c);
+                continue;
+            }
+        };
+
+        match sequence {
+            // A control sequence, e.g. `ESC [ 2 J`, ends with a byte from `@` to `~`.
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // These strings, e.g. setting a window title with `ESC ] 0 ; title BEL`, end with BEL
+            // or a string terminator, `ESC \`.
+            Some(']' | 'P' | 'X' | '^' | '_') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07'
+                        || c == '\u{9c}'
+                        || (c == '\x1b' && chars.next_if_eq(&'\\').is_some())
+                    {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    stripped
+}
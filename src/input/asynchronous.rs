@@ -0,0 +1,30 @@
+//! Reading lines from clients with the async-std crate.
+
+use super::{Line, LineBuffer};
+use crate::config::InputConfig;
+use async_std::io::{self, BufRead};
+use futures_lite::AsyncBufReadExt;
+use std::pin::Pin;
+
+/// Reads the next line from `reader`, holding no more of it than `config` allows. Returns `None`
+/// at the end of the input. Fails with a `Rejection` if the client is to be disconnected for
+/// sending a line that is too long.
+pub async fn read_line<R>(reader: &mut R, config: InputConfig) -> io::Result<Option<Line>>
+where
+    R: BufRead + Unpin,
+{
+    let mut line = LineBuffer::new(config);
+    let mut received = false;
+
+    while !line.ended {
+        let data = reader.fill_buf().await?;
+        if data.is_empty() {
+            break;
+        }
+        received = true;
+        let used = line.push(data)?;
+        Pin::new(&mut *reader).consume(used);
+    }
+
+    Ok(received.then(|| line.finish()))
+}
@@ -0,0 +1,25 @@
+//! Reading lines from clients with the blocking I/O of `std::io`.
+
+use super::{Line, LineBuffer};
+use crate::config::InputConfig;
+use std::io::{self, BufRead};
+
+/// Reads the next line from `reader`, holding no more of it than `config` allows. Returns `None`
+/// at the end of the input. Fails with a `Rejection` if the client is to be disconnected for
+/// sending a line that is too long.
+pub fn read_line(reader: &mut impl BufRead, config: InputConfig) -> io::Result<Option<Line>> {
+    let mut line = LineBuffer::new(config);
+    let mut received = false;
+
+    while !line.ended {
+        let data = reader.fill_buf()?;
+        if data.is_empty() {
+            break;
+        }
+        received = true;
+        let used = line.push(data)?;
+        reader.consume(used);
+    }
+
+    Ok(received.then(|| line.finish()))
+}
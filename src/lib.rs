@@ -0,0 +1,24 @@
+//! Building blocks shared by the echo and chat servers in `src/bin/`. Each binary is a thin wrapper
+//! that binds a listener and hands accepted connections to the handlers defined here, so the same
+//! servers can also be embedded in other programs or driven from integration tests.
+//!
+//! Most modules come in a `blocking` flavour built on `std::net` and `std::thread`, and an
+//! `asynchronous` flavour built on the async-std crate, mirroring the two concurrency mechanisms this
+//! repository sets out to compare.
+
+pub mod broker;
+pub mod chat;
+pub mod codec;
+pub mod config;
+pub mod flood;
+pub mod governor;
+pub mod handler;
+pub mod input;
+pub mod listener;
+pub mod log;
+pub mod pool;
+pub mod rate;
+pub mod shutdown;
+pub mod timeout;
+pub mod tls;
+pub mod websocket;
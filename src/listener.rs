@@ -0,0 +1,224 @@
+//! Listener setup shared by every server: the default address to listen on, and binding a
+//! `TcpListener` in either the blocking or async-std flavour.
+//!
+//! The chat servers can listen on a port per protocol. Each listener then accepts connections on a
+//! thread or task of its own and passes them to the server's accept loop through a channel, so one
+//! loop serves every port.
+//!
+//! Accepting a connection can fail without anything being wrong with the listener, most often
+//! because the server has run out of file descriptors (`EMFILE`). Every accept loop keeps going
+//! after such a failure, pausing for as long as an `AcceptBackoff` says first.
+
+use crate::codec::Protocol;
+use crate::config::{IpFamily, ListenConfig};
+use crate::log::Context;
+use crate::{info, warn};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::Duration;
+
+pub const LOCAL_ADDR_IPV6: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1); // Represents [::1]
+pub const LOCAL_PORT: u16 = 8080;
+
+/// How long an accept loop pauses after the first of a run of failures.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The longest an accept loop pauses after a failure, however many precede it.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long an accept loop pauses after accepting a connection fails. A failure such as running
+/// out of file descriptors usually lasts until other connections close, so retrying at once would
+/// only spin. The pause doubles with each failure in a row, and is reset once a connection is
+/// accepted. A failure that only affects the connection being accepted, such as the client
+/// resetting it first, causes no pause.
+#[derive(Debug, Default)]
+pub struct AcceptBackoff {
+    /// The pause after the next failure, or `None` if the last accept succeeded.
+    next: Option<Duration>,
+}
+
+impl AcceptBackoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a connection was accepted, ending any run of failures.
+    pub fn accepted(&mut self) {
+        self.next = None;
+    }
+
+    /// Records that accepting a connection failed with `e`, logs why, and returns how long to
+    /// pause before accepting again.
+    pub fn failed(&mut self, e: &io::Error) -> Duration {
+        if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+        ) {
+            warn!(
+                Context::SERVER,
+                "accept_failed", "Failed to accept a connection: {e}"
+            );
+            return Duration::ZERO;
+        }
+
+        let pause = self.next.unwrap_or(MIN_ACCEPT_BACKOFF);
+        self.next = Some((pause * 2).min(MAX_ACCEPT_BACKOFF));
+        warn!(
+            Context::SERVER,
+            "accept_failed",
+            "Failed to accept a connection: {e}; retrying in {}ms",
+            pause.as_millis()
+        );
+        pause
+    }
+}
+
+/// Binds a blocking `TcpListener` as described by `config` and logs the address it is listening
+/// on, which includes the port the OS picked if `config.port` is 0.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound, e.g., because another server is already using the port.
+pub fn bind(config: &ListenConfig) -> TcpListener {
+    let listener = bind_addr(config.socket_addr(), config.family);
+    info!(
+        Context::SERVER,
+        "listening",
+        "Listening on {}",
+        local_addr(&listener)
+    );
+    listener
+}
+
+/// Binds a blocking `TcpListener` on `port` for chat clients speaking `protocol`, at the address
+/// given by `config`, and logs the address it is listening on.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound.
+pub fn bind_protocol(config: &ListenConfig, port: u16, protocol: Protocol) -> TcpListener {
+    let listener = bind_addr(config.socket_addr_with_port(port), config.family);
+    info!(
+        Context::SERVER,
+        "listening",
+        "Listening for {protocol} clients on {}",
+        local_addr(&listener)
+    );
+    listener
+}
+
+/// Binds an async-std `TcpListener` as described by `config` and logs the address it is
+/// listening on, which includes the port the OS picked if `config.port` is 0.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound, e.g., because another server is already using the port.
+pub async fn bind_async(config: &ListenConfig) -> async_std::net::TcpListener {
+    async_std::net::TcpListener::from(bind(config))
+}
+
+/// Binds an async-std `TcpListener` on `port` for chat clients speaking `protocol`, as
+/// `bind_protocol` does.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound.
+pub async fn bind_protocol_async(
+    config: &ListenConfig,
+    port: u16,
+    protocol: Protocol,
+) -> async_std::net::TcpListener {
+    async_std::net::TcpListener::from(bind_protocol(config, port, protocol))
+}
+
+/// Accepts connections on `listener` in a new thread, sending each to `accepted` along with the
+/// `protocol` its client speaks, until `accepted` is disconnected. Failures to accept are logged
+/// and retried after a pause.
+pub fn accept_into(
+    listener: TcpListener,
+    protocol: Protocol,
+    accepted: SyncSender<(TcpStream, Protocol)>,
+) {
+    thread::spawn(move || {
+        let mut backoff = AcceptBackoff::new();
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    backoff.accepted();
+                    if accepted.send((stream, protocol)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => thread::sleep(backoff.failed(&e)),
+            }
+        }
+    });
+}
+
+/// Accepts connections on `listener` in a new task, sending each to `accepted` along with the
+/// `protocol` its client speaks, until `accepted` is closed. Failures to accept are logged and
+/// retried after a pause.
+pub fn accept_into_async(
+    listener: async_std::net::TcpListener,
+    protocol: Protocol,
+    accepted: async_std::channel::Sender<(async_std::net::TcpStream, Protocol)>,
+) {
+    async_std::task::spawn(async move {
+        let mut backoff = AcceptBackoff::new();
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    backoff.accepted();
+                    if accepted.send((stream, protocol)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => async_std::task::sleep(backoff.failed(&e)).await,
+            }
+        }
+    });
+}
+
+/// Binds a blocking `TcpListener` to `socket_addr`.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound.
+fn bind_addr(socket_addr: SocketAddr, family: IpFamily) -> TcpListener {
+    bind_socket(socket_addr, family)
+        .unwrap_or_else(|e| panic!("Failed to bind to {socket_addr}: {e}"))
+}
+
+/// Returns the address `listener` is bound to.
+fn local_addr(listener: &TcpListener) -> SocketAddr {
+    listener
+        .local_addr()
+        .expect("Failed to query the address of the listener")
+}
+
+/// Creates a socket listening on `socket_addr`. The socket is created with `socket2` rather than
+/// `TcpListener::bind` because the standard library offers no way to choose whether an IPv6 socket
+/// also accepts IPv4 connections.
+fn bind_socket(socket_addr: SocketAddr, family: IpFamily) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(socket_addr), Type::STREAM, None)?;
+
+    if socket_addr.is_ipv6() {
+        socket.set_only_v6(family != IpFamily::DualStack)?;
+    }
+
+    // Matches `TcpListener::bind`, which allows a restarted server to reuse its port immediately.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&socket_addr.into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
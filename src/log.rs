@@ -0,0 +1,210 @@
+//! The servers' diagnostics. Each record has a level, the name of the event it records, a message
+//! for people to read, the time since the server started and, when the record is about a client
+//! connection, the connection's id and peer address.
+//!
+//! Records are written to standard output as text or as JSON lines, as chosen by `--log-format`,
+//! and those less severe than `--log-level` are skipped. Records are made with the `error!`,
+//! `warn!`, `info!`, `debug!` and `trace!` macros, which take the record's `Context` and event name
+//! before the format string of its message, e.g.,
+//! `info!(Context::SERVER, "listening", "Listening on {addr}")`.
+
+use crate::broker::ClientId;
+use crate::config::{LogConfig, LogFormat, LogLevel};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// The connection a record is about, if any.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    pub id: Option<ClientId>,
+    pub peer: Option<SocketAddr>,
+}
+
+impl Context {
+    /// The context of records about the server as a whole.
+    pub const SERVER: Self = Self {
+        id: None,
+        peer: None,
+    };
+
+    /// Returns the context of records about connection `id`, whose client is at `peer`.
+    pub fn connection(id: ClientId, peer: SocketAddr) -> Self {
+        Self {
+            id: Some(id),
+            peer: Some(peer),
+        }
+    }
+
+    /// Returns the context of records about a connection that has not been given an id, whose
+    /// client is at `peer`.
+    pub fn peer(peer: SocketAddr) -> Self {
+        Self {
+            id: None,
+            peer: Some(peer),
+        }
+    }
+
+    /// Returns the context of records about a connection that has just been accepted, whose client
+    /// is at `peer` unless the address could not be queried.
+    pub fn accepted(peer: io::Result<SocketAddr>) -> Self {
+        Self {
+            id: None,
+            peer: peer.ok(),
+        }
+    }
+
+    /// Returns the context of records about chat client `id`, made where its address is not known.
+    pub fn client(id: ClientId) -> Self {
+        Self {
+            id: Some(id),
+            peer: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Logger {
+    config: LogConfig,
+    started: Instant,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Starts logging as `config` says, timing records from now, and records that the server is
+/// starting. Only the first call has any effect. Records made before it are written as text at
+/// the default level, timed from the first of them.
+pub fn init(config: LogConfig) {
+    let started = Instant::now();
+    if LOGGER.set(Logger { config, started }).is_ok() {
+        write(
+            LogLevel::Info,
+            Context::SERVER,
+            "starting",
+            format_args!("Starting at monotonic clock time: {started:?}"),
+        );
+    }
+}
+
+/// Returns whether records at `level` are written.
+pub fn enabled(level: LogLevel) -> bool {
+    level <= logger().config.level
+}
+
+/// Writes a record of `event` at `level`, about the connection in `context`, with `message`, unless
+/// records at `level` are skipped. Call this through the macros, such as `info!`, rather than
+/// directly.
+pub fn write(level: LogLevel, context: Context, event: &str, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let logger = logger();
+    let elapsed_ms = logger.started.elapsed().as_millis();
+    let line = match logger.config.format {
+        LogFormat::Text => {
+            let mut line = format!("{elapsed_ms}ms {:<5} {event}", level.to_string());
+            if let Some(id) = context.id {
+                line += &format!(" conn={id}");
+            }
+            if let Some(peer) = context.peer {
+                line += &format!(" peer={peer}");
+            }
+            format!("{line}: {message}")
+        }
+        LogFormat::Json => {
+            let mut record = json!({
+                "elapsed_ms": elapsed_ms,
+                "level": level.to_string(),
+                "event": event,
+                "message": message.to_string(),
+            });
+            if let Some(id) = context.id {
+                record["conn"] = Value::from(id);
+            }
+            if let Some(peer) = context.peer {
+                record["peer"] = Value::from(peer.to_string());
+            }
+            record.to_string()
+        }
+    };
+
+    // A record that cannot be written, e.g., because standard output has been closed, is lost
+    // rather than stopping the server.
+    let _ = writeln!(io::stdout().lock(), "{line}");
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        config: LogConfig::default(),
+        started: Instant::now(),
+    })
+}
+
+/// Writes a record at `LogLevel::Error`: `error!(context, "event", "message {}", args)`.
+#[macro_export]
+macro_rules! error {
+    ($context:expr, $event:expr, $($message:tt)+) => {
+        $crate::log::write(
+            $crate::config::LogLevel::Error,
+            $context,
+            $event,
+            format_args!($($message)+),
+        )
+    };
+}
+
+/// Writes a record at `LogLevel::Warn`: `warn!(context, "event", "message {}", args)`.
+#[macro_export]
+macro_rules! warn {
+    ($context:expr, $event:expr, $($message:tt)+) => {
+        $crate::log::write(
+            $crate::config::LogLevel::Warn,
+            $context,
+            $event,
+            format_args!($($message)+),
+        )
+    };
+}
+
+/// Writes a record at `LogLevel::Info`: `info!(context, "event", "message {}", args)`.
+#[macro_export]
+macro_rules! info {
+    ($context:expr, $event:expr, $($message:tt)+) => {
+        $crate::log::write(
+            $crate::config::LogLevel::Info,
+            $context,
+            $event,
+            format_args!($($message)+),
+        )
+    };
+}
+
+/// Writes a record at `LogLevel::Debug`: `debug!(context, "event", "message {}", args)`.
+#[macro_export]
+macro_rules! debug {
+    ($context:expr, $event:expr, $($message:tt)+) => {
+        $crate::log::write(
+            $crate::config::LogLevel::Debug,
+            $context,
+            $event,
+            format_args!($($message)+),
+        )
+    };
+}
+
+/// Writes a record at `LogLevel::Trace`: `trace!(context, "event", "message {}", args)`.
+#[macro_export]
+macro_rules! trace {
+    ($context:expr, $event:expr, $($message:tt)+) => {
+        $crate::log::write(
+            $crate::config::LogLevel::Trace,
+            $context,
+            $event,
+            format_args!($($message)+),
+        )
+    };
+}
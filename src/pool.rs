@@ -0,0 +1,177 @@
+//! A fixed-size pool of worker threads for the threaded servers, as an alternative to spawning a
+//! thread per connection. Accepted connections are queued for the workers in a bounded queue, so a
+//! flood of connections cannot exhaust the OS's threads.
+
+use crate::codec;
+use crate::config::{FullPolicy, PoolConfig};
+use crate::log::Context;
+use crate::shutdown;
+use crate::{debug, error, info, warn};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often a full queue is checked for space, and for a shutdown, while waiting to queue a job.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a rejected client is given to accept the notice saying the server is busy.
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The error returned when a job cannot be run because the pool's queue is full, or because no
+/// thread could be spawned to run it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolFull;
+
+/// A fixed number of worker threads that run jobs taken from a bounded queue.
+#[derive(Debug)]
+pub struct ThreadPool {
+    sender: SyncSender<Job>,
+    when_full: FullPolicy,
+}
+
+impl ThreadPool {
+    /// Spawns `config.size` worker threads sharing a queue of `config.queue_depth` jobs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.size` is 0.
+    pub fn new(config: &PoolConfig) -> Self {
+        assert!(config.size > 0, "A thread pool needs at least one worker");
+
+        let (sender, receiver) = sync_channel::<Job>(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for id in 0..config.size {
+            let receiver = receiver.clone();
+            thread::spawn(move || work(id, receiver));
+        }
+
+        info!(
+            Context::SERVER,
+            "pool_started",
+            "Thread pool started with {} workers and a queue of {}",
+            config.size,
+            config.queue_depth
+        );
+
+        Self {
+            sender,
+            when_full: config.when_full,
+        }
+    }
+
+    /// Queues `job` to be run by the next free worker. If the queue is full, this either waits for
+    /// space or returns `PoolFull`, depending on the pool's `FullPolicy`. Waiting stops, and
+    /// `PoolFull` is returned, once `shutdown` is requested, as the accept loop waiting here could
+    /// not otherwise notice.
+    pub fn execute<F>(&self, job: F, shutdown: &shutdown::Shutdown) -> Result<(), PoolFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut job: Job = Box::new(job);
+
+        loop {
+            match self.sender.try_send(job) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(returned))
+                    if self.when_full == FullPolicy::Queue && !shutdown.is_requested() =>
+                {
+                    job = returned;
+                    thread::sleep(QUEUE_POLL_INTERVAL);
+                }
+                Err(_) => return Err(PoolFull),
+            }
+        }
+    }
+}
+
+/// The body of each worker thread, which runs jobs until the pool is dropped. A job that panics
+/// only ends its own connection, not the worker.
+fn work(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is released at the end of this statement, before the job runs.
+        let job = receiver.lock().unwrap().recv();
+
+        match job {
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!(
+                        Context::SERVER,
+                        "job_panicked", "Worker {id} recovered from a panicking job"
+                    );
+                }
+            }
+            Err(_) => {
+                debug!(
+                    Context::SERVER,
+                    "worker_exiting", "Worker {id} exiting as the pool has been dropped"
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Runs each job either in a newly spawned thread or in a `ThreadPool`, as configured.
+#[derive(Debug)]
+pub enum Executor {
+    ThreadPerConnection,
+    Pool(ThreadPool),
+}
+
+impl Executor {
+    /// Creates a `ThreadPool` if `config.size` is non-zero, otherwise spawns a thread per job.
+    pub fn new(config: &PoolConfig) -> Self {
+        if config.size == 0 {
+            Self::ThreadPerConnection
+        } else {
+            Self::Pool(ThreadPool::new(config))
+        }
+    }
+
+    /// Runs `job` in a new thread, or queues it in the pool, giving up on waiting for room in the
+    /// pool once `shutdown` is requested. Returns `PoolFull` if the pool rejected the job, or the OS
+    /// refused to create a thread for it, in which case `job` has been dropped without running.
+    pub fn execute<F>(&self, job: F, shutdown: &shutdown::Shutdown) -> Result<(), PoolFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self {
+            Self::ThreadPerConnection => match thread::Builder::new().spawn(job) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    error!(
+                        Context::SERVER,
+                        "thread_spawn_failed", "Failed to spawn connection thread: {e}"
+                    );
+                    Err(PoolFull)
+                }
+            },
+            Self::Pool(pool) => pool.execute(job, shutdown),
+        }
+    }
+}
+
+/// Closes the connection on `stream` because the server is too busy to handle it, first telling the
+/// client so if `notify` is true. `notify` should be false if connections are encrypted, as the
+/// notice is sent in plaintext.
+pub fn reject(mut stream: TcpStream, notify: bool) {
+    let context = Context::accepted(stream.peer_addr());
+    warn!(
+        context,
+        "connection_rejected", "Rejecting connection as the server is too busy"
+    );
+
+    // Errors are ignored as the connection is being closed anyway.
+    if notify {
+        let _ = stream.set_write_timeout(Some(NOTICE_WRITE_TIMEOUT));
+        let _ = stream.write_all(codec::BUSY_NOTICE.as_bytes());
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
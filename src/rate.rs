@@ -0,0 +1,56 @@
+//! Rate limiting with token buckets, which let something happen in short bursts while holding the
+//! rate at which it happens over time to a limit.
+
+use std::time::Instant;
+
+/// A bucket holding up to `capacity` tokens, refilled continuously at `rate` tokens per second.
+/// Each event takes a token, so up to `capacity` events can happen at once, but no more than `rate`
+/// per second on average.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Returns a full bucket of `capacity` tokens, refilled at `rate` tokens per second.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one. Returns whether there was, i.e., whether the event the token
+    /// is taken for is within the rate.
+    pub fn take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether the bucket is full, i.e., whether nothing has taken a token for long enough
+    /// for all of them to be replaced.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// Adds the tokens due since the bucket was last refilled, up to its capacity.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+    }
+}
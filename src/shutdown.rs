@@ -0,0 +1,110 @@
+//! Graceful shutdown of the threaded and async servers on SIGINT (Ctrl-C) or SIGTERM.
+//!
+//! A `Shutdown` is shared by a server's accept loop and its signal handler. When shutdown is
+//! requested, the accept loop stops accepting connections and uses `Connections` to stop reading
+//! from every client, wait a limited time for the handlers to finish their in-flight writes, send
+//! each client a notice and close its stream.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::log::Context;
+use crate::{error, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A cloneable handle used to request a server shutdown and to check whether one was requested.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    wake_addr: SocketAddr,
+}
+
+impl Shutdown {
+    /// Creates a handle for a server whose listener is bound to `listener_addr`.
+    pub fn new(listener_addr: SocketAddr) -> Self {
+        // A listener bound to all interfaces can be reached through the loopback address.
+        let wake_ip = match listener_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        Self {
+            requested: Arc::new(AtomicBool::new(false)),
+            wake_addr: SocketAddr::new(wake_ip, listener_addr.port()),
+        }
+    }
+
+    /// Requests a shutdown whenever the process receives SIGINT or SIGTERM. A second signal exits
+    /// the process immediately, in case clients take too long to disconnect.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a signal handler has already been installed for this process.
+    pub fn install_signal_handler(&self) {
+        let shutdown = self.clone();
+
+        ctrlc::set_handler(move || {
+            if shutdown.is_requested() {
+                info!(
+                    Context::SERVER,
+                    "shutdown_forced", "Second shutdown signal received; exiting immediately"
+                );
+                process::exit(130);
+            }
+
+            info!(
+                Context::SERVER,
+                "shutdown_requested",
+                "Shutdown signal received; press Ctrl-C again to exit immediately"
+            );
+            shutdown.request();
+        })
+        .expect("Failed to install signal handler");
+    }
+
+    /// Requests a shutdown. The accept loop is blocked waiting for the next connection, so a
+    /// connection is made to the listener to wake it up and have it check `is_requested`.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        if let Err(e) = TcpStream::connect(self.wake_addr) {
+            error!(
+                Context::SERVER,
+                "wake_failed", "Failed to wake the accept loop: {e}"
+            );
+        }
+    }
+
+    /// Returns `true` if a shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks a tracked connection as finished when dropped. It is moved into the thread or task that
+/// handles the connection so that it is dropped even if the handler panics.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    finished: Arc<AtomicBool>,
+}
+
+impl ConnectionGuard {
+    fn new() -> (Self, Arc<AtomicBool>) {
+        let finished = Arc::new(AtomicBool::new(false));
+        let guard = Self {
+            finished: finished.clone(),
+        };
+
+        (guard, finished)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.finished.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,90 @@
+//! Connection tracking for servers built on the async-std crate.
+
+use super::ConnectionGuard;
+use crate::info;
+use crate::log::Context;
+use async_std::future;
+use async_std::io::WriteExt;
+use async_std::net::TcpStream;
+use async_std::task::{self, JoinHandle};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often `Connections::drain` checks whether the handlers have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long `Connections::close` waits for a client to accept the shutdown notice.
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The connections currently being handled by a server, tracked so they can be closed cleanly when
+/// the server shuts down.
+#[derive(Debug, Default)]
+pub struct Connections {
+    active: Vec<(TcpStream, Arc<AtomicBool>)>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking `stream`. The returned guard must be moved into the task handling the
+    /// connection and dropped when the task finishes.
+    pub fn track(&mut self, stream: &TcpStream) -> ConnectionGuard {
+        self.active
+            .retain(|(_, finished)| !finished.load(Ordering::SeqCst));
+
+        let (guard, finished) = ConnectionGuard::new();
+        self.active.push((stream.clone(), finished));
+
+        guard
+    }
+
+    /// Stops reading from every tracked stream, so each handler sees the end of its input once it
+    /// has processed what it has already received. Then waits until every handler has finished or
+    /// `deadline` passes, whichever is sooner. Returns the number of handlers still running.
+    pub async fn drain(&mut self, deadline: Instant) -> usize {
+        for (stream, _) in &self.active {
+            // Fails harmlessly if the client has already disconnected.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        loop {
+            let running = self
+                .active
+                .iter()
+                .filter(|(_, finished)| !finished.load(Ordering::SeqCst))
+                .count();
+
+            if running == 0 || Instant::now() >= deadline {
+                info!(
+                    Context::SERVER,
+                    "drained", "Drained connections; {running} handler(s) still running"
+                );
+                return running;
+            }
+
+            task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Sends `notice`, if given, to every tracked stream and then closes it.
+    pub async fn close(self, notice: Option<&[u8]>) {
+        for (mut stream, _) in self.active {
+            if let Some(notice) = notice {
+                let _ = future::timeout(NOTICE_WRITE_TIMEOUT, stream.write_all(notice)).await;
+            }
+
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Waits until the task of `handle` has finished or `deadline` passes, whichever is sooner.
+/// Returns `true` if the task finished.
+pub async fn join_until(handle: JoinHandle<()>, deadline: Instant) -> bool {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    future::timeout(timeout, handle).await.is_ok()
+}
@@ -0,0 +1,102 @@
+//! Connection tracking for servers built on `std::thread`.
+
+use super::ConnectionGuard;
+use crate::info;
+use crate::log::Context;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often `Connections::drain` and `join_until` check whether the handlers have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long `Connections::close` waits for a client to accept the shutdown notice.
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The connections currently being handled by a server, tracked so they can be closed cleanly when
+/// the server shuts down.
+#[derive(Debug, Default)]
+pub struct Connections {
+    active: Vec<(TcpStream, Arc<AtomicBool>)>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking `stream`. The returned guard must be moved into the handler of the
+    /// connection and dropped when the handler finishes.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `stream` cannot be cloned, e.g., because the server has run out of file
+    /// descriptors.
+    pub fn track(&mut self, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        self.active
+            .retain(|(_, finished)| !finished.load(Ordering::SeqCst));
+
+        let stream = stream.try_clone()?;
+        let (guard, finished) = ConnectionGuard::new();
+        self.active.push((stream, finished));
+
+        Ok(guard)
+    }
+
+    /// Stops reading from every tracked stream, so each handler sees the end of its input once it
+    /// has processed what it has already received. Then waits until every handler has finished or
+    /// `deadline` passes, whichever is sooner. Returns the number of handlers still running.
+    pub fn drain(&mut self, deadline: Instant) -> usize {
+        for (stream, _) in &self.active {
+            // Fails harmlessly if the client has already disconnected.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        loop {
+            let running = self
+                .active
+                .iter()
+                .filter(|(_, finished)| !finished.load(Ordering::SeqCst))
+                .count();
+
+            if running == 0 || Instant::now() >= deadline {
+                info!(
+                    Context::SERVER,
+                    "drained", "Drained connections; {running} handler(s) still running"
+                );
+                return running;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Sends `notice`, if given, to every tracked stream and then closes it.
+    pub fn close(self, notice: Option<&[u8]>) {
+        for (mut stream, _) in self.active {
+            if let Some(notice) = notice {
+                let _ = stream.set_write_timeout(Some(NOTICE_WRITE_TIMEOUT));
+                let _ = stream.write_all(notice);
+            }
+
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Waits until the thread of `handle` has finished or `deadline` passes, whichever is sooner.
+/// Returns `true` if the thread finished.
+pub fn join_until(handle: JoinHandle<()>, deadline: Instant) -> bool {
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    handle.join().is_ok()
+}
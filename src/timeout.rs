@@ -0,0 +1,120 @@
+//! Timeouts that disconnect clients which stop sending, send too slowly, or stop reading what is
+//! sent to them, so an abandoned connection does not hold a thread or task forever.
+//!
+//! A client's input is read through a `TimedReader`, which fails with a `TimedOut` error carrying a
+//! `Timeout` once the client has sent no complete line for the idle timeout, or has taken longer
+//! than the line timeout to finish a line it started. Writes are limited by the write timeout. Each
+//! timeout is configured by a `TimeoutConfig`, and a handler can tell the client which one it
+//! exceeded before disconnecting it.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::config::TimeoutConfig;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// A timeout that a client has exceeded, giving how long it was allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// The client sent no complete line for this long.
+    Idle(Duration),
+    /// The client did not finish a line within this long of starting it.
+    Line(Duration),
+    /// A write to the client did not complete within this long.
+    Write(Duration),
+}
+
+impl Timeout {
+    /// Returns the timeout carried by `e`, if it is the error returned when a timeout is exceeded.
+    pub fn from_error(e: &io::Error) -> Option<Self> {
+        e.get_ref()?.downcast_ref().copied()
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = |limit: &Duration| match limit.as_secs() {
+            1 => "1 second".to_owned(),
+            n => format!("{n} seconds"),
+        };
+
+        match self {
+            Self::Idle(limit) => write!(f, "no input for {}", seconds(limit)),
+            Self::Line(limit) => write!(f, "line not finished within {}", seconds(limit)),
+            Self::Write(limit) => write!(f, "could not send for {}", seconds(limit)),
+        }
+    }
+}
+
+impl Error for Timeout {}
+
+impl From<Timeout> for io::Error {
+    fn from(timeout: Timeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+/// Tracks when a client must next send something: within the idle timeout of finishing its last
+/// line, and within the line timeout of starting the line it is sending.
+///
+/// Input that is not made of lines, such as WebSocket frames, is only subject to the idle timeout,
+/// which anything received restarts.
+#[derive(Debug)]
+struct Deadlines {
+    config: TimeoutConfig,
+    lines: bool,
+    /// When the client connected or last finished a line.
+    finished: Instant,
+    /// When the client started the line it is sending, if it is part way through one.
+    started: Option<Instant>,
+}
+
+impl Deadlines {
+    fn new(config: TimeoutConfig, lines: bool) -> Self {
+        Self {
+            config,
+            lines,
+            finished: Instant::now(),
+            started: None,
+        }
+    }
+
+    /// Returns when the client times out if nothing more is received, and which timeout that is.
+    fn next(&self) -> Option<(Instant, Timeout)> {
+        let idle = self
+            .config
+            .idle
+            .map(|limit| (self.finished + limit, Timeout::Idle(limit)));
+        let line = self
+            .started
+            .zip(self.config.line)
+            .map(|(started, limit)| (started + limit, Timeout::Line(limit)));
+
+        idle.into_iter().chain(line).min_by_key(|(at, _)| *at)
+    }
+
+    /// Records that `data` has been received from the client.
+    fn received(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        if !self.lines {
+            self.finished = now;
+            return;
+        }
+
+        if data.contains(&b'\n') {
+            self.finished = now;
+            self.started = None;
+        }
+        // Anything after the last newline starts a new line.
+        if !data.ends_with(b"\n") && self.started.is_none() {
+            self.started = Some(now);
+        }
+    }
+}
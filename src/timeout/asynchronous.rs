@@ -0,0 +1,118 @@
+//! Timeouts for connections built on the async-std crate, enforced with timers that wake the task
+//! waiting on a client once its time is up.
+
+use super::{Deadlines, Timeout};
+use crate::config::TimeoutConfig;
+use crate::tls::asynchronous::Reader;
+use async_std::io::{self, Read};
+use async_std::task;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// A future that completes once a deadline has passed.
+type Timer = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Reads a client's input from the plaintext half of its connection, failing with a `Timeout`
+/// once the client has taken too long to send a line.
+pub struct TimedReader {
+    inner: Reader,
+    deadlines: Deadlines,
+    /// Fires at the given deadline, waking the task waiting for the client to send something.
+    timer: Option<(Instant, Timer)>,
+}
+
+impl TimedReader {
+    /// Returns a reader of lines from `inner`, subject to the idle and line timeouts in `config`.
+    pub fn new(inner: Reader, config: TimeoutConfig) -> Self {
+        Self::with_deadlines(inner, Deadlines::new(config, true))
+    }
+
+    /// Returns a reader of input from `inner` that is not made of lines, such as WebSocket frames,
+    /// which is only subject to the idle timeout in `config`.
+    pub fn without_lines(inner: Reader, config: TimeoutConfig) -> Self {
+        Self::with_deadlines(inner, Deadlines::new(config, false))
+    }
+
+    fn with_deadlines(inner: Reader, deadlines: Deadlines) -> Self {
+        Self {
+            inner,
+            deadlines,
+            timer: None,
+        }
+    }
+}
+
+impl Read for TimedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        // Checked first, so a client trickling a line in a byte at a time still times out.
+        let next = this.deadlines.next();
+        if let Some((at, timeout)) = next {
+            if at <= Instant::now() {
+                return Poll::Ready(Err(timeout.into()));
+            }
+        }
+
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            if let Ok(n) = result {
+                this.deadlines.received(&buf[..n]);
+            }
+            return Poll::Ready(result);
+        }
+
+        let Some((at, timeout)) = next else {
+            return Poll::Pending;
+        };
+        if this.timer.as_ref().is_none_or(|(armed, _)| *armed != at) {
+            let delay = at.saturating_duration_since(Instant::now());
+            this.timer = Some((at, Box::pin(task::sleep(delay))));
+        }
+        let (_, timer) = this.timer.as_mut().unwrap();
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(timeout.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Runs `handshake`, such as a TLS handshake, which happens before there is a `TimedReader` to
+/// read from, failing with an idle `Timeout` if it takes longer than `limit`. There is no limit if
+/// `limit` is `None`.
+pub async fn handshake_within<T>(
+    limit: Option<Duration>,
+    handshake: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(limit) = limit else {
+        return handshake.await;
+    };
+
+    io::timeout(limit, handshake)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => Timeout::Idle(limit).into(),
+            _ => e,
+        })
+}
+
+/// Runs `write`, failing with a `Timeout` if it takes longer than `limit`. There is no limit if
+/// `limit` is `None`.
+pub async fn write_within<T>(
+    limit: Option<Duration>,
+    write: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(limit) = limit else {
+        return write.await;
+    };
+
+    io::timeout(limit, write).await.map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => Timeout::Write(limit).into(),
+        _ => e,
+    })
+}
@@ -0,0 +1,63 @@
+//! Timeouts for connections built on the blocking I/O of `std::net`, enforced with the read timeout
+//! of the client's socket. The write timeout is simply set as the socket's write timeout.
+
+use super::Deadlines;
+use crate::config::TimeoutConfig;
+use crate::tls::blocking::Reader;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::time::Instant;
+
+/// Reads a client's input from the plaintext half of its connection, failing with a `Timeout`
+/// once the client has taken too long to send a line.
+pub struct TimedReader {
+    inner: Reader,
+    stream: TcpStream,
+    deadlines: Deadlines,
+}
+
+impl TimedReader {
+    /// Returns a reader of lines from `inner`, the reading half of `stream`, subject to the idle
+    /// and line timeouts in `config`.
+    pub fn new(inner: Reader, stream: &TcpStream, config: TimeoutConfig) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            stream: stream.try_clone()?,
+            deadlines: Deadlines::new(config, true),
+        })
+    }
+}
+
+impl Read for TimedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let next = self.deadlines.next();
+        let limit = match next {
+            Some((at, timeout)) => match at.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(timeout.into()),
+            },
+            None => None,
+        };
+        self.stream.set_read_timeout(limit)?;
+
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.deadlines.received(&buf[..n]);
+                Ok(n)
+            }
+            // A socket read that times out fails with either of these, depending on the platform.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                match next {
+                    Some((_, timeout)) => Err(timeout.into()),
+                    None => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
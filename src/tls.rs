@@ -0,0 +1,60 @@
+//! Optional TLS for every server, built on rustls. When a certificate and private key are
+//! configured, each accepted connection is wrapped in a TLS session before anything is read from
+//! it, and the handlers read and write plaintext through the halves returned by `split`.
+//!
+//! Like the other modules, this has a `blocking` flavour built on `std::net` and an `asynchronous`
+//! flavour built on async-std. Either way, the reading half of a connection can be used while the
+//! writing half is used elsewhere, as the chat servers' writers do.
+
+pub mod asynchronous;
+pub mod blocking;
+
+use crate::config::TlsConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::io;
+use std::sync::Arc;
+
+/// A server's certificate and private key, ready to accept TLS connections. Clones share the same
+/// settings.
+#[derive(Clone, Debug)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate chain and private key named by `config`, both of which must be PEM
+    /// files. The certificate file can hold the whole chain, starting with the server's own
+    /// certificate.
+    pub fn load(config: &TlsConfig) -> io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(&config.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid_data(&config.cert_path.display(), e))?;
+        let key = PrivateKeyDer::from_pem_file(&config.key_path)
+            .map_err(|e| invalid_data(&config.key_path.display(), e))?;
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// Returns the acceptor for the certificate and private key named by `config`, or `None` if TLS is
+/// not configured.
+///
+/// # Panics
+///
+/// Panics if the certificate or private key cannot be loaded.
+pub fn acceptor(config: Option<&TlsConfig>) -> Option<TlsAcceptor> {
+    config.map(|config| TlsAcceptor::load(config).expect("Failed to load TLS certificate and key"))
+}
+
+fn invalid_data(path: &impl std::fmt::Display, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {e}"))
+}
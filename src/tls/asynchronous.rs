@@ -0,0 +1,45 @@
+//! TLS for connections built on the async-std crate, using futures-rustls.
+
+use super::TlsAcceptor;
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The half of a connection plaintext is read from.
+pub type Reader = Box<dyn Read + Send + Unpin>;
+
+/// The half of a connection plaintext is written to. Closing it ends a TLS session cleanly.
+pub type Writer = Box<dyn Write + Send + Unpin>;
+
+/// Returns the halves through which plaintext is read from and written to `stream`, which is
+/// encrypted with TLS if `tls` is given. The TLS handshake is completed before this returns.
+pub async fn split(stream: TcpStream, tls: Option<&TlsAcceptor>) -> io::Result<(Reader, Writer)> {
+    let Some(tls) = tls else {
+        return Ok((Box::new(stream.clone()), Box::new(stream)));
+    };
+
+    let stream = futures_rustls::TlsAcceptor::from(tls.config.clone())
+        .accept(stream)
+        .await?;
+    let (reader, writer) = futures_lite::io::split(stream);
+    Ok((Box::new(EndOfInput(reader)), Box::new(writer)))
+}
+
+/// Treats a client that disconnects without ending the TLS session, or a server that stops reading
+/// while shutting down, as the end of the input, just as on a plain connection.
+struct EndOfInput<R>(R);
+
+impl<R: Read + Unpin> Read for EndOfInput<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Poll::Ready(Ok(0)),
+            poll => poll,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! TLS for connections built on the blocking I/O of `std::net`.
+//!
+//! rustls keeps the state of a session in a single `ServerConnection`, which the two halves of a
+//! connection share behind a mutex. The mutex is never held while waiting for the client to send
+//! something, so the writing half is not held up by a reader waiting for input.
+
+use super::TlsAcceptor;
+use rustls::ServerConnection;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// The half of a connection plaintext is read from.
+pub type Reader = Box<dyn Read + Send>;
+
+/// The half of a connection plaintext is written to.
+pub type Writer = Box<dyn Write + Send>;
+
+/// How much encrypted data is read from a connection at once. This is small enough that rustls can
+/// always buffer the plaintext it decrypts to.
+const RECEIVE_BUFFER_SIZE: usize = 4096;
+
+/// Returns the halves through which plaintext is read from and written to `stream`, which is
+/// encrypted with TLS if `tls` is given. The TLS handshake is completed as the reading half is
+/// first read from; anything written before then is sent once it completes.
+pub fn split(stream: &TcpStream, tls: Option<&TlsAcceptor>) -> io::Result<(Reader, Writer)> {
+    let Some(tls) = tls else {
+        return Ok((Box::new(stream.try_clone()?), Box::new(stream.try_clone()?)));
+    };
+
+    let session = ServerConnection::new(tls.config.clone()).map_err(io::Error::other)?;
+    let session = Arc::new(Mutex::new(session));
+    let reader = TlsReader {
+        stream: stream.try_clone()?,
+        session: session.clone(),
+    };
+    let writer = TlsWriter {
+        stream: stream.try_clone()?,
+        session,
+    };
+
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+struct TlsReader {
+    stream: TcpStream,
+    session: Arc<Mutex<ServerConnection>>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0; RECEIVE_BUFFER_SIZE];
+
+        loop {
+            match self.session.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // A client that disconnects without ending the session, or a server that stops
+                // reading while shutting down, ends the input just as a plain connection does.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+
+            let n = self.stream.read(&mut received)?;
+            let mut session = self.session.lock().unwrap();
+
+            // Reading nothing tells the session that the client has closed the connection.
+            let mut received = &received[..n];
+            loop {
+                session.read_tls(&mut received)?;
+                if let Err(e) = session.process_new_packets() {
+                    // Fails harmlessly if the client has already disconnected.
+                    let _ = write_pending(&mut session, &self.stream);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                if received.is_empty() {
+                    break;
+                }
+            }
+
+            // Handshake messages, for example, need answering before the client sends more.
+            write_pending(&mut session, &self.stream)?;
+        }
+    }
+}
+
+struct TlsWriter {
+    stream: TcpStream,
+    session: Arc<Mutex<ServerConnection>>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.writer().write(buf)?;
+        write_pending(&mut session, &self.stream)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        write_pending(&mut session, &self.stream)
+    }
+}
+
+impl Drop for TlsWriter {
+    /// Ends the session cleanly, so the client can tell the server closed the connection on
+    /// purpose.
+    fn drop(&mut self) {
+        if let Ok(mut session) = self.session.lock() {
+            session.send_close_notify();
+            // Fails harmlessly if the client has already disconnected.
+            let _ = write_pending(&mut session, &self.stream);
+        }
+    }
+}
+
+/// Writes everything `session` has waiting to be sent to `stream`.
+fn write_pending(session: &mut ServerConnection, mut stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut stream)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! The parts of the WebSocket protocol (RFC 6455) the chat servers need to accept browsers as chat
+//! clients: the opening handshake, and the frames that carry messages and control information.
+//!
+//! Each text message a client sends is handled as one or more lines of the text protocol, and each
+//! message sent to the client is a text message holding one line, without its trailing newline.
+
+pub mod asynchronous;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt;
+
+/// Appended to a client's key to compute the `Sec-WebSocket-Accept` header, as set by RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The maximum length of a message from a client, in bytes, including all of its fragments.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// The maximum number of lines in an opening handshake, including the request line.
+pub const MAX_HANDSHAKE_LINES: usize = 64;
+
+/// The maximum length of an opening handshake, in bytes, including its line endings.
+pub const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+
+/// Status code closing a connection that has done its job.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Status code closing a connection whose peer broke the protocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Status code closing a connection whose peer sent a kind of message that is not accepted.
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+
+/// Status code closing a connection whose peer sent a message that is too long.
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The type of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    /// A further fragment of a text or binary message.
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    /// Returns the opcode with the value `bits`, if it is defined.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the opcode in a frame header.
+    pub fn bits(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+
+    /// Returns `true` for the opcodes of control frames, which can arrive between the fragments of
+    /// a message.
+    pub fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+/// A frame received from a client, with its payload unmasked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Whether this is the last fragment of its message.
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// The reasons an opening handshake is refused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The request is not an HTTP/1.1 `GET` request.
+    NotGet,
+    /// The request does not ask to upgrade the connection to a WebSocket.
+    NotUpgrade,
+    /// The request asks for a version of the protocol other than 13.
+    UnsupportedVersion,
+    MissingKey,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotGet => write!(f, "Expected an HTTP/1.1 GET request"),
+            Self::NotUpgrade => write!(f, "Expected a request to upgrade to a WebSocket"),
+            Self::UnsupportedVersion => write!(f, "Only WebSocket version 13 is supported"),
+            Self::MissingKey => write!(f, "Missing Sec-WebSocket-Key header"),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+/// Checks the `request` that opens a WebSocket connection, given as its request line followed by
+/// its headers without their line endings, and returns the client's key.
+pub fn parse_handshake(request: &[String]) -> Result<String, HandshakeError> {
+    let Some((request_line, headers)) = request.split_first() else {
+        return Err(HandshakeError::NotGet);
+    };
+    if !request_line.starts_with("GET ") || !request_line.ends_with(" HTTP/1.1") {
+        return Err(HandshakeError::NotGet);
+    }
+
+    let header = |name: &str| {
+        headers.iter().find_map(|header| {
+            let (header_name, value) = header.split_once(':')?;
+            header_name
+                .trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim())
+        })
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+
+    match header("Sec-WebSocket-Key") {
+        Some(key) if !key.is_empty() => Ok(key.to_owned()),
+        _ => Err(HandshakeError::MissingKey),
+    }
+}
+
+/// Returns the value of the `Sec-WebSocket-Accept` header answering a handshake with `key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{key}{ACCEPT_GUID}").as_bytes());
+    BASE64.encode(digest)
+}
+
+/// Returns the response accepting a handshake with `key`.
+pub fn handshake_response(key: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+}
+
+/// Returns the response refusing a handshake because of `error`.
+pub fn handshake_rejection(error: &HandshakeError) -> String {
+    let body = format!("{error}\n");
+    format!(
+        "HTTP/1.1 400 Bad Request\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Returns a complete, unfragmented frame with `opcode` and `payload`. Frames sent by a server
+/// are not masked.
+pub fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.bits());
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,238 @@
+//! WebSocket connections built on the async-std crate.
+
+use super::{
+    Frame, Opcode, CLOSE_NORMAL, CLOSE_PROTOCOL_ERROR, CLOSE_TOO_BIG, CLOSE_UNSUPPORTED_DATA,
+    MAX_HANDSHAKE_LEN, MAX_HANDSHAKE_LINES, MAX_MESSAGE_LEN,
+};
+use crate::config::{InputConfig, LongLinePolicy, Utf8Policy};
+use crate::input::{self, Line};
+use crate::tls::asynchronous::{Reader, Writer};
+use async_std::io::{BufReader, ReadExt, WriteExt};
+use async_std::sync::Mutex;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The sending half of a WebSocket connection. Clones share the connection, and each frame is
+/// written whole, so a client's handler can answer pings while its writer sends messages.
+#[derive(Clone)]
+pub struct FrameWriter {
+    stream: Arc<Mutex<Writer>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl FrameWriter {
+    pub fn new(stream: Writer) -> Self {
+        Self {
+            stream: Arc::new(Mutex::new(stream)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sends a frame with `opcode` and `payload`. Nothing may follow a close frame, so this does
+    /// nothing once the connection has been closed.
+    pub async fn send(&self, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.write_frame(opcode, payload).await
+    }
+
+    /// Sends a close frame with the status `code`, unless one has already been sent.
+    pub async fn close(&self, code: u16) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.write_frame(Opcode::Close, &code.to_be_bytes()).await
+    }
+
+    /// Flushes and closes the connection, ending any TLS session. A close frame should be sent
+    /// first.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        futures_lite::AsyncWriteExt::close(&mut *self.stream.lock().await).await
+    }
+
+    async fn write_frame(&self, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+        let frame = super::encode_frame(opcode, payload);
+        self.stream.lock().await.write_all(&frame).await
+    }
+}
+
+/// Reads the request that opens a WebSocket connection from `reader`, returning its request line
+/// and headers without their line endings. No more than `MAX_HANDSHAKE_LEN` bytes are read, and
+/// no line may be longer than `input` allows.
+pub async fn read_handshake(
+    reader: &mut BufReader<Reader>,
+    input: InputConfig,
+) -> io::Result<Vec<String>> {
+    // Truncating or discarding a line would change the request, so any line that is not accepted
+    // fails the handshake.
+    let config = InputConfig {
+        long_lines: LongLinePolicy::Disconnect,
+        utf8: Utf8Policy::Strict,
+        ..input
+    };
+    let mut request = Vec::new();
+    let mut limited = reader.take(MAX_HANDSHAKE_LEN as u64);
+
+    loop {
+        let line = match input::asynchronous::read_line(&mut limited, config).await? {
+            Some(Line::Accepted(line)) if line.ends_with('\n') => line,
+            Some(Line::Rejected(rejection)) => return Err(rejection.into()),
+            _ if limited.limit() == 0 => return Err(invalid_data("Handshake is too long")),
+            _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok(request);
+        }
+        if request.len() == MAX_HANDSHAKE_LINES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Handshake has too many headers",
+            ));
+        }
+        request.push(line.to_owned());
+    }
+}
+
+/// Reads the next text message from `reader`, reassembling it from its fragments and answering
+/// any pings through `writer`. Returns `None` once the client has closed the connection, after
+/// acknowledging the close.
+///
+/// If the client breaks the protocol or sends a message that is not accepted, the connection is
+/// closed with a suitable status code and an `InvalidData` error is returned.
+pub async fn read_message(
+    reader: &mut BufReader<Reader>,
+    writer: &FrameWriter,
+) -> io::Result<Option<String>> {
+    let mut message = Vec::new();
+    let mut fragmented = false;
+
+    loop {
+        let frame = match read_frame(reader).await {
+            Ok(frame) => frame,
+            Err(FrameError::Io(e)) => return Err(e),
+            Err(FrameError::Refused(code, reason)) => {
+                return Err(refuse(writer, code, invalid_data(reason)).await);
+            }
+        };
+
+        match frame.opcode {
+            Opcode::Ping => writer.send(Opcode::Pong, &frame.payload).await?,
+            Opcode::Pong => {}
+            Opcode::Close => {
+                // Fails harmlessly if the client closed the connection without waiting.
+                let _ = writer.close(CLOSE_NORMAL).await;
+                return Ok(None);
+            }
+            Opcode::Binary => {
+                let e = invalid_data("Binary messages are not supported");
+                return Err(refuse(writer, CLOSE_UNSUPPORTED_DATA, e).await);
+            }
+            Opcode::Text | Opcode::Continuation => {
+                if (frame.opcode == Opcode::Continuation) != fragmented {
+                    let e = invalid_data("Unexpected continuation frame");
+                    return Err(refuse(writer, CLOSE_PROTOCOL_ERROR, e).await);
+                }
+                if message.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                    let e = invalid_data("Message is too long");
+                    return Err(refuse(writer, CLOSE_TOO_BIG, e).await);
+                }
+
+                message.extend_from_slice(&frame.payload);
+                fragmented = !frame.fin;
+                if frame.fin {
+                    return match String::from_utf8(message) {
+                        Ok(message) => Ok(Some(message)),
+                        Err(_) => {
+                            let e = invalid_data("Text message is not valid UTF-8");
+                            Err(refuse(writer, CLOSE_PROTOCOL_ERROR, e).await)
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// The reasons a frame cannot be read.
+enum FrameError {
+    Io(io::Error),
+    /// The frame is not acceptable, so the connection should be closed with the given status code
+    /// for the given reason.
+    Refused(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Reads a single frame from `reader`, unmasking its payload.
+async fn read_frame(reader: &mut BufReader<Reader>) -> Result<Frame, FrameError> {
+    let protocol_error = |reason| FrameError::Refused(CLOSE_PROTOCOL_ERROR, reason);
+
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(protocol_error("Reserved bits are set"));
+    }
+    let opcode =
+        Opcode::from_bits(header[0] & 0x0F).ok_or_else(|| protocol_error("Unknown opcode"))?;
+    if header[1] & 0x80 == 0 {
+        return Err(protocol_error("Frames from clients must be masked"));
+    }
+
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len).await?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if opcode.is_control() && (len > 125 || !fin) {
+        return Err(protocol_error(
+            "Control frames must be short and unfragmented",
+        ));
+    }
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(FrameError::Refused(CLOSE_TOO_BIG, "Frame is too long"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// Closes the connection through `writer` with the status `code`, because of `error`, which is
+/// returned.
+async fn refuse(writer: &FrameWriter, code: u16, error: io::Error) -> io::Error {
+    // Fails harmlessly if the client has already disconnected.
+    let _ = writer.close(code).await;
+    error
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
@@ -0,0 +1,1089 @@
+//! Integration tests for the chat servers.
+
+mod common;
+
+use common::{Client, Server, Tls};
+use serde_json::{json, Value};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use std::{env, fs, process, thread};
+use tungstenite::{Message, WebSocket};
+
+const CHAT_THREADED: &str = env!("CARGO_BIN_EXE_chat_threaded");
+const CHAT_ASYNC: &str = env!("CARGO_BIN_EXE_chat_async");
+
+/// Connects a client to `server` and joins the chat as `display_name`, returning once the client
+/// has seen its own arrival announced.
+fn join(server: &Server, display_name: &str) -> Client {
+    let mut client = server.connect();
+    client.expect_line("Enter your display name");
+    client.send(display_name);
+    client.read_until(&format!("{display_name} has entered the chat"));
+    client
+}
+
+/// Reads the next line from `client`, which speaks the JSON-lines protocol, and checks it is the
+/// object `expected` once its `time` is removed.
+fn expect_json(client: &mut Client, expected: Value) {
+    let mut object: Value = serde_json::from_str(&client.read_line()).unwrap();
+    assert!(object["time"].is_string(), "{object} has no time");
+    object.as_object_mut().unwrap().remove("time");
+    assert_eq!(object, expected);
+}
+
+/// Opens a WebSocket connection to the server's listener at `addr`.
+fn connect_websocket(addr: SocketAddr) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(addr).expect("Failed to connect to server");
+    stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let (socket, _) =
+        tungstenite::client(format!("ws://{addr}/"), stream).expect("WebSocket handshake failed");
+    socket
+}
+
+/// Reads the next message from `socket` and checks it is the text message `expected`.
+fn expect_text(socket: &mut WebSocket<TcpStream>, expected: &str) {
+    match socket.read().expect("Failed to read from server") {
+        Message::Text(text) => assert_eq!(text.as_str(), expected),
+        message => panic!("Expected a text message but got {message:?}"),
+    }
+}
+
+/// Reads the next line from `client`, which speaks IRC, and checks it is `expected` followed by CRLF.
+fn expect_irc(client: &mut Client, expected: &str) {
+    client.expect_line(&format!("{expected}\r"));
+}
+
+/// Checks that a new client is asked for a display name and announced once it enters one.
+fn assert_prompts_for_display_name(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut client = server.connect();
+
+    client.expect_line("Enter your display name");
+    client.send("alice");
+    client.expect_line("alice has entered the chat");
+}
+
+/// Checks that clients already in the chat are told when someone enters it.
+fn assert_announces_new_users(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let _bob = join(&server, "bob");
+
+    alice.expect_line("bob has entered the chat");
+}
+
+/// Checks that a line of chat is sent to every client in the room, including its sender.
+fn assert_broadcasts_to_all_clients(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    let mut carol = join(&server, "carol");
+    alice.read_until("carol has entered the chat");
+    bob.read_until("carol has entered the chat");
+
+    bob.send("hello everyone");
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.expect_line("bob: hello everyone");
+    }
+}
+
+/// Checks that display names that are empty, taken, too long or contain spaces or control
+/// characters are refused, whether entered on arrival or with `/nick`.
+fn assert_rejects_unusable_display_names(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut client = server.connect();
+    client.expect_line("Enter your display name");
+
+    for (name, error) in [
+        ("", "Display name cannot be empty"),
+        ("ALICE", "Display name 'ALICE' is already taken"),
+        ("bob smith", "Display name cannot contain spaces"),
+        (
+            "bob\x1b[2J",
+            "Display name cannot contain control characters",
+        ),
+        (
+            &"b".repeat(33),
+            "Display name cannot be longer than 32 characters",
+        ),
+    ] {
+        client.send(name);
+        client.expect_line(error);
+        client.expect_line("Enter your display name");
+    }
+
+    client.send("bob");
+    client.expect_line("bob has entered the chat");
+    alice.expect_line("bob has entered the chat");
+
+    client.send("/nick Alice");
+    client.expect_line("Display name 'Alice' is already taken");
+}
+
+/// Checks that messages only reach the members of the room they are sent to, as clients join
+/// and leave rooms.
+fn assert_routes_messages_by_room(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    let mut carol = join(&server, "carol");
+    alice.read_until("carol has entered the chat");
+    bob.read_until("carol has entered the chat");
+
+    alice.send("/join rust");
+    alice.expect_line("alice has joined #rust");
+    bob.send("/join #rust");
+    bob.expect_line("bob has joined #rust");
+    alice.expect_line("bob has joined #rust");
+
+    alice.send("hello rust");
+    alice.expect_line("[#rust] alice: hello rust");
+    bob.expect_line("[#rust] alice: hello rust");
+
+    // Alice and bob are still in the lobby, but only carol is in it after alice leaves.
+    carol.send("hello lobby");
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.expect_line("carol: hello lobby");
+    }
+
+    alice.send("/part lobby");
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.expect_line("alice has left #lobby");
+    }
+
+    carol.send("anyone here?");
+    carol.expect_line("carol: anyone here?");
+    bob.expect_line("carol: anyone here?");
+
+    alice.send("/rooms");
+    alice.expect_line("Rooms: #lobby (2), #rust (2)");
+}
+
+/// Checks that the built-in commands work, and that a line starting with `//` is sent as chat.
+fn assert_runs_commands(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.read_until("bob has entered the chat");
+
+    alice.send("/nick alicia");
+    alice.expect_line("alice is now known as alicia");
+    bob.expect_line("alice is now known as alicia");
+
+    bob.send("/who");
+    bob.expect_line("People in #lobby: alicia, bob");
+
+    alice.send("/me waves");
+    alice.expect_line("* alicia waves");
+    bob.expect_line("* alicia waves");
+
+    alice.send("/msg bob psst");
+    alice.expect_line("[private to bob] alicia: psst");
+    bob.expect_line("[private] alicia: psst");
+
+    bob.send("//shrug");
+    alice.expect_line("bob: /shrug");
+    bob.expect_line("bob: /shrug");
+
+    bob.send("/quit");
+    bob.expect_line("Goodbye");
+    assert_eq!(bob.read_to_end(), Vec::<String>::new());
+    alice.read_until("bob has left the chat (quit)");
+}
+
+/// Checks that unknown commands and `/help` are answered only to the client that sent them.
+fn assert_answers_unknown_commands_privately(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.read_until("bob has entered the chat");
+
+    alice.send("/frobnicate now");
+    alice.expect_line("Unknown command: /frobnicate; use /help to list the commands");
+
+    alice.send("/help msg");
+    alice.expect_line("Commands:");
+    alice.expect_line("  /msg <name> <text>  Send a private message to one person");
+
+    // Bob's next line is his own, so he saw none of alice's commands or their replies.
+    bob.send("hello");
+    bob.expect_line("bob: hello");
+}
+
+/// Checks that private messages reach only their recipient, whatever room either client is in.
+fn assert_delivers_private_messages(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    let mut carol = join(&server, "carol");
+    alice.read_until("carol has entered the chat");
+    bob.read_until("carol has entered the chat");
+
+    // Bob and carol are in different rooms, but private messages reach them anyway.
+    carol.send("/join elsewhere");
+    carol.expect_line("carol has joined #elsewhere");
+
+    alice.send("/msg CAROL are you there?");
+    alice.expect_line("[private to carol] alice: are you there?");
+    carol.expect_line("[private] alice: are you there?");
+
+    alice.send("/msg dave hello");
+    alice.expect_line("No such user: dave");
+
+    alice.send("/msg bob");
+    alice.expect_line("Usage: /msg <name> <text>");
+
+    // Bob's next line is his own, so he received none of alice's private messages.
+    bob.send("hello");
+    bob.expect_line("bob: hello");
+}
+
+/// Checks that a client entering the chat or joining a room is first sent the room's most recent
+/// messages, up to the configured number.
+fn assert_replays_history(path: &str) {
+    let server = Server::start(path, &["--history", "2"]);
+    let mut alice = join(&server, "alice");
+    for line in ["one", "two", "three"] {
+        alice.send(line);
+        alice.expect_line(&format!("alice: {line}"));
+    }
+
+    let mut bob = server.connect();
+    bob.expect_line("Enter your display name");
+    bob.send("bob");
+    bob.expect_line("[history] alice: two");
+    bob.expect_line("[history] alice: three");
+    bob.expect_line("bob has entered the chat");
+    alice.expect_line("bob has entered the chat");
+
+    alice.send("/join rust");
+    alice.expect_line("alice has joined #rust");
+    alice.send("in rust");
+    alice.expect_line("[#rust] alice: in rust");
+
+    bob.send("/join rust");
+    bob.expect_line("[history] [#rust] alice: in rust");
+    bob.expect_line("bob has joined #rust");
+}
+
+/// Checks that messages older than the configured age are not replayed.
+fn assert_limits_history_by_age(path: &str) {
+    let server = Server::start(path, &["--history-age", "1"]);
+    let mut alice = join(&server, "alice");
+    alice.send("old news");
+    alice.expect_line("alice: old news");
+
+    thread::sleep(Duration::from_millis(1500));
+    let mut bob = server.connect();
+    bob.expect_line("Enter your display name");
+    bob.send("bob");
+    bob.expect_line("bob has entered the chat");
+}
+
+/// Checks that messages are written to the chat log, which is rotated once it is too big, and
+/// that a restarted server replays them from both logs.
+fn assert_reloads_chat_log(path: &str, log_name: &str) {
+    let log = env::temp_dir().join(format!("{log_name}-{}.log", process::id()));
+    let rotated = log.with_extension("log.1");
+    let _ = fs::remove_file(&log);
+    let _ = fs::remove_file(&rotated);
+    let log_arg = log.to_str().unwrap();
+
+    // Each record is about 50 bytes, so the log is rotated before the third is written.
+    let server = Server::start(path, &["--chat-log", log_arg, "--chat-log-max-size", "120"]);
+    let mut alice = join(&server, "alice");
+    alice.send("first message");
+    alice.expect_line("alice: first message");
+    alice.send("/me waves");
+    alice.expect_line("* alice waves");
+    alice.send("/msg alice not logged");
+    alice.expect_line("[private to alice] alice: not logged");
+    alice.send("last message");
+    alice.expect_line("alice: last message");
+    server.terminate();
+    assert!(rotated.exists(), "Chat log was not rotated");
+
+    let server = Server::start(path, &["--chat-log", log_arg]);
+    let mut bob = server.connect();
+    bob.expect_line("Enter your display name");
+    bob.send("bob");
+    bob.expect_line("[history] alice: first message");
+    bob.expect_line("[history] * alice waves");
+    bob.expect_line("[history] alice: last message");
+    bob.expect_line("bob has entered the chat");
+
+    let _ = fs::remove_file(&log);
+    let _ = fs::remove_file(&rotated);
+}
+
+/// Checks that lines of the chat log that cannot be read are skipped with a warning, and the
+/// rest still replayed.
+fn assert_skips_unreadable_chat_log_lines(path: &str, log_name: &str) {
+    let log = env::temp_dir().join(format!("{log_name}-{}.log", process::id()));
+    let _ = fs::remove_file(log.with_extension("log.1"));
+    let mut contents = b"2024-05-01T12:34:56.789Z\tlobby\talice\tchat\tbefore\n".to_vec();
+    contents.extend_from_slice(b"2024-05-01T12:34:57.000Z\tlobby\talice\tchat\tcaf\xe9\n");
+    // Times that are out of range, or too far in the future to represent, are unreadable too.
+    contents.extend_from_slice(b"2024-13-45T99:99:99.999Z\tlobby\talice\tchat\tnever\n");
+    contents.extend_from_slice(b"999999999999999-01-01T00:00:00Z\tlobby\talice\tchat\tnever\n");
+    contents.extend_from_slice(b"2024-05-01T12:34:58.12345678901234567Z\tlobby\talice\tchat\tx\n");
+    contents.extend_from_slice(b"2024-05-01T12:34:58.000Z\tlobby\talice\tchat\tafter\n");
+    fs::write(&log, contents).unwrap();
+
+    let server = Server::start(path, &["--chat-log", log.to_str().unwrap()]);
+    for number in 2..=5 {
+        server.wait_for_output(&format!("Skipping unreadable line {number} of chat log"));
+    }
+    let mut bob = server.connect();
+    bob.expect_line("Enter your display name");
+    bob.send("bob");
+    bob.expect_line("[history] alice: before");
+    bob.expect_line("[history] alice: after");
+    bob.expect_line("bob has entered the chat");
+
+    let _ = fs::remove_file(&log);
+}
+
+/// Checks that clients on the JSON port exchange messages with clients of the text protocol.
+fn assert_speaks_json(path: &str) {
+    let server = Server::start(path, &["--json-port", "0"]);
+    let mut alice = join(&server, "alice");
+    let mut bot = Client::connect(server.listener_addr("JSON"));
+
+    expect_json(
+        &mut bot,
+        json!({ "type": "system", "text": "Enter your display name" }),
+    );
+    bot.send(r#"{"type":"nick","name":"bot"}"#);
+    expect_json(
+        &mut bot,
+        json!({ "type": "join", "user": "bot", "room": null }),
+    );
+    alice.expect_line("bot has entered the chat");
+
+    alice.send("hello bot");
+    alice.expect_line("alice: hello bot");
+    expect_json(
+        &mut bot,
+        json!({
+            "type": "message", "kind": "chat", "from": "alice", "room": "lobby", "text": "hello bot"
+        }),
+    );
+
+    // Text sent with "say" is never a command.
+    bot.send(r#"{"type":"say","text":"/help"}"#);
+    alice.expect_line("bot: /help");
+    expect_json(
+        &mut bot,
+        json!({ "type": "message", "kind": "chat", "from": "bot", "room": "lobby", "text": "/help" }),
+    );
+
+    bot.send(r#"{"type":"msg","to":"alice","text":"psst"}"#);
+    alice.expect_line("[private] bot: psst");
+    expect_json(
+        &mut bot,
+        json!({ "type": "message", "kind": "private", "from": "bot", "to": "alice", "text": "psst" }),
+    );
+
+    bot.send(r#"{"type":"who"}"#);
+    expect_json(
+        &mut bot,
+        json!({ "type": "users", "room": "lobby", "users": ["alice", "bot"] }),
+    );
+
+    bot.send(r#"{"type":"join","room":"no spaces"}"#);
+    expect_json(
+        &mut bot,
+        json!({
+            "type": "error",
+            "message": "Invalid room name 'no spaces': use up to 32 letters, digits, '-' or '_'"
+        }),
+    );
+
+    bot.send("hello");
+    let error: Value = serde_json::from_str(&bot.read_line()).unwrap();
+    assert_eq!(error["type"], "error");
+
+    drop(alice);
+    expect_json(
+        &mut bot,
+        json!({ "type": "leave", "user": "alice", "room": null, "reason": "quit" }),
+    );
+}
+
+/// Checks that WebSocket clients chat with clients of the text protocol, have their pings
+/// answered, and are closed cleanly when they leave.
+fn assert_speaks_websocket(path: &str) {
+    let server = Server::start(path, &["--ws-port", "0"]);
+    let mut alice = join(&server, "alice");
+    let mut browser = connect_websocket(server.listener_addr("WebSocket"));
+
+    expect_text(&mut browser, "Enter your display name");
+    browser.send(Message::text("bob")).unwrap();
+    expect_text(&mut browser, "bob has entered the chat");
+    alice.expect_line("bob has entered the chat");
+
+    alice.send("hello bob");
+    alice.expect_line("alice: hello bob");
+    expect_text(&mut browser, "alice: hello bob");
+
+    // Each line of a message is handled separately.
+    browser.send(Message::text("hi\n/me waves")).unwrap();
+    alice.expect_line("bob: hi");
+    alice.expect_line("* bob waves");
+    expect_text(&mut browser, "bob: hi");
+    expect_text(&mut browser, "* bob waves");
+
+    browser.send(Message::Ping("are you there".into())).unwrap();
+    match browser.read().unwrap() {
+        Message::Pong(payload) => assert_eq!(&payload[..], b"are you there"),
+        message => panic!("Expected a pong but got {message:?}"),
+    }
+
+    browser.close(None).unwrap();
+    loop {
+        match browser.read() {
+            Ok(Message::Close(_)) => {}
+            Ok(message) => panic!("Expected the connection to close but got {message:?}"),
+            Err(tungstenite::Error::ConnectionClosed) => break,
+            Err(e) => panic!("Connection was not closed cleanly: {e}"),
+        }
+    }
+    alice.expect_line("bob has left the chat (quit)");
+}
+
+/// Checks that a request that is not a WebSocket upgrade is answered with an error, and the
+/// client never joins the chat.
+fn assert_refuses_bad_websocket_handshakes(path: &str) {
+    let server = Server::start(path, &["--ws-port", "0"]);
+    let mut client = Client::connect(server.listener_addr("WebSocket"));
+
+    client.send("GET / HTTP/1.1\r");
+    client.send("Host: localhost\r");
+    client.send("\r");
+    client.expect_line("HTTP/1.1 400 Bad Request\r");
+    let response = client.read_to_end();
+    assert_eq!(
+        response.last().unwrap(),
+        "Expected a request to upgrade to a WebSocket\n"
+    );
+
+    // Nobody joined the chat.
+    let mut alice = join(&server, "alice");
+    alice.send("/who");
+    alice.expect_line("People in #lobby: alice");
+}
+
+/// Checks that a WebSocket handshake with a line or in total longer than allowed is refused.
+fn assert_refuses_oversized_websocket_handshakes(path: &str) {
+    let server = Server::start(path, &["--ws-port", "0"]);
+    let mut client = Client::connect(server.listener_addr("WebSocket"));
+
+    client.send("GET / HTTP/1.1\r");
+    client.send(&format!("X-Padding: {}\r", "a".repeat(5_000)));
+    server.wait_for_output("line longer than 4096 bytes");
+
+    // However short its lines, the whole handshake is limited too.
+    let mut client = Client::connect(server.listener_addr("WebSocket"));
+    let padding = (0..40).map(|i| format!("X-Padding-{i}: {}\r\n", "a".repeat(500)));
+    client.send_partial(&format!(
+        "GET / HTTP/1.1\r\n{}",
+        padding.collect::<String>()
+    ));
+    server.wait_for_output("Handshake is too long");
+
+    // Nobody joined the chat.
+    let mut alice = join(&server, "alice");
+    alice.send("/who");
+    alice.expect_line("People in #lobby: alice");
+}
+
+/// Checks that IRC clients are registered, join the lobby and chat with clients of the text
+/// protocol.
+fn assert_speaks_irc(path: &str) {
+    let server = Server::start(path, &["--irc-port", "0"]);
+    let mut alice = join(&server, "alice");
+    let mut bob = Client::connect(server.listener_addr("IRC"));
+
+    bob.send("NICK bob");
+    bob.send("USER bob 0 * :Bob");
+    expect_irc(&mut bob, ":tcp_echo 001 bob :Welcome to the chat, bob");
+    expect_irc(&mut bob, ":tcp_echo 422 bob :MOTD File is missing");
+    expect_irc(&mut bob, ":bob!bob@tcp_echo JOIN #lobby");
+    expect_irc(&mut bob, ":tcp_echo 353 bob = #lobby :alice bob");
+    expect_irc(&mut bob, ":tcp_echo 366 bob #lobby :End of /NAMES list");
+    alice.expect_line("bob has entered the chat");
+
+    alice.send("hello bob");
+    alice.expect_line("alice: hello bob");
+    expect_irc(&mut bob, ":alice!alice@tcp_echo PRIVMSG #lobby :hello bob");
+
+    // Nothing bob sends is echoed back to him.
+    bob.send("PRIVMSG #lobby :hi alice");
+    alice.expect_line("bob: hi alice");
+    bob.send("PRIVMSG #lobby :\x01ACTION waves\x01");
+    alice.expect_line("* bob waves");
+    bob.send("PRIVMSG alice :psst");
+    alice.expect_line("[private] bob: psst");
+
+    alice.send("/msg bob hey");
+    alice.expect_line("[private to bob] alice: hey");
+    expect_irc(&mut bob, ":alice!alice@tcp_echo PRIVMSG bob :hey");
+
+    bob.send("PING :12345");
+    expect_irc(&mut bob, ":tcp_echo PONG tcp_echo :12345");
+
+    bob.send("JOIN #rust");
+    expect_irc(&mut bob, ":bob!bob@tcp_echo JOIN #rust");
+    expect_irc(&mut bob, ":tcp_echo 353 bob = #rust :bob");
+    expect_irc(&mut bob, ":tcp_echo 366 bob #rust :End of /NAMES list");
+
+    bob.send("PRIVMSG #nowhere :hi");
+    expect_irc(
+        &mut bob,
+        ":tcp_echo 442 bob #nowhere :You are not in #nowhere",
+    );
+
+    bob.send("NAMES #lobby");
+    expect_irc(&mut bob, ":tcp_echo 353 bob = #lobby :alice bob");
+    expect_irc(&mut bob, ":tcp_echo 366 bob #lobby :End of /NAMES list");
+
+    bob.send("FROB");
+    expect_irc(
+        &mut bob,
+        ":tcp_echo 421 bob FROB :Unknown command: /frob; use /help to list the commands",
+    );
+
+    alice.send("/nick carol");
+    alice.expect_line("alice is now known as carol");
+    expect_irc(&mut bob, ":alice!alice@tcp_echo NICK :carol");
+
+    let mut dave = Client::connect(server.listener_addr("IRC"));
+    dave.send("NICK carol");
+    expect_irc(
+        &mut dave,
+        ":tcp_echo 433 * carol :Display name 'carol' is already taken",
+    );
+
+    bob.send("QUIT :bye");
+    expect_irc(&mut bob, ":tcp_echo NOTICE bob :Goodbye");
+    assert_eq!(bob.read_to_end(), Vec::<String>::new());
+    alice.expect_line("bob has left the chat (quit)");
+}
+
+/// Checks that clients chat over TLS when the server is given a certificate and key, and that
+/// the session is ended cleanly when a client quits.
+fn assert_chats_over_tls(path: &str, tls_name: &str) {
+    let tls = Tls::generate(tls_name);
+    let server = Server::start(path, &tls.args());
+
+    // A client that does not speak TLS is dropped without disturbing anyone else.
+    let mut stranger = server.connect();
+    stranger.send("hello");
+
+    let mut alice = server.connect_tls(&tls);
+    alice.expect_line("Enter your display name");
+    alice.send("alice");
+    alice.read_until("alice has entered the chat");
+
+    let mut bob = server.connect_tls(&tls);
+    bob.expect_line("Enter your display name");
+    bob.send("bob");
+    bob.read_until("bob has entered the chat");
+    alice.expect_line("bob has entered the chat");
+
+    bob.send("hello alice");
+    alice.expect_line("bob: hello alice");
+    bob.expect_line("bob: hello alice");
+
+    // The session is ended cleanly, so the client sees the end of the stream rather than an error.
+    bob.send("/quit");
+    bob.expect_line("Goodbye");
+    assert_eq!(bob.read_to_end(), Vec::<String>::new());
+    alice.expect_line("bob has left the chat (quit)");
+}
+
+/// Checks that clients are warned and disconnected for exceeding the idle or line timeouts, and
+/// that a TLS client that never completes its handshake is disconnected too.
+fn assert_times_out_clients(path: &str, tls_name: &str) {
+    let server = Server::start(path, &["--idle-timeout", "3", "--line-timeout", "1"]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    bob.send_partial("half a li");
+    assert_eq!(
+        bob.read_to_end(),
+        vec!["Disconnecting: line not finished within 1 second\n"]
+    );
+    alice.expect_line("bob has left the chat (timeout)");
+
+    assert_eq!(
+        alice.read_to_end(),
+        vec!["Disconnecting: no input for 3 seconds\n"]
+    );
+
+    // A TLS client that never completes its handshake is held to the idle timeout too. Only the
+    // end of the TLS session may be sent, so the client is never told why.
+    let tls = Tls::generate(tls_name);
+    let server = Server::start(path, &[&tls.args()[..], &["--idle-timeout", "1"]].concat());
+    server.connect().read_to_end();
+    server.wait_for_output("no input for 1 second");
+}
+
+/// Checks that lines that are too long are ignored with a notice to their sender, and that
+/// control characters and escape sequences are removed from chat.
+fn assert_limits_input(path: &str) {
+    let server = Server::start(path, &["--max-line-length", "16"]);
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    bob.send("this line is far too long");
+    bob.expect_line("Ignored line longer than 16 bytes");
+
+    // Neither the escape sequences nor the bell reach anyone's terminal.
+    bob.send("\x1b[2Jhi\x07 there");
+    alice.expect_line("bob: hi there");
+    bob.expect_line("bob: hi there");
+}
+
+/// Checks that a client sending a line that is too long is disconnected, if so configured.
+fn assert_disconnects_on_long_lines(path: &str) {
+    let server = Server::start(
+        path,
+        &["--max-line-length", "16", "--long-lines", "disconnect"],
+    );
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    bob.send("this line is far too long");
+    assert_eq!(
+        bob.read_to_end(),
+        vec!["Disconnecting: line longer than 16 bytes\n"]
+    );
+    alice.expect_line("bob has left the chat (invalid input)");
+}
+
+/// Checks that a client sending lines too fast is warned and then muted, and that nothing it
+/// sends while muted reaches anyone.
+fn assert_mutes_flooding_clients(path: &str) {
+    // The display name takes one of the burst of three lines.
+    let server = Server::start(
+        path,
+        &[
+            "--message-rate",
+            "1",
+            "--message-burst",
+            "3",
+            "--flood-warnings",
+            "1",
+            "--flood-mute",
+            "60",
+        ],
+    );
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    for line in ["one", "two", "three", "four", "five"] {
+        bob.send(line);
+    }
+    bob.expect_line("bob: one");
+    bob.expect_line("bob: two");
+    bob.expect_line("Not sent: you are sending messages too fast, please slow down");
+    bob.expect_line("You are muted for 60 seconds for sending messages too fast");
+    alice.expect_line("bob: one");
+    alice.expect_line("bob: two");
+
+    // Nothing bob sent while muted reaches alice.
+    alice.send("are you there?");
+    alice.expect_line("alice: are you there?");
+    bob.expect_line("alice: are you there?");
+
+    // Lines dropped while muted are not each logged as a warning.
+    assert!(!server.has_output("still muted"));
+}
+
+/// Checks that a client that goes on flooding after being warned is disconnected.
+fn assert_disconnects_flooding_clients(path: &str) {
+    let server = Server::start(
+        path,
+        &[
+            "--message-rate",
+            "1",
+            "--message-burst",
+            "2",
+            "--flood-warnings",
+            "1",
+            "--kick-after",
+            "0",
+        ],
+    );
+    let mut alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    for line in ["one", "two", "three"] {
+        bob.send(line);
+    }
+    assert_eq!(
+        bob.read_to_end(),
+        vec![
+            "bob: one\n",
+            "Not sent: you are sending messages too fast, please slow down\n",
+            "Disconnecting: sending messages too fast\n",
+        ]
+    );
+    alice.expect_line("bob: one");
+    alice.expect_line("bob has left the chat (flooding)");
+}
+
+/// Checks that a connection over the per-address limit is refused with a notice, without
+/// affecting the clients already connected.
+fn assert_limits_connections_per_address(path: &str) {
+    let server = Server::start(path, &["--max-per-ip", "2"]);
+    let mut alice = join(&server, "alice");
+    let _bob = join(&server, "bob");
+    alice.expect_line("bob has entered the chat");
+
+    assert_eq!(
+        server.connect().read_to_end(),
+        vec![
+            "Connection refused: too many connections from your address, please try again later\n"
+        ]
+    );
+    alice.send("still here");
+    alice.expect_line("alice: still here");
+}
+
+/// Checks that a client that disconnects is announced as having left, and the chat goes on.
+fn assert_removes_disconnected_clients(path: &str) {
+    let server = Server::start(path, &[]);
+    let alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+    drop(alice);
+
+    bob.expect_line("alice has left the chat (quit)");
+
+    bob.send("still here");
+    bob.expect_line("bob: still here");
+}
+
+/// Checks that a client that stops reading is disconnected once its queue is full, without
+/// holding up the others.
+fn assert_disconnects_slow_clients(path: &str) {
+    let server = Server::start(
+        path,
+        &[
+            "--client-buffer",
+            "4",
+            "--slow-client",
+            "drop-client",
+            "--max-line-length",
+            "0",
+        ],
+    );
+    let _alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+
+    // Alice never reads, so once the socket buffers between her and the server are full, her
+    // queue fills too. Bob must keep receiving his own messages throughout.
+    let long_line = "x".repeat(64 * 1024);
+    for _ in 0..256 {
+        bob.send(&long_line);
+        let skipped = bob.read_until(&format!("bob: {long_line}"));
+
+        if skipped.contains(&"alice has left the chat (too slow)\n".to_owned()) {
+            return;
+        }
+    }
+
+    panic!("Slow client was never disconnected");
+}
+
+/// Checks that a client whose connection is reset is announced as having left for that reason.
+fn assert_announces_reset_connections(path: &str) {
+    let server = Server::start(path, &[]);
+    let alice = join(&server, "alice");
+    let mut bob = join(&server, "bob");
+
+    alice.reset();
+    bob.expect_line("alice has left the chat (connection reset)");
+}
+
+/// Checks that every client is sent a notice when the server shuts down.
+fn assert_shuts_down_gracefully(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut alice = join(&server, "alice");
+
+    server.terminate();
+    assert_eq!(alice.read_to_end(), vec!["Server shutting down\n"]);
+}
+
+#[test]
+fn chat_threaded_prompts_for_display_name() {
+    assert_prompts_for_display_name(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_prompts_for_display_name() {
+    assert_prompts_for_display_name(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_announces_new_users() {
+    assert_announces_new_users(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_announces_new_users() {
+    assert_announces_new_users(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_broadcasts_to_all_clients() {
+    assert_broadcasts_to_all_clients(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_broadcasts_to_all_clients() {
+    assert_broadcasts_to_all_clients(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_rejects_unusable_display_names() {
+    assert_rejects_unusable_display_names(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_rejects_unusable_display_names() {
+    assert_rejects_unusable_display_names(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_routes_messages_by_room() {
+    assert_routes_messages_by_room(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_routes_messages_by_room() {
+    assert_routes_messages_by_room(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_runs_commands() {
+    assert_runs_commands(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_runs_commands() {
+    assert_runs_commands(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_answers_unknown_commands_privately() {
+    assert_answers_unknown_commands_privately(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_answers_unknown_commands_privately() {
+    assert_answers_unknown_commands_privately(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_delivers_private_messages() {
+    assert_delivers_private_messages(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_delivers_private_messages() {
+    assert_delivers_private_messages(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_replays_history() {
+    assert_replays_history(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_replays_history() {
+    assert_replays_history(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_limits_history_by_age() {
+    assert_limits_history_by_age(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_limits_history_by_age() {
+    assert_limits_history_by_age(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_reloads_chat_log() {
+    assert_reloads_chat_log(CHAT_THREADED, "chat_threaded_reloads_chat_log");
+}
+
+#[test]
+fn chat_async_reloads_chat_log() {
+    assert_reloads_chat_log(CHAT_ASYNC, "chat_async_reloads_chat_log");
+}
+
+#[test]
+fn chat_threaded_skips_unreadable_chat_log_lines() {
+    assert_skips_unreadable_chat_log_lines(CHAT_THREADED, "chat_threaded_skips_unreadable_lines");
+}
+
+#[test]
+fn chat_async_skips_unreadable_chat_log_lines() {
+    assert_skips_unreadable_chat_log_lines(CHAT_ASYNC, "chat_async_skips_unreadable_lines");
+}
+
+#[test]
+fn chat_threaded_speaks_json() {
+    assert_speaks_json(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_speaks_json() {
+    assert_speaks_json(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_speaks_irc() {
+    assert_speaks_irc(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_speaks_irc() {
+    assert_speaks_irc(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_async_speaks_websocket() {
+    assert_speaks_websocket(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_async_refuses_bad_websocket_handshakes() {
+    assert_refuses_bad_websocket_handshakes(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_async_refuses_oversized_websocket_handshakes() {
+    assert_refuses_oversized_websocket_handshakes(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_chats_over_tls() {
+    assert_chats_over_tls(CHAT_THREADED, "chat-threaded-tls");
+}
+
+#[test]
+fn chat_async_chats_over_tls() {
+    assert_chats_over_tls(CHAT_ASYNC, "chat-async-tls");
+}
+
+#[test]
+fn chat_threaded_times_out_clients() {
+    assert_times_out_clients(CHAT_THREADED, "chat-threaded-timeout-tls");
+}
+
+#[test]
+fn chat_async_times_out_clients() {
+    assert_times_out_clients(CHAT_ASYNC, "chat-async-timeout-tls");
+}
+
+#[test]
+fn chat_threaded_limits_input() {
+    assert_limits_input(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_limits_input() {
+    assert_limits_input(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_disconnects_on_long_lines() {
+    assert_disconnects_on_long_lines(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_disconnects_on_long_lines() {
+    assert_disconnects_on_long_lines(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_mutes_flooding_clients() {
+    assert_mutes_flooding_clients(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_mutes_flooding_clients() {
+    assert_mutes_flooding_clients(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_disconnects_flooding_clients() {
+    assert_disconnects_flooding_clients(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_disconnects_flooding_clients() {
+    assert_disconnects_flooding_clients(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_limits_connections_per_address() {
+    assert_limits_connections_per_address(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_limits_connections_per_address() {
+    assert_limits_connections_per_address(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_removes_disconnected_clients() {
+    assert_removes_disconnected_clients(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_removes_disconnected_clients() {
+    assert_removes_disconnected_clients(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_disconnects_slow_clients() {
+    assert_disconnects_slow_clients(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_disconnects_slow_clients() {
+    assert_disconnects_slow_clients(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_announces_reset_connections() {
+    assert_announces_reset_connections(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_announces_reset_connections() {
+    assert_announces_reset_connections(CHAT_ASYNC);
+}
+
+#[test]
+fn chat_threaded_shuts_down_gracefully() {
+    assert_shuts_down_gracefully(CHAT_THREADED);
+}
+
+#[test]
+fn chat_async_shuts_down_gracefully() {
+    assert_shuts_down_gracefully(CHAT_ASYNC);
+}
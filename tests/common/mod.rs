@@ -0,0 +1,336 @@
+//! Helpers shared by the integration tests, which run the server binaries as child processes on
+//! ephemeral ports and talk to them over TCP like any other client.
+
+#![allow(dead_code)] // Not every test file uses every helper.
+
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use socket2::SockRef;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a server or client to do what a test expects before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server binary running as a child process, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub addr: SocketAddr,
+    output: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    /// Runs the binary at `path`, typically given by `env!("CARGO_BIN_EXE_<name>")`, on a free port
+    /// with the extra command-line `args`, and waits until it is listening.
+    pub fn start(path: &str, args: &[&str]) -> Self {
+        let mut child = Command::new(path)
+            .args(["--port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to start server");
+
+        // Keep reading the server's output so it never blocks on a full pipe, and keep a copy of
+        // it for tests that check what the server logged.
+        let stdout = child.stdout.take().unwrap();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let (addr_tx, addr_rx) = channel();
+
+        let output_cloned = output.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { return };
+
+                if let Some(addr) = address_after(&line, "Listening on ") {
+                    let _ = addr_tx.send(addr);
+                }
+                output_cloned.lock().unwrap().push(line);
+            }
+        });
+
+        let addr = addr_rx
+            .recv_timeout(TIMEOUT)
+            .expect("Server did not report the address it is listening on");
+
+        Self {
+            child,
+            addr,
+            output,
+        }
+    }
+
+    /// Connects a new client to the server.
+    pub fn connect(&self) -> Client {
+        Client::connect(self.addr)
+    }
+
+    /// Connects a new client to the server over TLS, trusting the certificate in `tls`.
+    pub fn connect_tls(&self, tls: &Tls) -> Client {
+        Client::connect_tls(self.addr, tls)
+    }
+
+    /// Returns the address of the server's listener for clients speaking `protocol`, as named in
+    /// its output, e.g. `JSON`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server does not report such a listener within `TIMEOUT`.
+    pub fn listener_addr(&self, protocol: &str) -> SocketAddr {
+        let prefix = format!("Listening for {protocol} clients on ");
+        let line = self.wait_for_output(&prefix);
+        address_after(&line, &prefix).unwrap()
+    }
+
+    /// Waits until the server has written a line containing `text` to its standard output, and
+    /// returns the first such line.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no such line is written within `TIMEOUT`.
+    pub fn wait_for_output(&self, text: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+
+        while Instant::now() < deadline {
+            if let Some(line) = self
+                .output
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|l| l.contains(text))
+            {
+                return line.clone();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Server never output '{text}'");
+    }
+
+    /// Returns whether the server has written a line containing `text` to its standard output so
+    /// far.
+    pub fn has_output(&self, text: &str) -> bool {
+        self.output.lock().unwrap().iter().any(|l| l.contains(text))
+    }
+
+    /// Sends SIGTERM to the server and waits for it to exit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server is still running after twice `TIMEOUT`, which leaves it time to wait
+    /// out its own shutdown timeout.
+    pub fn terminate(mut self) {
+        Command::new("kill")
+            .args(["-TERM", &self.child.id().to_string()])
+            .status()
+            .expect("Failed to send SIGTERM to server");
+
+        let deadline = Instant::now() + 2 * TIMEOUT;
+        while Instant::now() < deadline {
+            let status = self
+                .child
+                .try_wait()
+                .expect("Failed to wait for server to exit");
+            if status.is_some() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Server still running after SIGTERM");
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A self-signed certificate for `localhost` and its private key, written to temporary files for
+/// a server to load, and deleted when dropped.
+pub struct Tls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Tls {
+    /// Generates a certificate and key whose file names include `name`, which must be unique among
+    /// the tests that run at the same time.
+    pub fn generate(name: &str) -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+            .expect("Failed to generate certificate");
+
+        let dir = env::temp_dir();
+        let cert_path = dir.join(format!("{name}-{}.crt", process::id()));
+        let key_path = dir.join(format!("{name}-{}.key", process::id()));
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::clone(certified.cert.der()))
+            .unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Self {
+            cert_path,
+            key_path,
+            client_config: Arc::new(client_config),
+        }
+    }
+
+    /// Returns the command-line arguments that make a server use this certificate and key.
+    pub fn args(&self) -> [&str; 4] {
+        [
+            "--tls-cert",
+            self.cert_path.to_str().unwrap(),
+            "--tls-key",
+            self.key_path.to_str().unwrap(),
+        ]
+    }
+}
+
+impl Drop for Tls {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.cert_path);
+        let _ = fs::remove_file(&self.key_path);
+    }
+}
+
+/// The plaintext of a connection to a server, whether or not it is encrypted.
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// Returns the socket address following `prefix` in `line`, a line of the server's output, which
+/// ends the line in text records and the message in JSON ones.
+fn address_after(line: &str, prefix: &str) -> Option<SocketAddr> {
+    let (_, rest) = line.split_once(prefix)?;
+    rest.split('"').next()?.parse().ok()
+}
+
+/// A client connection to a server under test.
+pub struct Client {
+    reader: BufReader<Box<dyn Connection>>,
+    stream: TcpStream,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).expect("Failed to connect to server");
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let reader = BufReader::new(Box::new(stream.try_clone().unwrap()) as Box<dyn Connection>);
+
+        Self { reader, stream }
+    }
+
+    /// Connects to the server at `addr` over TLS, trusting the certificate in `tls`.
+    pub fn connect_tls(addr: SocketAddr, tls: &Tls) -> Self {
+        let stream = TcpStream::connect(addr).expect("Failed to connect to server");
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let session = ClientConnection::new(
+            tls.client_config.clone(),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let tls_stream = StreamOwned::new(session, stream.try_clone().unwrap());
+        let reader = BufReader::new(Box::new(tls_stream) as Box<dyn Connection>);
+
+        Self { reader, stream }
+    }
+
+    /// Sends `line` to the server, appending a newline.
+    pub fn send(&mut self, line: &str) {
+        // Writing bypasses the buffer, which only holds what has been read.
+        self.reader
+            .get_mut()
+            .write_all(format!("{line}\n").as_bytes())
+            .expect("Failed to send to server");
+    }
+
+    /// Sends `text` to the server as it is, without appending a newline.
+    pub fn send_partial(&mut self, text: &str) {
+        self.reader
+            .get_mut()
+            .write_all(text.as_bytes())
+            .expect("Failed to send to server");
+    }
+
+    /// Sends `bytes` to the server as they are, whether or not they are valid UTF-8.
+    pub fn send_bytes(&mut self, bytes: &[u8]) {
+        self.reader
+            .get_mut()
+            .write_all(bytes)
+            .expect("Failed to send to server");
+    }
+
+    /// Reads the next line from the server, including its newline. Returns an empty string if the
+    /// server closed the connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no line arrives within `TIMEOUT`.
+    pub fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .expect("Failed to read from server");
+        line
+    }
+
+    /// Reads the next line and checks it is `expected`, which excludes the newline.
+    pub fn expect_line(&mut self, expected: &str) {
+        assert_eq!(self.read_line(), format!("{expected}\n"));
+    }
+
+    /// Reads lines until one equal to `expected`, which excludes the newline, arrives. Returns the
+    /// lines skipped over.
+    pub fn read_until(&mut self, expected: &str) -> Vec<String> {
+        let mut skipped = Vec::new();
+
+        loop {
+            let line = self.read_line();
+            assert!(
+                !line.is_empty(),
+                "Connection closed waiting for '{expected}'"
+            );
+
+            if line.trim_end_matches('\n') == expected {
+                return skipped;
+            }
+            skipped.push(line);
+        }
+    }
+
+    /// Closes the connection abruptly, so the server sees it reset rather than closed cleanly.
+    pub fn reset(self) {
+        SockRef::from(&self.stream)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+    }
+
+    /// Reads until the server closes the connection, returning everything received.
+    pub fn read_to_end(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+}
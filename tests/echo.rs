@@ -0,0 +1,427 @@
+//! Integration tests for the echo servers.
+
+mod common;
+
+use common::{Server, Tls};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+const ECHO_SIMPLE: &str = env!("CARGO_BIN_EXE_echo_simple");
+const ECHO_THREADED: &str = env!("CARGO_BIN_EXE_echo_threaded");
+const ECHO_ASYNC: &str = env!("CARGO_BIN_EXE_echo_async");
+
+/// Checks that each line a client sends is echoed back with the `Server responds: ` prefix.
+fn assert_echoes_lines(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut client = server.connect();
+
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+
+    client.send("a second line");
+    client.expect_line("Server responds: a second line");
+}
+
+/// Checks that a client is served while another client's connection remains open.
+fn assert_serves_concurrent_clients(path: &str, args: &[&str]) {
+    let server = Server::start(path, args);
+    let mut first = server.connect();
+    let mut second = server.connect();
+
+    first.send("first");
+    second.send("second");
+    second.expect_line("Server responds: second");
+    first.expect_line("Server responds: first");
+}
+
+/// Checks that on SIGTERM every client is sent a notice and disconnected.
+fn assert_shuts_down_gracefully(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut client = server.connect();
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+
+    server.terminate();
+    assert_eq!(client.read_to_end(), vec!["Server shutting down\n"]);
+}
+
+/// Checks that lines are echoed over TLS when the server is given a certificate and key.
+fn assert_echoes_lines_over_tls(path: &str, tls_name: &str) {
+    let tls = Tls::generate(tls_name);
+    let server = Server::start(path, &tls.args());
+    let mut client = server.connect_tls(&tls);
+
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+    client.send("a second line");
+    client.expect_line("Server responds: a second line");
+}
+
+/// Checks that a connection that fails, here because the client does not speak TLS, is closed
+/// without affecting the server, which goes on serving other clients.
+fn assert_survives_failed_connections(path: &str, tls_name: &str) {
+    let tls = Tls::generate(tls_name);
+    let server = Server::start(path, &tls.args());
+
+    let mut plaintext = server.connect();
+    plaintext.send("hello");
+    server.wait_for_output("Closed connection: ");
+    drop(plaintext);
+
+    let mut client = server.connect_tls(&tls);
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+}
+
+/// Checks that the lines clients send are only logged at the debug level, and that JSON records
+/// carry the connection's id and peer address alongside the event and message.
+fn assert_logs_structured_records(path: &str) {
+    let server = Server::start(path, &[]);
+    let mut client = server.connect();
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+    drop(client);
+    server.wait_for_output("End of data; closing connection");
+    assert!(server.has_output("connection_established"));
+    assert!(!server.has_output("[5 chars] hello"));
+
+    let server = Server::start(path, &["--log-level", "debug", "--log-format", "json"]);
+    let mut client = server.connect();
+    client.send("hello");
+    client.expect_line("Server responds: hello");
+
+    let record = server.wait_for_output(r#""event":"line_received""#);
+    let record: Value = serde_json::from_str(&record).unwrap();
+    assert_eq!(record["level"], "debug");
+    assert_eq!(record["message"], "[5 chars] hello");
+    assert!(record["conn"].is_u64(), "{record} has no connection id");
+    assert!(
+        record["elapsed_ms"].is_u64(),
+        "{record} has no elapsed time"
+    );
+    let peer = record["peer"].as_str().unwrap();
+    assert!(
+        peer.parse::<SocketAddr>().is_ok(),
+        "{peer} is not an address"
+    );
+}
+
+/// Checks that connections beyond the global maximum, the per-address maximum or the accept rate
+/// are refused with a notice, and that a closed connection makes room for another.
+fn assert_governs_connections(path: &str) {
+    let server = Server::start(path, &["--max-connections", "1"]);
+    let mut first = server.connect();
+    first.send("hello");
+    first.expect_line("Server responds: hello");
+    assert_eq!(
+        server.connect().read_to_end(),
+        vec!["Connection refused: server is full, please try again later\n"]
+    );
+    drop(first);
+    server.wait_for_output("End of data; closing connection");
+    thread::sleep(Duration::from_millis(100));
+    let mut next = server.connect();
+    next.send("hello");
+    next.expect_line("Server responds: hello");
+
+    let server = Server::start(path, &["--max-per-ip", "2"]);
+    let mut clients = [server.connect(), server.connect()];
+    for client in &mut clients {
+        client.send("hello");
+        client.expect_line("Server responds: hello");
+    }
+    assert_eq!(
+        server.connect().read_to_end(),
+        vec![
+            "Connection refused: too many connections from your address, please try again later\n"
+        ]
+    );
+
+    let server = Server::start(path, &["--accept-rate", "1", "--accept-burst", "2"]);
+    let mut clients = [server.connect(), server.connect()];
+    for client in &mut clients {
+        client.send("hello");
+        client.expect_line("Server responds: hello");
+    }
+    assert_eq!(
+        server.connect().read_to_end(),
+        vec!["Connection refused: too many new connections, please try again later\n"]
+    );
+}
+
+/// Checks that a client is warned and disconnected once it has sent nothing for the idle timeout,
+/// or has taken longer than the line timeout to finish a line, and that a TLS client that never
+/// completes its handshake is disconnected once the idle timeout has passed.
+fn assert_times_out_clients(path: &str, tls_name: &str) {
+    let server = Server::start(path, &["--idle-timeout", "2", "--line-timeout", "1"]);
+
+    let mut idle = server.connect();
+    idle.send("hello");
+    idle.expect_line("Server responds: hello");
+    assert_eq!(
+        idle.read_to_end(),
+        vec!["Disconnecting: no input for 2 seconds\n"]
+    );
+
+    let mut slow = server.connect();
+    slow.send_partial("hel");
+    assert_eq!(
+        slow.read_to_end(),
+        vec!["Disconnecting: line not finished within 1 second\n"]
+    );
+
+    let tls = Tls::generate(tls_name);
+    let server = Server::start(path, &[&tls.args()[..], &["--idle-timeout", "1"]].concat());
+    // Only the end of the TLS session may be sent, so the client is never told why.
+    server.connect().read_to_end();
+    server.wait_for_output("no input for 1 second");
+}
+
+/// Checks that lines that are too long or not valid UTF-8 are ignored with a notice to the client,
+/// and that control characters and escape sequences are removed from the lines echoed.
+fn assert_limits_input(path: &str) {
+    let server = Server::start(path, &["--max-line-length", "16", "--utf8", "strict"]);
+    let mut client = server.connect();
+
+    client.send("far longer than allowed");
+    client.expect_line("Ignored line longer than 16 bytes");
+    client.send_bytes(b"caf\xe9\n");
+    client.expect_line("Ignored line that is not valid UTF-8");
+    client.send("\x1b[31mred\x1b[0m\x07");
+    client.expect_line("Server responds: red");
+    client.send("still here");
+    client.expect_line("Server responds: still here");
+}
+
+/// Checks that long lines are truncated or disconnect the client, invalid UTF-8 is replaced and
+/// control characters are kept when the options say so.
+fn assert_applies_input_policies(path: &str) {
+    let server = Server::start(
+        path,
+        &[
+            "--max-line-length",
+            "8",
+            "--long-lines",
+            "truncate",
+            "--keep-control-chars",
+        ],
+    );
+    let mut client = server.connect();
+    client.send("truncated line");
+    client.expect_line("Server responds: truncate");
+    client.send_bytes(b"caf\xe9\n");
+    client.expect_line("Server responds: caf\u{fffd}");
+    client.send("\x1b[1mhi");
+    client.expect_line("Server responds: \x1b[1mhi");
+
+    let server = Server::start(
+        path,
+        &["--max-line-length", "8", "--long-lines", "disconnect"],
+    );
+    let mut client = server.connect();
+    client.send("far longer than allowed");
+    assert_eq!(
+        client.read_to_end(),
+        vec!["Disconnecting: line longer than 8 bytes\n"]
+    );
+}
+
+#[test]
+fn echo_simple_echoes_lines() {
+    assert_echoes_lines(ECHO_SIMPLE);
+}
+
+#[test]
+fn echo_threaded_echoes_lines() {
+    assert_echoes_lines(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_echoes_lines() {
+    assert_echoes_lines(ECHO_ASYNC);
+}
+
+#[test]
+fn echo_threaded_serves_concurrent_clients() {
+    assert_serves_concurrent_clients(ECHO_THREADED, &[]);
+}
+
+#[test]
+fn echo_threaded_pool_serves_concurrent_clients() {
+    assert_serves_concurrent_clients(ECHO_THREADED, &["--pool-size", "2"]);
+}
+
+#[test]
+fn echo_async_serves_concurrent_clients() {
+    assert_serves_concurrent_clients(ECHO_ASYNC, &[]);
+}
+
+#[test]
+fn echo_threaded_pool_rejects_connections_when_full() {
+    let args = [
+        "--pool-size",
+        "1",
+        "--queue-depth",
+        "1",
+        "--when-full",
+        "reject",
+    ];
+    let server = Server::start(ECHO_THREADED, &args);
+
+    // The first connection occupies the only worker and the second fills the queue.
+    let mut first = server.connect();
+    first.send("first");
+    first.expect_line("Server responds: first");
+    let _second = server.connect();
+
+    let mut third = server.connect();
+    assert_eq!(
+        third.read_to_end(),
+        vec!["Server busy, please try again later\n"]
+    );
+}
+
+#[test]
+fn echo_threaded_pool_rejects_tls_connections_without_a_plaintext_notice() {
+    let tls = Tls::generate("echo-threaded-pool-tls");
+    let mut args = vec![
+        "--pool-size",
+        "1",
+        "--queue-depth",
+        "0",
+        "--when-full",
+        "reject",
+    ];
+    args.extend(tls.args());
+    let server = Server::start(ECHO_THREADED, &args);
+
+    // The first connection occupies the only worker, so the second is rejected.
+    let mut first = server.connect_tls(&tls);
+    first.send("first");
+    first.expect_line("Server responds: first");
+
+    assert!(server.connect().read_to_end().is_empty());
+}
+
+#[test]
+fn echo_threaded_pool_shuts_down_while_full() {
+    let server = Server::start(ECHO_THREADED, &["--pool-size", "1", "--queue-depth", "0"]);
+
+    // The first connection occupies the only worker, so the accept loop waits for room to queue
+    // the second.
+    let mut first = server.connect();
+    first.send("first");
+    first.expect_line("Server responds: first");
+    let _second = server.connect();
+    thread::sleep(Duration::from_millis(100));
+
+    server.terminate();
+    assert_eq!(first.read_to_end(), vec!["Server shutting down\n"]);
+}
+
+#[test]
+fn echo_threaded_shuts_down_gracefully() {
+    assert_shuts_down_gracefully(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_shuts_down_gracefully() {
+    assert_shuts_down_gracefully(ECHO_ASYNC);
+}
+
+#[test]
+fn echo_simple_echoes_lines_over_tls() {
+    assert_echoes_lines_over_tls(ECHO_SIMPLE, "echo-simple-tls");
+}
+
+#[test]
+fn echo_threaded_echoes_lines_over_tls() {
+    assert_echoes_lines_over_tls(ECHO_THREADED, "echo-threaded-tls");
+}
+
+#[test]
+fn echo_async_echoes_lines_over_tls() {
+    assert_echoes_lines_over_tls(ECHO_ASYNC, "echo-async-tls");
+}
+
+#[test]
+fn echo_simple_times_out_clients() {
+    assert_times_out_clients(ECHO_SIMPLE, "echo-simple-timeout-tls");
+}
+
+#[test]
+fn echo_threaded_times_out_clients() {
+    assert_times_out_clients(ECHO_THREADED, "echo-threaded-timeout-tls");
+}
+
+#[test]
+fn echo_async_times_out_clients() {
+    assert_times_out_clients(ECHO_ASYNC, "echo-async-timeout-tls");
+}
+
+#[test]
+fn echo_simple_limits_input() {
+    assert_limits_input(ECHO_SIMPLE);
+}
+
+#[test]
+fn echo_threaded_limits_input() {
+    assert_limits_input(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_limits_input() {
+    assert_limits_input(ECHO_ASYNC);
+}
+
+#[test]
+fn echo_simple_applies_input_policies() {
+    assert_applies_input_policies(ECHO_SIMPLE);
+}
+
+#[test]
+fn echo_threaded_applies_input_policies() {
+    assert_applies_input_policies(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_applies_input_policies() {
+    assert_applies_input_policies(ECHO_ASYNC);
+}
+
+#[test]
+fn echo_simple_survives_failed_connections() {
+    assert_survives_failed_connections(ECHO_SIMPLE, "echo-simple-failure");
+}
+
+#[test]
+fn echo_threaded_survives_failed_connections() {
+    assert_survives_failed_connections(ECHO_THREADED, "echo-threaded-failure");
+}
+
+#[test]
+fn echo_async_survives_failed_connections() {
+    assert_survives_failed_connections(ECHO_ASYNC, "echo-async-failure");
+}
+
+#[test]
+fn echo_threaded_governs_connections() {
+    assert_governs_connections(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_governs_connections() {
+    assert_governs_connections(ECHO_ASYNC);
+}
+
+#[test]
+fn echo_threaded_logs_structured_records() {
+    assert_logs_structured_records(ECHO_THREADED);
+}
+
+#[test]
+fn echo_async_logs_structured_records() {
+    assert_logs_structured_records(ECHO_ASYNC);
+}